@@ -1,7 +1,16 @@
 use warp::Filter;
+use warp::http::StatusCode;
+use warp::ws::{Message, WebSocket};
+use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use crate::network::Network;
+use crate::core::blockchain::Blockchain;
+use crate::core::events::{EventFilter, EventManager, SubscriptionFilter};
+use crate::core::receipt::{ExecutionStatus, Receipt};
+use crate::core::transaction::Transaction as ChainTransaction;
+use crate::security;
+use crate::smart_contracts::{disassemble, ContractAbi};
 
 #[derive(Serialize, Deserialize)]
 struct NodeStatus {
@@ -22,8 +31,47 @@ struct Transaction {
     sender: String,
     receiver: String,
     amount: u64,
-    fee: u64,
+    gas_limit: u64,
+    gas_price: u64,
+    priority_fee: u64,
     status: String,
+    #[serde(default)]
+    receipt: Option<Receipt>,
+}
+
+impl Transaction {
+    fn from_chain(transaction: &ChainTransaction, status: &str, receipt: Option<Receipt>) -> Self {
+        Transaction {
+            tx_hash: transaction.id(),
+            sender: transaction.sender.clone(),
+            receiver: transaction.receiver.clone(),
+            amount: transaction.amount,
+            gas_limit: transaction.gas_limit,
+            gas_price: transaction.gas_price,
+            priority_fee: transaction.priority_fee,
+            status: status.to_string(),
+            receipt,
+        }
+    }
+}
+
+// Looks the transaction up among included transactions first, then in the pool
+fn find_transaction(blockchain: &Blockchain, tx_hash: &str) -> Option<Transaction> {
+    if let Some(receipt) = blockchain.receipt(tx_hash) {
+        let status = match receipt.status {
+            ExecutionStatus::Success => "Confirmed",
+            ExecutionStatus::Failed(_) => "Failed",
+        };
+        let transaction = blockchain.block(receipt.block_index)?
+            .transactions()
+            .into_iter()
+            .find(|transaction| transaction.id() == tx_hash)?;
+        return Some(Transaction::from_chain(&transaction, status, Some(receipt)));
+    }
+
+    blockchain.transaction_pool.transactions.iter()
+        .find(|transaction| transaction.id() == tx_hash)
+        .map(|transaction| Transaction::from_chain(transaction, "Pending", None))
 }
 
 #[derive(Serialize, Deserialize)]
@@ -34,7 +82,60 @@ struct Block {
     timestamp: u64,
 }
 
-pub async fn start_api(network: Arc<Network>) {
+#[derive(Serialize, Deserialize)]
+struct ContractCode {
+    address: String,
+    version: u32,
+    code: String,
+    abi: ContractAbi,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disassembly: Option<String>, // Assembly for contracts deployed as hex bytecode
+}
+
+#[derive(Serialize, Deserialize)]
+struct TokenBalance {
+    token: String,
+    address: String,
+    balance: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RoyaltyInfo {
+    recipient: String,
+    amount: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OwnedNft {
+    collection: String,
+    token_id: u64,
+}
+
+// Streams chain events to a WebSocket client as JSON. The client's first message is the
+// subscription filter; an empty object subscribes to everything.
+async fn stream_events(socket: WebSocket, event_manager: EventManager) {
+    let (mut outgoing, mut incoming) = socket.split();
+    let filter: SubscriptionFilter = match incoming.next().await {
+        Some(Ok(message)) => match message.to_str().ok().map(serde_json::from_str) {
+            Some(Ok(filter)) => filter,
+            _ => {
+                let _ = outgoing.send(Message::close_with(1003u16, "Expected a subscription filter")).await;
+                return;
+            }
+        },
+        _ => return,
+    };
+
+    let mut subscription = event_manager.subscribe(filter);
+    while let Some(event) = subscription.next().await {
+        let text = serde_json::to_string(&event).expect("Failed to serialize event");
+        if outgoing.send(Message::text(text)).await.is_err() {
+            break;
+        }
+    }
+}
+
+pub async fn start_api(network: Arc<Network>, blockchain: Arc<Mutex<Blockchain>>) {
     // Node status endpoint
     let get_status = warp::path("status")
         .map(|| {
@@ -66,16 +167,57 @@ pub async fn start_api(network: Arc<Network>) {
 
     // Get transaction by hash endpoint
     let get_transaction = warp::path!("transaction" / String)
-        .map(|tx_hash: String| {
-            let transaction = Transaction {
-                tx_hash: tx_hash.clone(),
-                sender: "sender_address".to_string(),
-                receiver: "receiver_address".to_string(),
-                amount: 500,
-                fee: 10,
-                status: "Confirmed".to_string(),
-            };
-            warp::reply::json(&transaction)
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |tx_hash: String| {
+                let blockchain = blockchain.lock().unwrap();
+                match find_transaction(&blockchain, &tx_hash) {
+                    Some(transaction) => warp::reply::with_status(warp::reply::json(&transaction), StatusCode::OK),
+                    None => warp::reply::with_status(warp::reply::json(&"Transaction not found"), StatusCode::NOT_FOUND),
+                }
+            }
+        });
+
+    // Get transaction receipt endpoint
+    let get_receipt = warp::path!("receipt" / String)
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |tx_hash: String| {
+                match blockchain.lock().unwrap().receipt(&tx_hash) {
+                    Some(receipt) => warp::reply::with_status(warp::reply::json(&receipt), StatusCode::OK),
+                    None => warp::reply::with_status(warp::reply::json(&"Receipt not found"), StatusCode::NOT_FOUND),
+                }
+            }
+        });
+
+    // Headers from a height on, for light clients to sync
+    let get_headers = warp::path!("headers" / u64)
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |from: u64| warp::reply::json(&blockchain.lock().unwrap().headers(from))
+        });
+
+    // Merkle proofs light clients check against the headers they have synced
+    let get_transaction_proof = warp::path!("proof" / "transaction" / String)
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |tx_hash: String| {
+                match blockchain.lock().unwrap().transaction_proof(&tx_hash) {
+                    Some(proof) => warp::reply::with_status(warp::reply::json(&proof), StatusCode::OK),
+                    None => warp::reply::with_status(warp::reply::json(&"Transaction not found"), StatusCode::NOT_FOUND),
+                }
+            }
+        });
+
+    let get_account_proof = warp::path!("proof" / "account" / String)
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |address: String| {
+                match blockchain.lock().unwrap().account_proof(&address) {
+                    Some(proof) => warp::reply::with_status(warp::reply::json(&proof), StatusCode::OK),
+                    None => warp::reply::with_status(warp::reply::json(&"Account not found"), StatusCode::NOT_FOUND),
+                }
+            }
         });
 
     // Get block by height endpoint
@@ -95,6 +237,150 @@ pub async fn start_api(network: Arc<Network>) {
         .and(warp::get())
         .map(|| warp::reply::json(&"List of contracts"));
 
+    // Get the code a contract ran at a given version, including earlier ones replaced by upgrades
+    let get_contract_code = warp::path!("contract" / String / "code" / u32)
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |address: String, version: u32| {
+                match blockchain.lock().unwrap().contract_code(&address, version) {
+                    Some((code, abi)) => {
+                        let disassembly = code.trim().strip_prefix("0x")
+                            .and_then(security::from_hex)
+                            .and_then(|bytecode| disassemble(&bytecode).ok());
+                        warp::reply::with_status(warp::reply::json(&ContractCode { address, version, code, abi, disassembly }), StatusCode::OK)
+                    }
+                    None => warp::reply::with_status(warp::reply::json(&"Contract version not found"), StatusCode::NOT_FOUND),
+                }
+            }
+        });
+
+    // Metadata and supply of a fungible token
+    let get_token = warp::path!("token" / String)
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |id: String| {
+                match blockchain.lock().unwrap().tokens.token(&id) {
+                    Ok(token) => warp::reply::with_status(warp::reply::json(token), StatusCode::OK),
+                    Err(e) => warp::reply::with_status(warp::reply::json(&e), StatusCode::NOT_FOUND),
+                }
+            }
+        });
+
+    // Balance of a token held by an account
+    let get_token_balance = warp::path!("token" / String / "balance" / String)
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |id: String, address: String| {
+                let balance = blockchain.lock().unwrap().tokens.balance(&address, &id);
+                warp::reply::json(&TokenBalance { token: id, address, balance })
+            }
+        });
+
+    // Settings and tokens of an NFT collection
+    let get_collection = warp::path!("nft" / String)
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |id: String| {
+                match blockchain.lock().unwrap().nfts.collection(&id) {
+                    Ok(collection) => warp::reply::with_status(warp::reply::json(collection), StatusCode::OK),
+                    Err(e) => warp::reply::with_status(warp::reply::json(&e), StatusCode::NOT_FOUND),
+                }
+            }
+        });
+
+    // Owner, metadata URI and approval of one NFT
+    let get_nft = warp::path!("nft" / String / u64)
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |id: String, token_id: u64| {
+                match blockchain.lock().unwrap().nfts.nft(&id, token_id) {
+                    Ok(nft) => warp::reply::with_status(warp::reply::json(nft), StatusCode::OK),
+                    Err(e) => warp::reply::with_status(warp::reply::json(&e), StatusCode::NOT_FOUND),
+                }
+            }
+        });
+
+    // Royalty owed on a sale of the NFT at the given price; null if the collection sets none
+    let get_nft_royalty = warp::path!("nft" / String / u64 / "royalty" / u64)
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |id: String, token_id: u64, sale_price: u64| {
+                match blockchain.lock().unwrap().nfts.royalty_info(&id, token_id, sale_price) {
+                    Ok(royalty) => {
+                        let royalty = royalty.map(|(recipient, amount)| RoyaltyInfo { recipient, amount });
+                        warp::reply::with_status(warp::reply::json(&royalty), StatusCode::OK)
+                    }
+                    Err(e) => warp::reply::with_status(warp::reply::json(&e), StatusCode::NOT_FOUND),
+                }
+            }
+        });
+
+    // Every NFT an account owns
+    let get_owned_nfts = warp::path!("nfts" / String)
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |owner: String| {
+                let owned: Vec<OwnedNft> = blockchain.lock().unwrap().nfts.tokens_of(&owner).into_iter()
+                    .map(|(collection, token_id)| OwnedNft { collection, token_id })
+                    .collect();
+                warp::reply::json(&owned)
+            }
+        });
+
+    // Amount, parties, hash lock and timeout of an open hash-time-lock
+    let get_htlc = warp::path!("htlc" / String)
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |id: String| {
+                match blockchain.lock().unwrap().htlcs.get(&id) {
+                    Ok(htlc) => warp::reply::with_status(warp::reply::json(htlc), StatusCode::OK),
+                    Err(e) => warp::reply::with_status(warp::reply::json(&e), StatusCode::NOT_FOUND),
+                }
+            }
+        });
+
+    // Query contract events, e.g. /events?address=...&topic=Transfer&from_block=10&to_block=20
+    let get_events = warp::path("events")
+        .and(warp::get())
+        .and(warp::query::<EventFilter>())
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |filter: EventFilter| warp::reply::json(&blockchain.lock().unwrap().events(&filter))
+        });
+
+    // Live feed of blocks, transactions, contract events and reorgs
+    let events_feed = warp::path!("ws" / "events")
+        .and(warp::ws())
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |ws: warp::ws::Ws| {
+                let event_manager = blockchain.lock().unwrap().event_manager.clone();
+                ws.on_upgrade(move |socket| stream_events(socket, event_manager))
+            }
+        });
+
+    // Base fee of the next block and suggested tips from recent blocks
+    let get_fees = warp::path("fees")
+        .and(warp::get())
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move || warp::reply::json(&blockchain.lock().unwrap().fee_estimate())
+        });
+
+    // Preview a transaction without committing it: result, gas used, events and state changes
+    let simulate = warp::path("simulate")
+        .and(warp::post())
+        .and(warp::body::json())
+        .map({
+            let blockchain = Arc::clone(&blockchain);
+            move |transaction: ChainTransaction| {
+                match blockchain.lock().unwrap().simulate_call(&transaction) {
+                    Ok(simulation) => warp::reply::with_status(warp::reply::json(&simulation), StatusCode::OK),
+                    Err(e) => warp::reply::with_status(warp::reply::json(&e), StatusCode::BAD_REQUEST),
+                }
+            }
+        });
+
     // Get transactions endpoint
     let get_transactions = warp::path("transactions")
         .and(warp::get())
@@ -140,8 +426,24 @@ pub async fn start_api(network: Arc<Network>) {
         .or(get_balance)
         .or(send_transaction)
         .or(get_transaction)
+        .or(get_receipt)
+        .or(get_headers)
+        .or(get_transaction_proof)
+        .or(get_account_proof)
         .or(get_block)
         .or(get_contracts)
+        .or(get_contract_code)
+        .or(get_token)
+        .or(get_token_balance)
+        .or(get_collection)
+        .or(get_nft)
+        .or(get_nft_royalty)
+        .or(get_owned_nfts)
+        .or(get_htlc)
+        .or(get_events)
+        .or(events_feed)
+        .or(get_fees)
+        .or(simulate)
         .or(get_transactions)
         .or(add_peer)
         .or(discover_peers)
@@ -1,5 +1,6 @@
-use sha2::{Sha256, Digest};
 use serde::{Serialize, Deserialize};
+use crate::core::merkle::{hash_str, merkle_root};
+use crate::core::transaction::Transaction;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Block {
@@ -9,6 +10,54 @@ pub struct Block {
     pub previous_hash: String,
     pub hash: String,
     pub nonce: u64,
+    #[serde(default)]
+    pub signer: String, // Address of the authority or validator that produced the block
+    #[serde(default)]
+    pub seal: String, // Hex signature of the signer over the block hash
+    #[serde(default)]
+    pub tx_root: String, // Merkle root of the transaction ids in `data`
+    #[serde(default)]
+    pub state_root: String, // Merkle root of the account balances after this block
+    #[serde(default)]
+    pub beneficiary: String, // Account the gas fees are paid to; fees are burned if empty
+    #[serde(default)]
+    pub gas_limit: u64,
+    #[serde(default)]
+    pub gas_used: u64, // Sum over the block's receipts
+    #[serde(default)]
+    pub base_fee: u64, // Burned per unit of gas; set from the parent block's gas use
+}
+
+// Everything a light client needs to follow the chain without the block body
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockHeader {
+    pub index: u64,
+    pub timestamp: u128,
+    pub previous_hash: String,
+    pub hash: String,
+    pub nonce: u64,
+    pub signer: String,
+    pub seal: String,
+    pub tx_root: String,
+    pub state_root: String,
+    #[serde(default)]
+    pub beneficiary: String,
+    #[serde(default)]
+    pub gas_limit: u64,
+    #[serde(default)]
+    pub gas_used: u64,
+    #[serde(default)]
+    pub base_fee: u64,
+}
+
+impl BlockHeader {
+    pub fn calculate_hash(&self) -> String {
+        hash_str(&format!(
+            "{}{}{}{}{}{}{}{}{}{}{}",
+            self.index, self.timestamp, self.tx_root, self.state_root, self.previous_hash, self.nonce, self.signer,
+            self.beneficiary, self.gas_limit, self.gas_used, self.base_fee
+        ))
+    }
 }
 
 impl Block {
@@ -16,27 +65,87 @@ impl Block {
         let mut block = Block {
             index,
             timestamp,
+            tx_root: Block::compute_tx_root(&data),
             data,
             previous_hash,
             hash: String::new(),
             nonce: 0,
+            signer: String::new(),
+            seal: String::new(),
+            state_root: String::new(),
+            beneficiary: String::new(),
+            gas_limit: 0,
+            gas_used: 0,
+            base_fee: 0,
         };
         block.hash = block.calculate_hash();
         block
     }
 
+    // Rebuilds a block whose body is not available, e.g. the base block of a checkpoint sync
+    pub fn from_header(header: BlockHeader) -> Self {
+        Block {
+            index: header.index,
+            timestamp: header.timestamp,
+            data: String::new(),
+            previous_hash: header.previous_hash,
+            hash: header.hash,
+            nonce: header.nonce,
+            signer: header.signer,
+            seal: header.seal,
+            tx_root: header.tx_root,
+            state_root: header.state_root,
+            beneficiary: header.beneficiary,
+            gas_limit: header.gas_limit,
+            gas_used: header.gas_used,
+            base_fee: header.base_fee,
+        }
+    }
+
+    // Pruned blocks keep their header fields but drop the transaction data
+    pub fn has_body(&self) -> bool {
+        !self.data.is_empty()
+    }
+
+    pub fn transactions(&self) -> Vec<Transaction> {
+        serde_json::from_str(&self.data).unwrap_or_default()
+    }
+
+    // Blocks carrying a transaction list commit to their ids; any other payload is a single leaf
+    pub fn compute_tx_root(data: &str) -> String {
+        match serde_json::from_str::<Vec<Transaction>>(data) {
+            Ok(transactions) => merkle_root(&transactions.iter().map(Transaction::id).collect::<Vec<_>>()),
+            Err(_) => merkle_root(&[hash_str(data)]),
+        }
+    }
+
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            timestamp: self.timestamp,
+            previous_hash: self.previous_hash.clone(),
+            hash: self.hash.clone(),
+            nonce: self.nonce,
+            signer: self.signer.clone(),
+            seal: self.seal.clone(),
+            tx_root: self.tx_root.clone(),
+            state_root: self.state_root.clone(),
+            beneficiary: self.beneficiary.clone(),
+            gas_limit: self.gas_limit,
+            gas_used: self.gas_used,
+            base_fee: self.base_fee,
+        }
+    }
+
     pub fn calculate_hash(&self) -> String {
-        let data = format!("{}{}{}{}{}", self.index, self.timestamp, self.data, self.previous_hash, self.nonce);
-        let mut hasher = sha2::Sha256::new();
-        hasher.update(data);
-        format!("{:x}", hasher.finalize())
+        self.header().calculate_hash()
     }
 
     pub fn mine_block(&mut self, difficulty: usize) {
         let target = "0".repeat(difficulty);
-        while &self.hash[..difficulty] != target {
+        while self.hash[..difficulty] != target {
             self.nonce += 1;
             self.hash = self.calculate_hash();
         }
     }
-} 
\ No newline at end of file
+}
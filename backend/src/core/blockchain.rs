@@ -1,15 +1,118 @@
-use crate::core::block::Block;
-use crate::core::transaction::{Transaction, TransactionPool};
-use std::collections::HashMap;
-use crate::smart_contracts::{SmartContract, VirtualMachine};
-use rand::Rng;
+use crate::core::block::{Block, BlockHeader};
+use crate::core::checkpoint::Checkpoint;
+use crate::core::events::{ChainEvent, EventFilter, EventIndex, EventManager, EventRecord, IndexEntry};
+use crate::core::fees::{self, effective_price, FeeEstimate, FEE_HISTORY_BLOCKS, INITIAL_BASE_FEE};
+use crate::core::htlc::{htlc_id, Htlc, HtlcRegistry};
+use crate::core::light_client::{account_leaf, state_leaves, AccountProof, TransactionProof};
+use crate::core::merkle::{hash_str, merkle_root, MerkleProof};
+use crate::core::nft::{collection_id, NftRegistry};
+use crate::core::oracle::Oracle;
+use crate::core::transaction::{Transaction, TransactionKind, TransactionPool, INTRINSIC_GAS};
+use crate::core::poa::Clique;
+use crate::core::pruning::NodeMode;
+use crate::core::receipt::{ExecutionStatus, Log, Receipt};
+use crate::core::simulation::{Simulation, StateDiff};
+use crate::core::token::{token_id, TokenLedger, TokenParams};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use crate::storage::Storage;
+use crate::smart_contracts::{analyzer, CallContext, ContractAbi, ContractError, ContractStorage, PendingUpgrade, ReentrancyPolicy, Severity, SmartContract, Value, WorldState};
 use rayon::prelude::*;
+use ring::signature::Ed25519KeyPair;
+use crate::security;
+use serde::{Serialize, Deserialize};
+
+const TIP_KEY: &str = "meta:tip";
+const EVENT_INDEX_PREFIX: &str = "events:";
+
+// Address oracle logs are recorded under
+pub const ORACLE_ADDRESS: &str = "oracle";
+
+pub const DEFAULT_BLOCK_GAS_LIMIT: u64 = 1_000_000;
+
+// States an in-memory node keeps by default; without a store older ones cannot be read back
+pub const DEFAULT_MEMORY_HISTORY: u64 = 1024;
+
+fn block_key(index: u64) -> String {
+    format!("block:{}", index)
+}
+
+fn state_key(index: u64) -> String {
+    format!("state:{}", index)
+}
+
+fn block_receipts_key(index: u64) -> String {
+    format!("receipts:{}", index)
+}
+
+// Contracts live at an address derived from the deploying account and its nonce
+pub fn contract_address(deployer: &str, nonce: u64) -> String {
+    hash_str(&format!("contract:{}:{}", deployer, nonce))
+}
+
+fn receipt_key(transaction_id: &str) -> String {
+    format!("receipt:{}", transaction_id)
+}
+
+// The block comes last so that one key's entries are stored in order
+fn event_index_key(entry: &IndexEntry) -> String {
+    match entry {
+        IndexEntry::Address(address, block) => format!("{}address:{}:{:020}", EVENT_INDEX_PREFIX, address, block),
+        IndexEntry::Topic(topic, block) => format!("{}topic:{}:{:020}", EVENT_INDEX_PREFIX, topic, block),
+    }
+}
+
+// Chain state after a given block, kept to roll back on reorgs and for historical queries
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StateSnapshot {
+    pub balances: HashMap<String, u64>,
+    #[serde(default)]
+    pub nonces: HashMap<String, u64>,
+    pub contracts: HashMap<String, SmartContract>,
+    pub clique: Option<Clique>,
+    #[serde(default)]
+    pub oracle: Oracle,
+    #[serde(default)]
+    pub tokens: TokenLedger,
+    #[serde(default)]
+    pub nfts: NftRegistry,
+    #[serde(default)]
+    pub htlcs: HtlcRegistry,
+}
 
 pub struct Blockchain {
     pub chain: Vec<Block>,
     pub difficulty: usize,
     pub transaction_pool: TransactionPool,
     pub balances: HashMap<String, u64>,
+    pub nonces: HashMap<String, u64>, // Last nonce used by each sender
+    pub clique: Option<Clique>,
+    pub validators: Option<HashSet<String>>, // Trusted proof-of-stake validators, if known
+    pub contracts: HashMap<String, SmartContract>, // Deployed contracts by address
+    pub oracle: Oracle,
+    pub tokens: TokenLedger, // Fungible tokens and the token balances of every account
+    pub nfts: NftRegistry, // Non-fungible token collections and their owners
+    pub htlcs: HtlcRegistry, // Funds held under open hash-time-locks
+    pub checkpoint_interval: u64,
+    pub mode: NodeMode,
+    pub finality_window: u64, // Blocks that can still be reorganised; never pruned
+    pub storage: Option<Storage>,
+    pub state_history: BTreeMap<u64, StateSnapshot>,
+    pub memory_history: u64, // Most states kept without a store; never less than the finality window
+    pub pruned_bodies_below: u64,
+    pub pruned_states_below: u64,
+    pub receipts: HashMap<String, Receipt>, // Indexed by transaction id
+    pub event_index: EventIndex,
+    pub event_manager: EventManager, // Notifies subscribers of new blocks, transactions and reorgs
+    pub analysis_threshold: Option<Severity>, // Findings at or above this block deploys and upgrades
+    pub block_gas_limit: u64, // Most gas the transactions of one block may reserve
+    pub coinbase: String, // Receives the fees of blocks this node mines with proof of work
+    pub validator_key: Option<Ed25519KeyPair>, // Seals the proof-of-stake blocks this node produces
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Blockchain::new()
+    }
 }
 
 impl Blockchain {
@@ -19,16 +122,282 @@ impl Blockchain {
             difficulty: 2,
             transaction_pool: TransactionPool::new(),
             balances: HashMap::new(),
+            nonces: HashMap::new(),
+            clique: None,
+            validators: None,
+            contracts: HashMap::new(),
+            oracle: Oracle::default(),
+            tokens: TokenLedger::default(),
+            nfts: NftRegistry::default(),
+            htlcs: HtlcRegistry::default(),
+            checkpoint_interval: 1000,
+            mode: NodeMode::Archive,
+            finality_window: 64,
+            storage: None,
+            state_history: BTreeMap::new(),
+            memory_history: DEFAULT_MEMORY_HISTORY,
+            pruned_bodies_below: 0,
+            pruned_states_below: 0,
+            receipts: HashMap::new(),
+            event_index: EventIndex::default(),
+            event_manager: EventManager::default(),
+            analysis_threshold: Some(Severity::Error),
+            block_gas_limit: DEFAULT_BLOCK_GAS_LIMIT,
+            coinbase: String::new(),
+            validator_key: None,
         };
         blockchain.chain.push(Block::new(0, 0, "[]".to_string(), "0".to_string()));
         blockchain
     }
 
+    // Creates a proof-of-authority chain sealed by the given signer addresses (hex public keys)
+    pub fn with_authorities(signers: Vec<String>) -> Self {
+        let mut blockchain = Blockchain::new();
+        blockchain.clique = Some(Clique::new(signers));
+        blockchain
+    }
+
+    // Opens a node backed by the persistent store, resuming from the stored tip if there is one
+    pub fn open(storage: Storage, mode: NodeMode) -> Self {
+        let mut blockchain = Blockchain::new();
+        blockchain.mode = mode;
+        match storage.get::<u64>(TIP_KEY) {
+            Some(tip) => {
+                blockchain.chain = (0..=tip).filter_map(|index| storage.get(&block_key(index))).collect();
+                if let Some(snapshot) = storage.get(&state_key(tip)) {
+                    blockchain.restore(snapshot);
+                }
+                blockchain.pruned_bodies_below = mode.body_floor(tip, blockchain.finality_window);
+                blockchain.pruned_states_below = mode.state_floor(tip, blockchain.finality_window);
+                for entry in storage.scan_prefix::<IndexEntry>(EVENT_INDEX_PREFIX) {
+                    blockchain.event_index.insert(&entry);
+                }
+            }
+            None => {
+                storage.put(&block_key(0), &blockchain.chain[0]);
+                storage.put(&state_key(0), &blockchain.snapshot());
+                storage.put(TIP_KEY, &0u64);
+            }
+        }
+        blockchain.storage = Some(storage);
+        blockchain.prune();
+        blockchain
+    }
+
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot {
+            balances: self.balances.clone(),
+            nonces: self.nonces.clone(),
+            contracts: self.contracts.clone(),
+            clique: self.clique.clone(),
+            oracle: self.oracle.clone(),
+            tokens: self.tokens.clone(),
+            nfts: self.nfts.clone(),
+            htlcs: self.htlcs.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: StateSnapshot) {
+        self.balances = snapshot.balances;
+        self.nonces = snapshot.nonces;
+        self.contracts = snapshot.contracts;
+        self.clique = snapshot.clique;
+        self.oracle = snapshot.oracle;
+        self.tokens = snapshot.tokens;
+        self.nfts = snapshot.nfts;
+        self.htlcs = snapshot.htlcs;
+    }
+
+    fn record_state(&mut self, index: u64) {
+        let snapshot = self.snapshot();
+        if let Some(storage) = &self.storage {
+            storage.put(&state_key(index), &snapshot);
+            // Latest storage of each contract, rewritten only when it changed
+            let previous = self.state_history.range(..index).next_back().map(|(_, previous)| previous);
+            for (address, contract) in &self.contracts {
+                let unchanged = previous
+                    .and_then(|previous| previous.contracts.get(address))
+                    .is_some_and(|old| old.state == contract.state);
+                if !unchanged {
+                    storage.store_state(address, &contract.state);
+                }
+            }
+        }
+        self.state_history.insert(index, snapshot);
+    }
+
+    // Every new block goes through here so it is persisted and old history pruned
+    fn append_block(&mut self, block: Block, mut receipts: Vec<Receipt>) {
+        let index = block.index;
+        for receipt in receipts.iter_mut() {
+            receipt.block_index = index;
+            receipt.block_hash = block.hash.clone();
+        }
+        let logs: Vec<&Log> = receipts.iter().flat_map(|receipt| &receipt.logs).collect();
+        let indexed = self.event_index.add(index, &logs);
+        if let Some(storage) = &self.storage {
+            storage.put(&block_key(index), &block);
+            storage.put(&block_receipts_key(index), &receipts);
+            for receipt in &receipts {
+                storage.put(&receipt_key(&receipt.transaction_id), receipt);
+            }
+            for entry in &indexed {
+                storage.put(&event_index_key(entry), entry);
+            }
+            storage.put(TIP_KEY, &index);
+        }
+        self.publish_block(&block, &receipts);
+        for receipt in receipts {
+            self.receipts.insert(receipt.transaction_id.clone(), receipt);
+        }
+        self.chain.push(block);
+        self.record_state(index);
+        self.prune();
+    }
+
+    fn publish_block(&self, block: &Block, receipts: &[Receipt]) {
+        self.event_manager.publish(ChainEvent::NewBlock {
+            index: block.index,
+            hash: block.hash.clone(),
+            transactions: receipts.len(),
+        });
+        for record in EventRecord::from_receipts(receipts) {
+            self.event_manager.publish(ChainEvent::Contract(record));
+        }
+    }
+
+    // Drops block bodies and historical state according to the node mode. With a store attached,
+    // memory only holds what the finality window needs and the rest is read back from disk.
+    // Without one, states older than `memory_history` blocks are dropped whatever the mode.
+    pub fn prune(&mut self) {
+        let tip = match self.chain.last() {
+            Some(block) => block.index,
+            None => return,
+        };
+        let body_floor = self.mode.body_floor(tip, self.finality_window);
+        let state_floor = self.mode.state_floor(tip, self.finality_window);
+
+        let (memory_body_floor, memory_state_floor) = if self.storage.is_some() {
+            let window_floor = (tip + 1).saturating_sub(self.finality_window);
+            (body_floor.max(window_floor), state_floor.max(window_floor))
+        } else {
+            let history_floor = (tip + 1).saturating_sub(self.memory_history.max(self.finality_window));
+            (body_floor, state_floor.max(history_floor))
+        };
+        for block in self.chain.iter_mut().filter(|block| block.index < memory_body_floor) {
+            block.data.clear();
+        }
+        self.state_history.retain(|index, _| *index >= memory_state_floor);
+
+        if let Some(storage) = &self.storage {
+            for index in self.pruned_bodies_below..body_floor {
+                if let Some(mut block) = storage.get::<Block>(&block_key(index)) {
+                    block.data.clear();
+                    storage.put(&block_key(index), &block);
+                }
+            }
+            for index in self.pruned_states_below..state_floor {
+                storage.remove(&state_key(index));
+            }
+        }
+        self.pruned_bodies_below = self.pruned_bodies_below.max(body_floor);
+        self.pruned_states_below = self.pruned_states_below.max(state_floor);
+    }
+
+    // Full block by height, falling back to the store for bodies pruned from memory
+    pub fn block(&self, index: u64) -> Option<Block> {
+        let offset = index.checked_sub(self.chain.first()?.index)?;
+        let block = self.chain.get(offset as usize)?;
+        if !block.has_body() {
+            if let Some(stored) = self.storage.as_ref().and_then(|storage| storage.get::<Block>(&block_key(index))) {
+                return Some(stored);
+            }
+        }
+        Some(block.clone())
+    }
+
+    pub fn receipt(&self, transaction_id: &str) -> Option<Receipt> {
+        self.receipts.get(transaction_id).cloned()
+            .or_else(|| self.storage.as_ref()?.get(&receipt_key(transaction_id)))
+    }
+
+    pub fn block_receipts(&self, index: u64) -> Vec<Receipt> {
+        match self.block(index) {
+            Some(block) if block.has_body() => block.transactions().iter()
+                .filter_map(|transaction| self.receipt(&transaction.id()))
+                .collect(),
+            _ => self.storage.as_ref()
+                .and_then(|storage| storage.get(&block_receipts_key(index)))
+                .unwrap_or_default(),
+        }
+    }
+
+    // Drops the receipts of blocks above the given height, e.g. when they are reorganised away
+    fn discard_receipts_above(&mut self, index: u64) {
+        let discarded: Vec<Receipt> = self.receipts.values().filter(|receipt| receipt.block_index > index).cloned().collect();
+        if let Some(storage) = &self.storage {
+            for receipt in &discarded {
+                storage.remove(&receipt_key(&receipt.transaction_id));
+                storage.remove(&block_receipts_key(receipt.block_index));
+            }
+        }
+        self.receipts.retain(|_, receipt| receipt.block_index <= index);
+        let discarded = self.event_index.discard_above(index);
+        if let Some(storage) = &self.storage {
+            for entry in &discarded {
+                storage.remove(&event_index_key(entry));
+            }
+        }
+    }
+
+    // Events matching the filter in block order, read through the event index
+    pub fn events(&self, filter: &EventFilter) -> Vec<EventRecord> {
+        let tip = self.chain.last().map_or(0, |block| block.index);
+        let to_block = filter.to_block.map_or(tip, |to| to.min(tip));
+        self.event_index.candidate_blocks(filter, to_block).into_iter()
+            .flat_map(|index| EventRecord::from_receipts(&self.block_receipts(index)))
+            .filter(|record| filter.matches(record))
+            .collect()
+    }
+
+    // Rewrites blocks and states held in memory back to the store, e.g. after an aborted reorg
+    fn persist_range(&self, from: u64, to: u64) {
+        if let Some(storage) = &self.storage {
+            for index in from..=to {
+                if let Some(block) = self.chain.iter().find(|block| block.index == index) {
+                    storage.put(&block_key(index), block);
+                }
+                if let Some(snapshot) = self.state_history.get(&index) {
+                    storage.put(&state_key(index), snapshot);
+                }
+                let receipts: Vec<&Receipt> = self.receipts.values().filter(|receipt| receipt.block_index == index).collect();
+                storage.put(&block_receipts_key(index), &receipts);
+                for receipt in receipts {
+                    storage.put(&receipt_key(&receipt.transaction_id), receipt);
+                }
+            }
+            for entry in self.event_index.entries().filter(|entry| (from..=to).contains(&entry.block_index())) {
+                storage.put(&event_index_key(&entry), &entry);
+            }
+            storage.put(TIP_KEY, &to);
+        }
+    }
+
+    pub fn state_at(&self, index: u64) -> Option<StateSnapshot> {
+        self.state_history.get(&index).cloned()
+            .or_else(|| self.storage.as_ref()?.get(&state_key(index)))
+    }
+
     pub fn add_block(&mut self, use_pow: bool) {
         if use_pow {
             self.add_block_with_pow();
+        } else if let Some(keypair) = self.validator_key.take() {
+            if let Err(e) = self.add_block_with_pos(&keypair) {
+                println!("{}", e);
+            }
+            self.validator_key = Some(keypair);
         } else {
-            self.add_block_with_pos();
+            println!("No validator key to seal a proof-of-stake block with");
         }
     }
 
@@ -38,10 +407,8 @@ impl Blockchain {
             return;
         }
 
-        let previous_block = self.chain.last().expect("Expected a previous block");
         let transactions = self.validate_transactions();
-        let data = serde_json::to_string(&transactions).expect("Failed to serialize transactions");
-        let mut new_block = Block::new(self.chain.len() as u64, 0, data, previous_block.hash.clone());
+        let (mut new_block, receipts) = self.prepare_block(transactions, String::new());
 
         for nonce in 0..10_000_000 {
             new_block.nonce = nonce;
@@ -52,14 +419,748 @@ impl Blockchain {
             }
         }
 
-        self.chain.push(new_block);
-        self.transaction_pool.clear();
-        self.update_balances();
+        self.append_block(new_block, receipts);
+        self.retain_pending();
+    }
+
+    // Applies the transactions to the account state and builds the next block, committing
+    // to the included transactions and the resulting state
+    fn prepare_block(&mut self, transactions: Vec<Transaction>, signer: String) -> (Block, Vec<Receipt>) {
+        let previous_block = self.chain.last().expect("Expected a previous block");
+        let (previous_index, previous_hash) = (previous_block.index, previous_block.hash.clone());
+        self.record_state(previous_index);
+        // Authorities and validators are paid themselves; proof-of-work blocks pay the coinbase
+        let beneficiary = if signer.is_empty() { self.coinbase.clone() } else { signer.clone() };
+        let base_fee = self.next_base_fee();
+        let (included, receipts) = self.execute_transactions(transactions, &beneficiary, self.block_gas_limit);
+
+        let data = serde_json::to_string(&included).expect("Failed to serialize transactions");
+        let mut new_block = Block::new(self.next_index(), 0, data, previous_hash);
+        new_block.signer = signer;
+        new_block.beneficiary = beneficiary;
+        new_block.gas_limit = self.block_gas_limit;
+        new_block.gas_used = receipts.iter().map(|receipt| receipt.gas_used).sum();
+        new_block.base_fee = base_fee;
+        new_block.state_root = self.state_root();
+        new_block.hash = new_block.calculate_hash();
+        (new_block, receipts)
+    }
+
+    // Applies the transactions in order, skipping any that are no longer valid, fall outside
+    // their height window or whose gas limit no longer fits in the block. Returns the
+    // transactions that were applied along with their receipts.
+    fn execute_transactions(&mut self, transactions: Vec<Transaction>, beneficiary: &str, gas_limit: u64) -> (Vec<Transaction>, Vec<Receipt>) {
+        let mut included = Vec::new();
+        let mut receipts = Vec::new();
+        let mut gas_reserved = 0;
+        let base_fee = self.next_base_fee();
+        for transaction in transactions {
+            if transaction.gas_limit > gas_limit - gas_reserved || !transaction.is_valid_at(self.next_index()) {
+                continue;
+            }
+            if self.validate_transaction(&transaction) {
+                gas_reserved += transaction.gas_limit;
+                receipts.push(self.execute_transaction(&transaction, beneficiary, base_fee));
+                included.push(transaction);
+            }
+        }
+        (included, receipts)
+    }
+
+    fn execute_transaction(&mut self, transaction: &Transaction, beneficiary: &str, base_fee: u64) -> Receipt {
+        self.nonces.insert(transaction.sender.clone(), transaction.nonce);
+        self.buy_gas(transaction);
+        let mut receipt = match &transaction.kind {
+            TransactionKind::Deploy { code, reentrancy, upgrade_delay } => self.execute_deploy(transaction, code, *reentrancy, *upgrade_delay),
+            TransactionKind::Call { function, args } => self.execute_call(transaction, function, args),
+            TransactionKind::Upgrade { code, migration } => self.execute_upgrade(transaction, code, migration),
+            TransactionKind::ApplyUpgrade => self.execute_apply_upgrade(transaction),
+            TransactionKind::Pause => self.execute_admin_action(transaction, "ContractPaused", String::new(), |contract, sender| contract.pause(sender)),
+            TransactionKind::Resume => self.execute_admin_action(transaction, "ContractResumed", String::new(), |contract, sender| contract.resume(sender)),
+            TransactionKind::GrantRole { role, account } => {
+                self.execute_admin_action(transaction, "RoleGranted", format!("{} {}", role, account), |contract, sender| contract.grant_role(sender, role, account))
+            }
+            TransactionKind::RevokeRole { role, account } => {
+                self.execute_admin_action(transaction, "RoleRevoked", format!("{} {}", role, account), |contract, sender| contract.revoke_role(sender, role, account))
+            }
+            TransactionKind::RequireRole { function, role } => {
+                let data = format!("{} {}", function, role.as_deref().unwrap_or(""));
+                self.execute_admin_action(transaction, "RoleRequired", data.trim_end().to_string(), |contract, sender| contract.require_role(sender, function, role.as_deref()))
+            }
+            TransactionKind::Terminate { beneficiary } => self.execute_terminate(transaction, beneficiary),
+            TransactionKind::Report { feed, round, value } => self.execute_report(transaction, feed, *round, *value),
+            TransactionKind::Transfer if !transaction.tokens.is_empty() => {
+                self.execute_token_operation(transaction, |tokens| tokens.transfer_all(&transaction.sender, &transaction.receiver, &transaction.tokens))
+            }
+            TransactionKind::CreateToken { name, symbol, decimals, supply, max_supply } => {
+                let id = token_id(&transaction.sender, transaction.nonce);
+                let mut receipt = self.execute_token_operation(transaction, |tokens| {
+                    let params = TokenParams {
+                        name: name.clone(),
+                        symbol: symbol.clone(),
+                        decimals: *decimals,
+                        supply: *supply,
+                        max_supply: *max_supply,
+                    };
+                    tokens.create(id.clone(), &transaction.sender, params)
+                });
+                if receipt.is_success() {
+                    receipt.return_value = Some(id);
+                }
+                receipt
+            }
+            TransactionKind::MintToken { token, amount } => {
+                self.execute_token_operation(transaction, |tokens| tokens.mint(token, &transaction.sender, &transaction.receiver, *amount))
+            }
+            TransactionKind::BurnToken { token, amount } => {
+                self.execute_token_operation(transaction, |tokens| tokens.burn(token, &transaction.sender, *amount))
+            }
+            TransactionKind::ApproveToken { token, amount } => {
+                self.execute_token_operation(transaction, |tokens| tokens.approve(token, &transaction.sender, &transaction.receiver, *amount))
+            }
+            TransactionKind::TransferTokenFrom { token, holder, amount } => {
+                self.execute_token_operation(transaction, |tokens| tokens.transfer_from(token, &transaction.sender, holder, &transaction.receiver, *amount))
+            }
+            TransactionKind::CreateCollection { name, symbol, royalty } => {
+                let id = collection_id(&transaction.sender, transaction.nonce);
+                let mut receipt = self.execute_nft_operation(transaction, |nfts| {
+                    nfts.create(id.clone(), &transaction.sender, name.clone(), symbol.clone(), royalty.clone())
+                });
+                if receipt.is_success() {
+                    receipt.return_value = Some(id);
+                }
+                receipt
+            }
+            TransactionKind::MintNft { collection, uri } => {
+                let token_id = self.nfts.next_token_id(collection).ok();
+                let mut receipt = self.execute_nft_operation(transaction, |nfts| nfts.mint(collection, &transaction.sender, &transaction.receiver, uri.clone()));
+                if receipt.is_success() {
+                    receipt.return_value = token_id.map(|id| id.to_string());
+                }
+                receipt
+            }
+            TransactionKind::TransferNft { collection, token_id } => {
+                self.execute_nft_operation(transaction, |nfts| nfts.transfer(collection, &transaction.sender, &transaction.receiver, *token_id))
+            }
+            TransactionKind::ApproveNft { collection, token_id } => {
+                let approved = Some(transaction.receiver.clone()).filter(|receiver| !receiver.is_empty());
+                self.execute_nft_operation(transaction, |nfts| nfts.approve(collection, &transaction.sender, *token_id, approved))
+            }
+            TransactionKind::SetNftOperator { collection, approved } => {
+                self.execute_nft_operation(transaction, |nfts| nfts.set_operator(collection, &transaction.sender, &transaction.receiver, *approved))
+            }
+            TransactionKind::BurnNft { collection, token_id } => {
+                self.execute_nft_operation(transaction, |nfts| nfts.burn(collection, &transaction.sender, *token_id))
+            }
+            TransactionKind::LockHtlc { hash_lock, timeout } => self.execute_lock(transaction, hash_lock, *timeout),
+            TransactionKind::ClaimHtlc { htlc, preimage } => {
+                let height = self.next_index();
+                self.execute_htlc_release(transaction, |htlcs| htlcs.claim(htlc, preimage, height))
+            }
+            TransactionKind::RefundHtlc { htlc } => {
+                let height = self.next_index();
+                self.execute_htlc_release(transaction, |htlcs| htlcs.refund(htlc, height))
+            }
+            _ => {
+                self.apply_transaction(transaction);
+                Receipt::new(transaction.id(), ExecutionStatus::Success)
+            }
+        };
+        self.settle_gas(transaction, &mut receipt, beneficiary, base_fee);
+        receipt
+    }
+
+    // Checks code as a deployment would, then runs the static analyzer over it unless analysis
+    // is switched off
+    fn checked_deploy(&self, code: &str) -> Result<SmartContract, String> {
+        let contract = SmartContract::deploy(code.to_string())?;
+        if let Some(threshold) = self.analysis_threshold {
+            analyzer::enforce(&contract.audit()?, threshold)?;
+        }
+        Ok(contract)
+    }
+
+    // The sender pays for the whole gas limit before execution, whether or not it succeeds
+    fn buy_gas(&mut self, transaction: &Transaction) {
+        let sender_balance = self.balances.entry(transaction.sender.clone()).or_insert(0);
+        *sender_balance -= transaction.max_fee().unwrap_or(0);
+    }
+
+    // Charges the gas the receipt records on top of the intrinsic gas. The base fee part is
+    // burned, the tip goes to the block's beneficiary and the rest of what was reserved goes back
+    // to the sender.
+    fn settle_gas(&mut self, transaction: &Transaction, receipt: &mut Receipt, beneficiary: &str, base_fee: u64) {
+        let (burned_price, tip) = effective_price(base_fee, transaction.gas_price, transaction.priority_fee);
+        receipt.gas_used = (receipt.gas_used + INTRINSIC_GAS).min(transaction.gas_limit);
+        receipt.burned = receipt.gas_used * burned_price;
+        receipt.fee = receipt.burned + receipt.gas_used * tip;
+        let refund = transaction.max_fee().unwrap_or(0) - receipt.fee;
+        *self.balances.entry(transaction.sender.clone()).or_insert(0) += refund;
+        let reward = receipt.fee - receipt.burned;
+        if reward > 0 && !beneficiary.is_empty() {
+            *self.balances.entry(beneficiary.to_string()).or_insert(0) += reward;
+        }
+    }
+
+    fn execute_deploy(&mut self, transaction: &Transaction, code: &str, reentrancy: ReentrancyPolicy, upgrade_delay: Option<u64>) -> Receipt {
+        let mut receipt = Receipt::new(transaction.id(), ExecutionStatus::Success);
+        let address = contract_address(&transaction.sender, transaction.nonce);
+        if self.contracts.contains_key(&address) {
+            receipt.status = ExecutionStatus::Failed(format!("A contract already exists at {}", address));
+            return receipt;
+        }
+        match self.checked_deploy(code) {
+            Ok(mut contract) => {
+                contract.reentrancy = reentrancy;
+                contract.admin = Some(transaction.sender.clone());
+                contract.upgrade_delay = upgrade_delay.unwrap_or(0);
+                self.apply_transaction(&Transaction { receiver: address.clone(), ..transaction.clone() });
+                self.contracts.insert(address.clone(), contract);
+                receipt.return_value = Some(address);
+            }
+            Err(e) => receipt.status = ExecutionStatus::Failed(e),
+        }
+        receipt
+    }
+
+    // Runs the call on a journaled view of the chain state. A failed call, including the value
+    // it carried, is rolled back in full.
+    fn execute_call(&mut self, transaction: &Transaction, function: &str, args: &[Value]) -> Receipt {
+        let mut receipt = Receipt::new(transaction.id(), ExecutionStatus::Success);
+        // Catches calls admitted before a role was required earlier in the same block
+        if self.calls_restricted_function(transaction) && !transaction.verify_sender() {
+            receipt.status = ExecutionStatus::Failed(format!("{} is restricted and the call is not signed by its sender", function));
+            return receipt;
+        }
+        let mut context = CallContext::new(transaction.sender.clone(), transaction.receiver.clone(), transaction.execution_gas());
+        context.value = transaction.amount;
+        let (result, logs) = self.run_call(&mut context, function, args);
+        receipt.gas_used = context.gas_used;
+        match result {
+            Ok(value) => {
+                receipt.return_value = value.map(|value| value.to_string());
+                receipt.logs = logs;
+            }
+            Err(e) => receipt.status = ExecutionStatus::Failed(e.to_string()),
+        }
+        receipt
+    }
+
+    // Upgrades take effect at once unless the contract has a timelock, in which case they are
+    // scheduled and need an ApplyUpgrade transaction once the delay has passed
+    fn execute_upgrade(&mut self, transaction: &Transaction, code: &str, migration: &Option<String>) -> Receipt {
+        let mut receipt = Receipt::new(transaction.id(), ExecutionStatus::Success);
+        let ready_at = self.next_index();
+        let contract = match self.contracts.get_mut(&transaction.receiver) {
+            Some(contract) if contract.is_admin(&transaction.sender) => contract,
+            Some(_) => {
+                receipt.status = ExecutionStatus::Failed(format!("{} is not allowed to upgrade {}", transaction.sender, transaction.receiver));
+                return receipt;
+            }
+            None => {
+                receipt.status = ExecutionStatus::Failed(format!("No contract at {}", transaction.receiver));
+                return receipt;
+            }
+        };
+        let delay = contract.upgrade_delay;
+        if delay == 0 {
+            return self.run_upgrade(transaction, code.to_string(), migration.clone());
+        }
+        match self.checked_deploy(code) {
+            Ok(_) => {
+                let ready_at = ready_at + delay;
+                let contract = self.contracts.get_mut(&transaction.receiver).expect("Upgraded contract exists");
+                contract.pending_upgrade = Some(PendingUpgrade { code: code.to_string(), migration: migration.clone(), ready_at });
+                receipt.return_value = Some(ready_at.to_string());
+            }
+            Err(e) => receipt.status = ExecutionStatus::Failed(e),
+        }
+        receipt
+    }
+
+    fn execute_apply_upgrade(&mut self, transaction: &Transaction) -> Receipt {
+        let height = self.next_index();
+        let pending = self.contracts.get(&transaction.receiver)
+            .filter(|contract| contract.is_admin(&transaction.sender))
+            .and_then(|contract| contract.pending_upgrade.clone());
+        match pending {
+            Some(pending) if pending.ready_at <= height => self.run_upgrade(transaction, pending.code, pending.migration),
+            Some(pending) => {
+                let reason = format!("Upgrade of {} is timelocked until block {}", transaction.receiver, pending.ready_at);
+                Receipt::new(transaction.id(), ExecutionStatus::Failed(reason))
+            }
+            None => {
+                let reason = format!("No upgrade of {} is scheduled by {}", transaction.receiver, transaction.sender);
+                Receipt::new(transaction.id(), ExecutionStatus::Failed(reason))
+            }
+        }
+    }
+
+    // Swaps in the new code and runs the migration on it as the admin. If the migration fails,
+    // the contract keeps its old code and state.
+    fn run_upgrade(&mut self, transaction: &Transaction, code: String, migration: Option<String>) -> Receipt {
+        let mut receipt = Receipt::new(transaction.id(), ExecutionStatus::Success);
+        if let Err(e) = self.checked_deploy(&code) {
+            receipt.status = ExecutionStatus::Failed(e);
+            return receipt;
+        }
+        let height = self.next_index();
+        let contract = self.contracts.get_mut(&transaction.receiver).expect("Upgraded contract exists");
+        let previous = contract.clone();
+        if let Err(e) = contract.upgrade(&transaction.sender, code, height) {
+            receipt.status = ExecutionStatus::Failed(e);
+            return receipt;
+        }
+        let version = contract.version();
+
+        if let Some(migration) = migration {
+            let mut context = CallContext::new(transaction.sender.clone(), transaction.receiver.clone(), transaction.execution_gas());
+            let (result, logs) = self.run_call(&mut context, &migration, &[]);
+            receipt.gas_used = context.gas_used;
+            if let Err(e) = result {
+                self.contracts.insert(transaction.receiver.clone(), previous);
+                receipt.status = ExecutionStatus::Failed(format!("Migration failed: {}", e));
+                return receipt;
+            }
+            receipt.logs = logs;
+        }
+        receipt.return_value = Some(version.to_string());
+        receipt
+    }
+
+    // Applies a lifecycle or role change and records it as a log from the contract, topped by
+    // the event name and the account that made it
+    fn execute_admin_action<F>(&mut self, transaction: &Transaction, event: &str, data: String, transition: F) -> Receipt
+    where
+        F: FnOnce(&mut SmartContract, &str) -> Result<(), String>,
+    {
+        let mut receipt = Receipt::new(transaction.id(), ExecutionStatus::Success);
+        let result = match self.contracts.get_mut(&transaction.receiver) {
+            Some(contract) => transition(contract, &transaction.sender),
+            None => Err(format!("No contract at {}", transaction.receiver)),
+        };
+        match result {
+            Ok(()) => receipt.logs.push(Log {
+                address: transaction.receiver.clone(),
+                topics: vec![event.to_string(), transaction.sender.clone()],
+                data,
+            }),
+            Err(e) => receipt.status = ExecutionStatus::Failed(e),
+        }
+        receipt
+    }
+
+    fn execute_terminate(&mut self, transaction: &Transaction, beneficiary: &str) -> Receipt {
+        let receipt = self.execute_admin_action(transaction, "ContractTerminated", beneficiary.to_string(), |contract, sender| contract.terminate(sender));
+        if receipt.is_success() {
+            let remaining = self.balances.remove(&transaction.receiver).unwrap_or(0);
+            *self.balances.entry(beneficiary.to_string()).or_insert(0) += remaining;
+        }
+        receipt
+    }
+
+    // Registers a data feed; like the authority set, feeds are part of the node configuration
+    // every node starts with
+    pub fn create_feed(&mut self, name: &str, reporters: Vec<String>, quorum: usize) -> Result<(), String> {
+        self.oracle.create_feed(name, reporters, quorum)
+    }
+
+    // Reports are aggregated in block order, so every node finalizes the same median
+    // A rejected report moves no value, like a failed token operation
+    fn execute_report(&mut self, transaction: &Transaction, feed: &str, round: u64, value: i64) -> Receipt {
+        let result = self.oracle.submit(feed, &transaction.sender, round, value, self.next_index())
+            .map(|finalized| finalized.into_iter().map(|value| Log {
+                address: ORACLE_ADDRESS.to_string(),
+                topics: vec!["FeedFinalized".to_string(), feed.to_string()],
+                data: value.to_string(),
+            }).collect());
+        self.ledger_receipt(transaction, result)
+    }
+
+    // Token and NFT operations check everything before changing the ledger, so a failed one
+    // changes nothing. The native amount moves only if the operation succeeds.
+    fn execute_token_operation<F>(&mut self, transaction: &Transaction, operation: F) -> Receipt
+    where
+        F: FnOnce(&mut TokenLedger) -> Result<Vec<Log>, String>,
+    {
+        let result = operation(&mut self.tokens);
+        self.ledger_receipt(transaction, result)
+    }
+
+    fn execute_nft_operation<F>(&mut self, transaction: &Transaction, operation: F) -> Receipt
+    where
+        F: FnOnce(&mut NftRegistry) -> Result<Vec<Log>, String>,
+    {
+        let result = operation(&mut self.nfts);
+        self.ledger_receipt(transaction, result)
+    }
+
+    // Escrows the amount instead of paying it to the receiver
+    fn execute_lock(&mut self, transaction: &Transaction, hash_lock: &str, timeout: u64) -> Receipt {
+        let id = htlc_id(&transaction.sender, transaction.nonce);
+        let htlc = Htlc {
+            sender: transaction.sender.clone(),
+            receiver: transaction.receiver.clone(),
+            amount: transaction.amount,
+            hash_lock: hash_lock.to_string(),
+            timeout,
+        };
+        let mut receipt = Receipt::new(transaction.id(), ExecutionStatus::Success);
+        match self.htlcs.lock(id.clone(), htlc, self.next_index()) {
+            Ok(logs) => {
+                *self.balances.entry(transaction.sender.clone()).or_insert(0) -= transaction.amount;
+                receipt.logs = logs;
+                receipt.return_value = Some(id);
+            }
+            Err(e) => receipt.status = ExecutionStatus::Failed(e),
+        }
+        receipt
+    }
+
+    // Pays the escrowed amount to whoever the claim or refund releases it to
+    fn execute_htlc_release<F>(&mut self, transaction: &Transaction, release: F) -> Receipt
+    where
+        F: FnOnce(&mut HtlcRegistry) -> Result<(String, u64, Vec<Log>), String>,
+    {
+        let result = release(&mut self.htlcs).map(|(account, amount, logs)| {
+            *self.balances.entry(account).or_insert(0) += amount;
+            logs
+        });
+        self.ledger_receipt(transaction, result)
+    }
+
+    fn ledger_receipt(&mut self, transaction: &Transaction, result: Result<Vec<Log>, String>) -> Receipt {
+        let mut receipt = Receipt::new(transaction.id(), ExecutionStatus::Success);
+        match result {
+            Ok(logs) => {
+                self.apply_transaction(transaction);
+                receipt.logs = logs;
+            }
+            Err(e) => receipt.status = ExecutionStatus::Failed(e),
+        }
+        receipt
+    }
+
+    // Current storage of a contract, read back from the store if the contract is not loaded
+    pub fn contract_storage(&self, address: &str) -> Option<ContractStorage> {
+        match self.contracts.get(address) {
+            Some(contract) => Some(contract.state.clone()),
+            None => self.storage.as_ref().map(|storage| storage.load_state(address)),
+        }
+    }
+
+    // Code and ABI the contract ran at the given version, numbered from 1
+    pub fn contract_code(&self, address: &str, version: u32) -> Option<(String, ContractAbi)> {
+        let (code, abi) = self.contracts.get(address)?.code_at(version)?;
+        Some((code.to_string(), abi.clone()))
+    }
+
+    // Executes a call against a journaled view of the chain state and writes the outcome back.
+    // World state rolls back on failure, so only successful calls leave changes behind.
+    fn run_call(&mut self, context: &mut CallContext, function: &str, args: &[Value]) -> (Result<Option<Value>, ContractError>, Vec<Log>) {
+        let mut world = WorldState::new(std::mem::take(&mut self.contracts), std::mem::take(&mut self.balances));
+        world.oracle = std::mem::take(&mut self.oracle);
+        let result = world.call(context, function, args);
+        self.oracle = std::mem::take(&mut world.oracle);
+        let (contracts, balances, logs) = world.into_parts();
+        self.contracts = contracts;
+        self.balances = balances;
+        (result, logs)
+    }
+
+    pub fn next_index(&self) -> u64 {
+        self.chain.last().map_or(0, |block| block.index + 1)
+    }
+
+    pub fn next_base_fee(&self) -> u64 {
+        self.chain.last().map_or(INITIAL_BASE_FEE, fees::next_base_fee)
+    }
+
+    // Fees to offer for inclusion in the next block, from the tips paid in recent blocks
+    pub fn fee_estimate(&self) -> FeeEstimate {
+        let from = self.next_index().saturating_sub(FEE_HISTORY_BLOCKS).max(1);
+        let receipts: Vec<Receipt> = (from..self.next_index())
+            .flat_map(|index| self.block_receipts(index))
+            .collect();
+        FeeEstimate::from_history(self.next_base_fee(), &receipts)
+    }
+
+    // Executes a transaction as if it were the first of the next block and reports what it
+    // would do, then puts the state back. Signatures are not checked so calls can be previewed
+    // before signing; a zero nonce takes the sender's next one and a zero gas limit runs with
+    // as much of the block gas limit as the sender can pay for, which is how gas is estimated.
+    pub fn simulate_call(&mut self, transaction: &Transaction) -> Result<Simulation, String> {
+        let mut transaction = transaction.clone();
+        let balance = self.balances.get(&transaction.sender).copied().unwrap_or(0);
+        if transaction.nonce == 0 {
+            transaction.nonce = self.next_nonce(&transaction.sender);
+        }
+        if transaction.gas_limit == 0 {
+            let affordable = balance.saturating_sub(transaction.amount).checked_div(transaction.gas_price).unwrap_or(u64::MAX);
+            transaction.gas_limit = self.block_gas_limit.min(affordable);
+        }
+        let cost = transaction.max_fee()
+            .and_then(|fee| fee.checked_add(transaction.amount))
+            .ok_or("Transaction cost overflows")?;
+        if balance < cost {
+            return Err(format!("Insufficient balance: {} has {}, needs {}", transaction.sender, balance, cost));
+        }
+
+        let before = self.snapshot();
+        let beneficiary = self.coinbase.clone();
+        let receipt = self.execute_transaction(&transaction, &beneficiary, self.next_base_fee());
+        let diff = StateDiff::between(&before, &self.snapshot());
+        self.restore(before);
+        Ok(Simulation { receipt, diff })
+    }
+
+    // Validates a block received from a peer against the current tip and re-executes it
+    pub fn import_block(&mut self, block: Block) -> Result<(), String> {
+        let tip = self.chain.last().ok_or("Blockchain is empty")?;
+        if block.index != tip.index + 1 || block.previous_hash != tip.hash {
+            return Err(format!("Block {} does not extend the current tip", block.index));
+        }
+        if block.hash != block.calculate_hash() || block.tx_root != Block::compute_tx_root(&block.data) {
+            return Err(format!("Block {} has an invalid hash", block.index));
+        }
+
+        match &self.clique {
+            Some(clique) => {
+                if !clique.verify_seal(&block.header()) || clique.recently_signed(&self.chain, &block.signer) {
+                    return Err(format!("Block {} has an invalid authority seal", block.index));
+                }
+                clique.check_votes(&block.signer, &block.transactions()).map_err(|e| format!("Block {}: {}", block.index, e))?;
+            }
+            // Proof-of-stake blocks must be sealed by a trusted validator; any other block, including
+            // one naming a validator when none are configured, must meet the proof-of-work target
+            None => match &self.validators {
+                Some(validators) if !block.signer.is_empty() => {
+                    if !validators.contains(&block.signer) {
+                        return Err(format!("Block {} was produced by unknown validator {}", block.index, block.signer));
+                    }
+                    if !security::verify_signature(&block.signer, block.hash.as_bytes(), &block.seal) {
+                        return Err(format!("Block {} has an invalid validator seal", block.index));
+                    }
+                }
+                _ => {
+                    if !block.hash.starts_with(&"0".repeat(self.difficulty)) {
+                        return Err(format!("Block {} does not meet the proof-of-work target", block.index));
+                    }
+                }
+            },
+        }
+
+        // The limit is a chain parameter, so a producer cannot raise it or shrink it to move the
+        // base fee
+        if block.gas_limit != self.block_gas_limit {
+            return Err(format!("Block {} has gas limit {} instead of {}", block.index, block.gas_limit, self.block_gas_limit));
+        }
+        // Signatures travel in the body, so a producer cannot speak for reporters, signers or admins
+        if let Some(unsigned) = block.transactions().iter().find(|transaction| self.needs_signed_sender(transaction) && !transaction.verify_sender()) {
+            return Err(format!("Block {} carries transaction {} without its sender's signature", block.index, unsigned.id()));
+        }
+        if block.base_fee != self.next_base_fee() {
+            return Err(format!("Block {} has base fee {} instead of {}", block.index, block.base_fee, self.next_base_fee()));
+        }
+
+        let tip_index = tip.index;
+        self.record_state(tip_index);
+        let transactions = block.transactions();
+        let (included, receipts) = self.execute_transactions(transactions.clone(), &block.beneficiary, block.gas_limit);
+        let gas_used: u64 = receipts.iter().map(|receipt| receipt.gas_used).sum();
+        if included.len() != transactions.len() || gas_used != block.gas_used || self.state_root() != block.state_root {
+            if let Some(snapshot) = self.state_history.get(&tip_index).cloned() {
+                self.restore(snapshot);
+            }
+            return Err(format!("Block {} does not execute to its state root", block.index));
+        }
+
+        if let Some(clique) = self.clique.as_mut() {
+            clique.apply_votes(&block.signer, &transactions);
+        }
+        // Transactions are equal when they move the same amount, so match the pool by id
+        let ids: HashSet<String> = transactions.iter().map(Transaction::id).collect();
+        self.transaction_pool.transactions.retain(|pending| !ids.contains(&pending.id()));
+        self.append_block(block, receipts);
+        Ok(())
+    }
+
+    pub fn create_checkpoint(&self, keypair: &Ed25519KeyPair) -> Result<Checkpoint, String> {
+        let tip = self.chain.last().ok_or("Blockchain is empty")?;
+        if tip.index == 0 || tip.index % self.checkpoint_interval != 0 {
+            return Err(format!("Block {} is not at a checkpoint height", tip.index));
+        }
+        if tip.state_root != self.state_root() {
+            return Err("Account state has diverged from the latest block".to_string());
+        }
+
+        let mut checkpoint = Checkpoint {
+            header: tip.header(),
+            balances: self.balances.iter().map(|(address, balance)| (address.clone(), *balance)).collect(),
+            nonces: self.nonces.iter().map(|(address, nonce)| (address.clone(), *nonce)).collect(),
+            contracts: self.contracts.iter().map(|(address, contract)| (address.clone(), contract.clone())).collect(),
+            oracle: self.oracle.clone(),
+            tokens: self.tokens.clone(),
+            nfts: self.nfts.clone(),
+            htlcs: self.htlcs.clone(),
+            authorities: self.clique.as_ref().map(|clique| clique.signers.clone()).unwrap_or_default(),
+            signer: String::new(),
+            signature: String::new(),
+        };
+        checkpoint.sign(keypair);
+        Ok(checkpoint)
+    }
+
+    // Stores a signed checkpoint of the current state and returns its hash
+    pub fn export_checkpoint(&self, storage: &Storage, keypair: &Ed25519KeyPair) -> Result<String, String> {
+        let checkpoint = self.create_checkpoint(keypair)?;
+        let hash = checkpoint.hash();
+        storage.put(&Checkpoint::storage_key(&hash), &checkpoint);
+        storage.flush();
+        println!("Exported checkpoint {} at block {}", hash, checkpoint.header.index);
+        Ok(hash)
+    }
+
+    // Bootstraps a node from a checkpoint with a trusted hash, signed by the trusted signer (a
+    // hex public key); later blocks are verified with `import_block`
+    pub fn from_checkpoint(storage: &Storage, trusted_hash: &str, trusted_signer: &str) -> Result<Self, String> {
+        let checkpoint: Checkpoint = storage
+            .get(&Checkpoint::storage_key(trusted_hash))
+            .ok_or_else(|| format!("Checkpoint {} not found", trusted_hash))?;
+        checkpoint.verify(trusted_hash, trusted_signer)?;
+
+        let mut blockchain = Blockchain::new();
+        if !checkpoint.authorities.is_empty() {
+            blockchain.clique = Some(Clique::new(checkpoint.authorities));
+        }
+        blockchain.chain = vec![Block::from_header(checkpoint.header)];
+        blockchain.balances = checkpoint.balances.into_iter().collect();
+        blockchain.nonces = checkpoint.nonces.into_iter().collect();
+        blockchain.contracts = checkpoint.contracts.into_iter().collect();
+        blockchain.oracle = checkpoint.oracle;
+        blockchain.tokens = checkpoint.tokens;
+        blockchain.nfts = checkpoint.nfts;
+        blockchain.htlcs = checkpoint.htlcs;
+        Ok(blockchain)
+    }
+
+    pub fn state_root(&self) -> String {
+        merkle_root(&self.state_leaves())
+    }
+
+    fn state_leaves(&self) -> Vec<String> {
+        state_leaves(&self.balances, &self.nonces, &self.contracts, &self.tokens, &self.nfts, &self.htlcs)
+    }
+
+    pub fn headers(&self, from: u64) -> Vec<BlockHeader> {
+        self.chain.iter().filter(|block| block.index >= from).map(Block::header).collect()
+    }
+
+    pub fn transaction_proof(&self, transaction_id: &str) -> Option<TransactionProof> {
+        self.chain.iter().find_map(|header| {
+            let block = self.block(header.index)?;
+            let transactions = block.transactions();
+            let ids: Vec<String> = transactions.iter().map(Transaction::id).collect();
+            let position = ids.iter().position(|id| id == transaction_id)?;
+            Some(TransactionProof {
+                block_index: block.index,
+                transaction: transactions[position].clone(),
+                proof: MerkleProof::build(&ids, position)?,
+            })
+        })
+    }
+
+    // Proves the balance against the latest block; fails if the state has drifted from it
+    pub fn account_proof(&self, address: &str) -> Option<AccountProof> {
+        let tip = self.chain.last()?;
+        if tip.state_root != self.state_root() {
+            return None;
+        }
+
+        let balance = *self.balances.get(address)?;
+        let leaves = self.state_leaves();
+        let leaf = account_leaf(address, balance);
+        let position = leaves.iter().position(|candidate| *candidate == leaf)?;
+        Some(AccountProof {
+            block_index: tip.index,
+            address: address.to_string(),
+            balance,
+            proof: MerkleProof::build(&leaves, position)?,
+        })
+    }
+
+    pub fn add_block_with_poa(&mut self, keypair: &Ed25519KeyPair) -> Result<(), String> {
+        let signer = security::public_key_hex(keypair);
+        let clique = self.clique.as_ref().ok_or("Proof-of-authority is not enabled")?;
+        clique.can_seal(&self.chain, &signer)?;
+
+        let height = self.next_index();
+        if clique.in_turn_signer(height) != Some(&signer) {
+            println!("Signer {} is sealing block {} out of turn", signer, height);
+        }
+
+        // Other signers' votes wait in the pool until they seal a block themselves
+        let mut has_vote = false;
+        let transactions = self.validate_transactions().into_iter()
+            .filter(|transaction| match transaction.kind {
+                TransactionKind::Vote { .. } if transaction.sender == signer && !has_vote => {
+                    has_vote = true;
+                    true
+                }
+                TransactionKind::Vote { .. } => false,
+                _ => true,
+            })
+            .collect();
+        let (mut new_block, receipts) = self.prepare_block(transactions, signer.clone());
+        if let Some(clique) = self.clique.as_mut() {
+            clique.seal(&mut new_block, keypair);
+            clique.apply_votes(&signer, &new_block.transactions());
+        }
+
+        self.append_block(new_block, receipts);
+        self.retain_pending();
+        Ok(())
+    }
+
+    // Calls to functions that require a role are checked against the sender, so like admin
+    // actions they must be signed by it
+    fn calls_restricted_function(&self, transaction: &Transaction) -> bool {
+        match &transaction.kind {
+            TransactionKind::Call { function, .. } => self.contracts.get(&transaction.receiver)
+                .is_some_and(|contract| contract.acl.requirements.contains_key(function)),
+            _ => false,
+        }
+    }
+
+    fn needs_signed_sender(&self, transaction: &Transaction) -> bool {
+        transaction.needs_signed_sender() || self.calls_restricted_function(transaction)
     }
 
     pub fn add_transaction(&mut self, transaction: Transaction) {
+        if self.needs_signed_sender(&transaction) && !transaction.verify_sender() {
+            println!("Transaction not signed by its sender: {:?}", transaction);
+            return;
+        }
+        if let TransactionKind::Vote { .. } = transaction.kind {
+            if !self.clique.as_ref().is_some_and(|clique| clique.is_valid_vote(&transaction)) {
+                println!("Vote rejected: {:?}", transaction);
+                return;
+            }
+        }
+        if let TransactionKind::Report { feed, .. } = &transaction.kind {
+            if !self.oracle.is_reporter(feed, &transaction.sender) {
+                println!("Report rejected: {:?}", transaction);
+                return;
+            }
+        }
+
         if self.validate_transaction(&transaction) && transaction.is_fully_signed() {
             println!("Adding transaction: {:?}", transaction);
+            self.event_manager.publish(ChainEvent::NewTransaction {
+                id: transaction.id(),
+                sender: transaction.sender.clone(),
+                receiver: transaction.receiver.clone(),
+            });
             self.transaction_pool.add_transaction(transaction);
         } else {
             println!("Transaction validation failed: {:?}", transaction);
@@ -68,7 +1169,24 @@ impl Blockchain {
 
     pub fn validate_transaction(&self, transaction: &Transaction) -> bool {
         let sender_balance = self.balances.get(&transaction.sender).cloned().unwrap_or(0);
-        let is_valid = sender_balance >= transaction.amount + transaction.fee;
+        // Tokens only move with plain transfers, and the sender must hold them
+        if !transaction.tokens.is_empty()
+            && (transaction.kind != TransactionKind::Transfer || self.tokens.check_transfer(&transaction.sender, &transaction.tokens).is_err())
+        {
+            return false;
+        }
+
+        // Expired transactions can never be included; those not yet valid wait in the pool
+        if transaction.is_expired_at(self.next_index()) {
+            return false;
+        }
+
+        // The sender must be able to pay for the whole gas limit on top of the amount, at a
+        // price that covers the base fee
+        let covers_base_fee = transaction.gas_price >= self.next_base_fee() || transaction.is_system();
+        let is_valid = covers_base_fee && transaction.gas_limit >= INTRINSIC_GAS && transaction.max_fee()
+            .and_then(|max_fee| max_fee.checked_add(transaction.amount))
+            .is_some_and(|cost| sender_balance >= cost);
 
         // Check for replay protection using nonce
         let sender_nonce = self.get_nonce(&transaction.sender);
@@ -83,8 +1201,12 @@ impl Blockchain {
     }
 
     fn get_nonce(&self, address: &str) -> u64 {
-        // Implement logic to retrieve the current nonce for the address
-        0 // Placeholder
+        self.nonces.get(address).copied().unwrap_or(0)
+    }
+
+    // Lowest nonce the next transaction from this address may use
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        self.get_nonce(address) + 1
     }
 
     pub fn update_balances(&mut self) {
@@ -99,9 +1221,8 @@ impl Blockchain {
             for transaction in transactions {
                 let sender_balance = self.balances.get(&transaction.sender).cloned().unwrap_or(0);
                 let receiver_balance = self.balances.get(&transaction.receiver).cloned().unwrap_or(0);
-                self.balances.insert(transaction.sender.clone(), sender_balance - transaction.amount - transaction.fee);
+                self.balances.insert(transaction.sender.clone(), sender_balance - transaction.amount);
                 self.balances.insert(transaction.receiver.clone(), receiver_balance + transaction.amount);
-                self.balances.insert(transaction.receiver.clone(), receiver_balance + transaction.fee);
             }
         }
     }
@@ -118,23 +1239,87 @@ impl Blockchain {
                 return false;
             }
 
+            if current_block.has_body() && current_block.tx_root != Block::compute_tx_root(&current_block.data) {
+                return false;
+            }
+
             if current_block.previous_hash != previous_block.hash {
                 return false;
             }
         }
-        true
+
+        match &self.clique {
+            Some(clique) => clique.verify_chain(&self.chain),
+            None => true,
+        }
     }
 
-    pub fn execute_contract(&mut self, contract: &mut SmartContract, function_name: &str, params: &[i32]) -> Result<i32, Box<dyn std::error::Error>> {
-        let mut vm = VirtualMachine::new(1000);
-        vm.execute(&contract.code, params)?;
-        contract.execute(function_name, params)
+    pub fn execute_contract(&mut self, contract: &mut SmartContract, function_name: &str, params: &[i32], gas_limit: u64) -> Result<i32, Box<dyn std::error::Error>> {
+        contract.execute_with_gas(function_name, params, gas_limit)
     }
 
+    // Switches to a longer fork if it branches off within the finality window. The fork's
+    // blocks are re-executed on top of the stored state at the branch point.
     pub fn resolve_fork(&mut self, other_chain: Vec<Block>) {
-        if other_chain.len() > self.chain.len() && self.is_chain_valid() {
-            self.chain = other_chain;
-            self.update_balances();
+        let tip = self.next_index() - 1;
+        if other_chain.last().is_none_or(|block| block.index <= tip) {
+            return;
+        }
+
+        let fork_point = other_chain.iter().rev()
+            .find(|block| block.index <= tip && self.chain.iter().any(|own| own.index == block.index && own.hash == block.hash))
+            .map(|block| block.index);
+        let fork_point = match fork_point {
+            Some(index) => index,
+            None => {
+                println!("Fork shares no known block with the local chain");
+                return;
+            }
+        };
+        if fork_point + self.finality_window < tip {
+            println!("Fork at block {} is beyond the finality window", fork_point);
+            return;
+        }
+        let snapshot = match self.state_at(fork_point) {
+            Some(snapshot) => snapshot,
+            None => {
+                println!("State at block {} has been pruned", fork_point);
+                return;
+            }
+        };
+
+        let saved_chain = self.chain.clone();
+        let saved_history = self.state_history.clone();
+        let saved_receipts = self.receipts.clone();
+        let saved_events = self.event_index.clone();
+        let saved_state = self.snapshot();
+        // Subscribers hear about the fork's blocks only once it has been accepted in full
+        let event_manager = std::mem::take(&mut self.event_manager);
+        self.discard_receipts_above(fork_point);
+        self.chain.retain(|block| block.index <= fork_point);
+        self.state_history.retain(|index, _| *index <= fork_point);
+        self.restore(snapshot);
+
+        for block in other_chain.into_iter().filter(|block| block.index > fork_point) {
+            if let Err(e) = self.import_block(block) {
+                println!("Fork rejected: {}", e);
+                self.chain = saved_chain;
+                self.discard_receipts_above(fork_point);
+                self.state_history = saved_history;
+                self.receipts = saved_receipts;
+                self.event_index = saved_events;
+                self.restore(saved_state);
+                self.persist_range(fork_point + 1, tip);
+                self.event_manager = event_manager;
+                return;
+            }
+        }
+
+        self.event_manager = event_manager;
+        let new_tip = self.next_index() - 1;
+        self.event_manager.publish(ChainEvent::Reorg { fork_point, old_tip: tip, new_tip });
+        for block in self.chain.iter().filter(|block| block.index > fork_point) {
+            self.publish_block(block, &self.block_receipts(block.index));
         }
     }
 
@@ -147,24 +1332,23 @@ impl Blockchain {
         *balance += reward;
     }
 
-    pub fn add_block_with_pos(&mut self) {
+    // Seals like a Clique authority: the validator signs the block hash with its key, so a
+    // header cannot claim a validator it was not produced by
+    pub fn add_block_with_pos(&mut self, keypair: &Ed25519KeyPair) -> Result<(), String> {
         if self.chain.is_empty() {
-            println!("Blockchain is empty. Cannot add a block.");
-            return;
+            return Err("Blockchain is empty. Cannot add a block.".to_string());
+        }
+        let validator = security::public_key_hex(keypair);
+        if self.validators.as_ref().is_some_and(|validators| !validators.contains(&validator)) {
+            return Err(format!("{} is not a validator", validator));
         }
 
-        let previous_block = self.chain.last().expect("Expected a previous block");
         let transactions = self.validate_transactions();
-        let data = serde_json::to_string(&transactions).expect("Failed to serialize transactions");
-        let mut new_block = Block::new(self.chain.len() as u64, 0, data, previous_block.hash.clone());
-
-        let validator = self.select_validator();
-        println!("Selected validator: {}", validator);
-
-        new_block.hash = new_block.calculate_hash();
-        self.chain.push(new_block);
-        self.transaction_pool.clear();
-        self.update_balances();
+        let (mut new_block, receipts) = self.prepare_block(transactions, validator);
+        new_block.seal = security::to_hex(security::sign_data(keypair, new_block.hash.as_bytes()).as_ref());
+        self.append_block(new_block, receipts);
+        self.retain_pending();
+        Ok(())
     }
 
     pub fn adjust_difficulty(&mut self) {
@@ -189,23 +1373,36 @@ impl Blockchain {
         }
     }
 
+    // Orders the pool by the tip each transaction would pay the producer in the next block
     pub fn prioritize_transactions(&mut self) {
+        let base_fee = self.next_base_fee();
+        let tip = |transaction: &Transaction| effective_price(base_fee, transaction.gas_price, transaction.priority_fee).1;
         let mut transactions: Vec<_> = self.transaction_pool.transactions.iter().cloned().collect();
-        transactions.sort_by(|a, b| b.fee.cmp(&a.fee));
+        transactions.sort_by_key(|transaction| std::cmp::Reverse(tip(transaction)));
         self.transaction_pool.transactions = transactions.into();
     }
 
-    pub fn deploy_contract(&mut self, code: String) -> SmartContract {
-        SmartContract::new(code)
+    // Builds a deployment transaction for the pool after checking the code can be deployed.
+    // Returns the transaction and the address the contract will have once it is included.
+    pub fn deploy_contract(&self, deployer: &str, code: String, gas_limit: u64, gas_price: u64) -> Result<(Transaction, String), String> {
+        self.checked_deploy(&code)?;
+        let nonce = self.next_nonce(deployer);
+        Ok((Transaction::deploy(deployer.to_string(), code, 0, gas_limit, gas_price, nonce), contract_address(deployer, nonce)))
     }
 
-    pub fn call_contract(&mut self, _caller: &mut SmartContract, callee: &mut SmartContract, function_name: &str, params: &[i32]) -> Result<i32, Box<dyn std::error::Error>> {
-        callee.execute(function_name, params)
+    // Calls the contract at `callee` as `caller`, sending `value` with the call. Calls the
+    // contract makes run in nested frames with the same rules as a Call transaction.
+    pub fn call_contract(&mut self, caller: &str, callee: &str, function_name: &str, args: &[Value], value: u64, gas_limit: u64) -> Result<Option<Value>, ContractError> {
+        let mut context = CallContext::new(caller.to_string(), callee.to_string(), gas_limit);
+        context.value = value;
+        self.run_call(&mut context, function_name, args).0
     }
 
-    pub fn call_contract_with_return(&mut self, caller: &mut SmartContract, callee: &mut SmartContract, function_name: &str, params: &[i32]) -> Result<i32, Box<dyn std::error::Error>> {
-        // Logic to call another contract and handle return values
-        callee.execute(function_name, params)
+    // Drops pool transactions that were included or can no longer be applied. Those left out
+    // because the block was full stay for the next one.
+    fn retain_pending(&mut self) {
+        let pending: Vec<Transaction> = self.validate_transactions();
+        self.transaction_pool.transactions = pending.into();
     }
 
     pub fn validate_transactions(&self) -> Vec<Transaction> {
@@ -216,27 +1413,25 @@ impl Blockchain {
     }
 
     pub fn mine_block_optimized(&mut self, difficulty: usize) {
-        let previous_block = self.chain.last().unwrap();
         let transactions = self.validate_transactions_parallel();
-        let data = serde_json::to_string(&transactions).unwrap();
-        let mut new_block = Block::new(self.chain.len() as u64, 0, data, previous_block.hash.clone());
+        let (mut new_block, receipts) = self.prepare_block(transactions, String::new());
 
-        let hash = (0..)
+        let (nonce, hash) = (0..)
             .take(1_000_000) // Limit the range for demonstration purposes
             .collect::<Vec<_>>() // Convert to Vec for parallel iteration
             .into_par_iter()
             .map(|nonce| {
                 let mut block = new_block.clone(); // Clone the block for each iteration
                 block.nonce = nonce;
-                block.calculate_hash()
+                (nonce, block.calculate_hash())
             })
-            .find_any(|hash| hash.starts_with(&"0".repeat(difficulty)))
+            .find_any(|(_, hash)| hash.starts_with(&"0".repeat(difficulty)))
             .unwrap();
 
+        new_block.nonce = nonce;
         new_block.hash = hash;
-        self.chain.push(new_block);
-        self.transaction_pool.clear();
-        self.update_balances();
+        self.append_block(new_block, receipts);
+        self.retain_pending();
     }
 
     pub fn process_transactions_in_batches(&mut self, batch_size: usize) {
@@ -248,9 +1443,10 @@ impl Blockchain {
         }
     }
 
+    // Moves the amount; gas is paid separately when the transaction is executed
     pub fn apply_transaction(&mut self, transaction: &Transaction) {
-        let sender_balance = self.balances.get_mut(&transaction.sender).unwrap();
-        *sender_balance -= transaction.amount + transaction.fee;
+        let sender_balance = self.balances.entry(transaction.sender.clone()).or_insert(0);
+        *sender_balance -= transaction.amount;
         let receiver_balance = self.balances.entry(transaction.receiver.clone()).or_insert(0);
         *receiver_balance += transaction.amount;
     }
@@ -264,6 +1460,6 @@ impl Blockchain {
 
     pub fn validate_transaction_security(&self, transaction: &Transaction) -> bool {
         // Add additional security checks
-        self.validate_transaction(transaction) && transaction.verify_signatures(&[&transaction.sender.as_bytes()])
+        self.validate_transaction(transaction) && transaction.verify_signatures(&[transaction.sender.as_bytes()])
     }
 } 
\ No newline at end of file
@@ -0,0 +1,84 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use crate::core::block::BlockHeader;
+use crate::core::htlc::HtlcRegistry;
+use crate::core::light_client::state_leaves;
+use crate::core::merkle::{hash_str, merkle_root};
+use crate::core::nft::NftRegistry;
+use crate::core::oracle::Oracle;
+use crate::core::token::TokenLedger;
+use crate::security;
+use crate::smart_contracts::SmartContract;
+use ring::signature::Ed25519KeyPair;
+
+// Full account and contract state at a checkpoint height, signed by the exporting node.
+// New nodes bootstrap from it given a trusted checkpoint hash instead of replaying from genesis.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Checkpoint {
+    pub header: BlockHeader,
+    pub balances: BTreeMap<String, u64>,
+    #[serde(default)]
+    pub nonces: BTreeMap<String, u64>,
+    pub contracts: BTreeMap<String, SmartContract>,
+    #[serde(default)]
+    pub oracle: Oracle,
+    #[serde(default)]
+    pub tokens: TokenLedger,
+    #[serde(default)]
+    pub nfts: NftRegistry,
+    #[serde(default)]
+    pub htlcs: HtlcRegistry,
+    pub authorities: Vec<String>,
+    pub signer: String,
+    pub signature: String,
+}
+
+impl Checkpoint {
+    pub fn storage_key(hash: &str) -> String {
+        format!("checkpoint:{}", hash)
+    }
+
+    // Commits to the header and the full state; this is the hash operators share out of band
+    pub fn hash(&self) -> String {
+        let contracts = serde_json::to_value(&self.contracts).expect("Failed to serialize contracts");
+        hash_str(&format!(
+            "{}{}{}{}{}{}{}{}{}",
+            self.header.hash,
+            serde_json::to_string(&self.balances).expect("Failed to serialize balances"),
+            serde_json::to_string(&self.nonces).expect("Failed to serialize nonces"),
+            contracts,
+            serde_json::to_string(&self.oracle).expect("Failed to serialize oracle"),
+            serde_json::to_string(&self.tokens).expect("Failed to serialize tokens"),
+            serde_json::to_string(&self.nfts).expect("Failed to serialize NFTs"),
+            serde_json::to_string(&self.htlcs).expect("Failed to serialize hash-time-locks"),
+            self.authorities.join(",")
+        ))
+    }
+
+    pub fn sign(&mut self, keypair: &Ed25519KeyPair) {
+        self.signer = security::public_key_hex(keypair);
+        self.signature = security::to_hex(security::sign_data(keypair, self.hash().as_bytes()).as_ref());
+    }
+
+    // Checks the checkpoint against the hash and signer key operators trust; the signer it names
+    // itself proves nothing, since anyone can sign any state
+    pub fn verify(&self, trusted_hash: &str, trusted_signer: &str) -> Result<(), String> {
+        let hash = self.hash();
+        if hash != trusted_hash {
+            return Err(format!("Checkpoint hash {} does not match trusted hash {}", hash, trusted_hash));
+        }
+        if self.header.hash != self.header.calculate_hash() {
+            return Err("Checkpoint header hash is invalid".to_string());
+        }
+
+        let leaves = state_leaves(&self.balances, &self.nonces, &self.contracts, &self.tokens, &self.nfts, &self.htlcs);
+        if merkle_root(&leaves) != self.header.state_root {
+            return Err("Checkpoint state does not match the header state root".to_string());
+        }
+
+        if self.signer != trusted_signer || !security::verify_signature(trusted_signer, hash.as_bytes(), &self.signature) {
+            return Err("Checkpoint signature is invalid".to_string());
+        }
+        Ok(())
+    }
+}
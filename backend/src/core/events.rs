@@ -0,0 +1,235 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use tokio::sync::broadcast::{self, error::RecvError};
+use crate::core::receipt::{Log, Receipt};
+
+// Events each subscriber can fall behind by before it starts missing the oldest ones
+pub const DEFAULT_EVENT_CAPACITY: usize = 1024;
+
+// Log together with the transaction and block that emitted it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EventRecord {
+    pub block_index: u64,
+    pub transaction_id: String,
+    pub log_index: usize, // Position among the logs of its transaction
+    pub log: Log,
+}
+
+impl EventRecord {
+    // Events of a block in execution order
+    pub fn from_receipts(receipts: &[Receipt]) -> Vec<EventRecord> {
+        receipts.iter()
+            .flat_map(|receipt| receipt.logs.iter().enumerate().map(move |(log_index, log)| EventRecord {
+                block_index: receipt.block_index,
+                transaction_id: receipt.transaction_id.clone(),
+                log_index,
+                log: log.clone(),
+            }))
+            .collect()
+    }
+}
+
+// Selects events by contract, topic and an inclusive block range. Unset fields match anything.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct EventFilter {
+    pub address: Option<String>,
+    pub topic: Option<String>, // Matches events carrying it in any position
+    pub from_block: u64,
+    pub to_block: Option<u64>,
+}
+
+impl EventFilter {
+    pub fn matches(&self, record: &EventRecord) -> bool {
+        self.address.as_ref().is_none_or(|address| *address == record.log.address)
+            && self.topic.as_ref().is_none_or(|topic| record.log.topics.contains(topic))
+            && record.block_index >= self.from_block
+            && self.to_block.is_none_or(|to| record.block_index <= to)
+    }
+}
+
+// One block a contract or topic emitted events in. The store keeps each entry under its own
+// key, so a new block only writes its own entries.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum IndexEntry {
+    Address(String, u64),
+    Topic(String, u64),
+}
+
+impl IndexEntry {
+    pub fn block_index(&self) -> u64 {
+        match self {
+            IndexEntry::Address(_, block) | IndexEntry::Topic(_, block) => *block,
+        }
+    }
+}
+
+// Heights of the blocks each contract and topic emitted events in, so queries only read the
+// receipts of blocks that can match
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct EventIndex {
+    by_address: BTreeMap<String, BTreeSet<u64>>,
+    by_topic: BTreeMap<String, BTreeSet<u64>>,
+}
+
+impl EventIndex {
+    // Returns the entries that were not already indexed
+    pub fn add(&mut self, block_index: u64, logs: &[&Log]) -> Vec<IndexEntry> {
+        let mut added = Vec::new();
+        for log in logs {
+            for entry in std::iter::once(IndexEntry::Address(log.address.clone(), block_index))
+                .chain(log.topics.iter().map(|topic| IndexEntry::Topic(topic.clone(), block_index)))
+            {
+                if self.insert(&entry) {
+                    added.push(entry);
+                }
+            }
+        }
+        added
+    }
+
+    pub fn insert(&mut self, entry: &IndexEntry) -> bool {
+        match entry {
+            IndexEntry::Address(address, block) => self.by_address.entry(address.clone()).or_default().insert(*block),
+            IndexEntry::Topic(topic, block) => self.by_topic.entry(topic.clone()).or_default().insert(*block),
+        }
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = IndexEntry> + '_ {
+        let by_address = self.by_address.iter()
+            .flat_map(|(address, blocks)| blocks.iter().map(move |block| IndexEntry::Address(address.clone(), *block)));
+        let by_topic = self.by_topic.iter()
+            .flat_map(|(topic, blocks)| blocks.iter().map(move |block| IndexEntry::Topic(topic.clone(), *block)));
+        by_address.chain(by_topic)
+    }
+
+    // Forgets blocks above the given height, e.g. when they are reorganised away, and returns
+    // the entries removed
+    pub fn discard_above(&mut self, index: u64) -> Vec<IndexEntry> {
+        let discarded: Vec<IndexEntry> = self.entries().filter(|entry| entry.block_index() > index).collect();
+        for blocks in self.by_address.values_mut().chain(self.by_topic.values_mut()) {
+            blocks.retain(|block| *block <= index);
+        }
+        self.by_address.retain(|_, blocks| !blocks.is_empty());
+        self.by_topic.retain(|_, blocks| !blocks.is_empty());
+        discarded
+    }
+
+    // Blocks in the range that may hold matching events
+    pub fn candidate_blocks(&self, filter: &EventFilter, to_block: u64) -> BTreeSet<u64> {
+        if filter.from_block > to_block {
+            return BTreeSet::new();
+        }
+        let empty = BTreeSet::new();
+        let range = |blocks: &BTreeSet<u64>| blocks.range(filter.from_block..=to_block).copied().collect::<BTreeSet<u64>>();
+        let by_address = filter.address.as_ref().map(|address| range(self.by_address.get(address).unwrap_or(&empty)));
+        let by_topic = filter.topic.as_ref().map(|topic| range(self.by_topic.get(topic).unwrap_or(&empty)));
+        match (by_address, by_topic) {
+            (Some(by_address), Some(by_topic)) => by_address.intersection(&by_topic).copied().collect(),
+            (Some(blocks), None) | (None, Some(blocks)) => blocks,
+            (None, None) => {
+                let all: BTreeSet<u64> = self.by_address.values().flatten().copied().collect();
+                range(&all)
+            }
+        }
+    }
+}
+
+// Notifications about changes to the chain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum ChainEvent {
+    NewBlock { index: u64, hash: String, transactions: usize },
+    NewTransaction { id: String, sender: String, receiver: String },
+    Contract(EventRecord),
+    // Blocks above the fork point were replaced; events for the new ones follow
+    Reorg { fork_point: u64, old_tip: u64, new_tip: u64 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ChainEventKind {
+    Block,
+    Transaction,
+    Contract,
+    Reorg,
+}
+
+impl ChainEvent {
+    pub fn kind(&self) -> ChainEventKind {
+        match self {
+            ChainEvent::NewBlock { .. } => ChainEventKind::Block,
+            ChainEvent::NewTransaction { .. } => ChainEventKind::Transaction,
+            ChainEvent::Contract(_) => ChainEventKind::Contract,
+            ChainEvent::Reorg { .. } => ChainEventKind::Reorg,
+        }
+    }
+}
+
+// Kinds of events a subscriber wants, all of them if empty. Contract events are narrowed
+// further by the event filter.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct SubscriptionFilter {
+    pub kinds: Vec<ChainEventKind>,
+    pub contract_events: EventFilter,
+}
+
+impl SubscriptionFilter {
+    pub fn matches(&self, event: &ChainEvent) -> bool {
+        let wanted = self.kinds.is_empty() || self.kinds.contains(&event.kind());
+        match event {
+            ChainEvent::Contract(record) => wanted && self.contract_events.matches(record),
+            _ => wanted,
+        }
+    }
+}
+
+// Fans chain events out to subscribers on any task or thread. Every subscriber has a bounded
+// queue: one that falls behind loses its oldest events instead of holding up the node, and
+// can see how many it missed.
+#[derive(Clone)]
+pub struct EventManager {
+    sender: broadcast::Sender<ChainEvent>,
+}
+
+impl EventManager {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        EventManager { sender }
+    }
+
+    pub fn publish(&self, event: ChainEvent) {
+        // Sending only fails when nobody is subscribed
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self, filter: SubscriptionFilter) -> Subscription {
+        Subscription { receiver: self.sender.subscribe(), filter, missed: 0 }
+    }
+}
+
+impl Default for EventManager {
+    fn default() -> Self {
+        EventManager::new(DEFAULT_EVENT_CAPACITY)
+    }
+}
+
+pub struct Subscription {
+    receiver: broadcast::Receiver<ChainEvent>,
+    filter: SubscriptionFilter,
+    pub missed: u64, // Events dropped because this subscriber fell behind
+}
+
+impl Subscription {
+    // Waits for the next matching event; None once the manager has been dropped
+    pub async fn next(&mut self) -> Option<ChainEvent> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) if self.filter.matches(&event) => return Some(event),
+                Ok(_) => {}
+                Err(RecvError::Lagged(count)) => self.missed += count,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use serde::{Serialize, Deserialize};
+use crate::core::block::Block;
+use crate::core::receipt::Receipt;
+
+// Base fee of the first block after genesis
+pub const INITIAL_BASE_FEE: u64 = 1;
+// Blocks aim to be this fraction of their gas limit full
+pub const ELASTICITY_MULTIPLIER: u64 = 2;
+// The base fee moves by at most 1/8 per block
+pub const BASE_FEE_CHANGE_DENOMINATOR: u64 = 8;
+// Recent blocks fee estimates are drawn from
+pub const FEE_HISTORY_BLOCKS: u64 = 20;
+
+// Base fee for the block after `parent`: it rises when the parent used more than the target
+// share of its gas limit and falls when it used less, in proportion to the difference
+pub fn next_base_fee(parent: &Block) -> u64 {
+    if parent.index == 0 {
+        return INITIAL_BASE_FEE; // Genesis carries no gas limit
+    }
+    let target = (parent.gas_limit / ELASTICITY_MULTIPLIER).max(1);
+    let base_fee = parent.base_fee as u128;
+    if parent.gas_used > target {
+        let delta = base_fee * (parent.gas_used - target) as u128 / target as u128 / BASE_FEE_CHANGE_DENOMINATOR as u128;
+        parent.base_fee.saturating_add((delta as u64).max(1))
+    } else {
+        let delta = base_fee * (target - parent.gas_used) as u128 / target as u128 / BASE_FEE_CHANGE_DENOMINATOR as u128;
+        parent.base_fee - delta as u64
+    }
+}
+
+// Price per gas a transaction pays under the given base fee, split into the part burned and
+// the tip to the producer. The sender never pays more than its gas price.
+pub fn effective_price(base_fee: u64, gas_price: u64, priority_fee: u64) -> (u64, u64) {
+    let price = gas_price.min(base_fee.saturating_add(priority_fee));
+    let burned = price.min(base_fee);
+    (burned, price - burned)
+}
+
+// Suggested fees for the next block. Tips are percentiles of what included transactions paid
+// over the recent blocks.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FeeEstimate {
+    pub base_fee: u64,
+    pub slow_tip: u64, // 25th percentile
+    pub standard_tip: u64, // Median
+    pub fast_tip: u64, // 75th percentile
+}
+
+impl FeeEstimate {
+    pub fn from_history(base_fee: u64, receipts: &[Receipt]) -> Self {
+        let mut tips: Vec<u64> = receipts.iter()
+            .filter(|receipt| receipt.gas_used > 0)
+            .map(|receipt| (receipt.fee - receipt.burned) / receipt.gas_used)
+            .collect();
+        tips.sort_unstable();
+        FeeEstimate {
+            base_fee,
+            slow_tip: percentile(&tips, 25),
+            standard_tip: percentile(&tips, 50),
+            fast_tip: percentile(&tips, 75),
+        }
+    }
+}
+
+fn percentile(sorted: &[u64], percent: usize) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    sorted[(sorted.len() - 1) * percent / 100]
+}
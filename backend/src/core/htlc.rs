@@ -0,0 +1,103 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::collections::BTreeMap;
+use crate::core::merkle::hash_str;
+use crate::core::receipt::Log;
+use crate::security;
+
+// Locks live at an id derived from the locking account and its nonce, like contracts
+pub fn htlc_id(sender: &str, nonce: u64) -> String {
+    hash_str(&format!("htlc:{}:{}", sender, nonce))
+}
+
+// SHA-256 of the raw preimage bytes as hex, so the same lock can be set on other chains
+pub fn hash_lock(preimage: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(preimage))
+}
+
+// Funds held until the receiver reveals the preimage of `hash_lock`, or returned to the sender
+// once the chain passes `timeout` without a claim
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Htlc {
+    pub sender: String,
+    pub receiver: String,
+    pub amount: u64,
+    pub hash_lock: String,
+    pub timeout: u64, // Last block that may include a claim; refunds are allowed after it
+}
+
+// What a LockHtlc transaction asks for: the hash to reveal and the last block a claim may land in
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HtlcTerms {
+    pub hash_lock: String,
+    pub timeout: u64,
+}
+
+// Open hash-time-locks. Claimed and refunded locks are removed; both return the account to pay
+// and the amount.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct HtlcRegistry {
+    pub locks: BTreeMap<String, Htlc>,
+}
+
+impl HtlcRegistry {
+    pub fn get(&self, id: &str) -> Result<&Htlc, String> {
+        self.locks.get(id).ok_or_else(|| format!("Unknown hash-time-lock {}", id))
+    }
+
+    // The hash lock is stored in lowercase, the form claims are checked against
+    pub fn lock(&mut self, id: String, mut htlc: Htlc, height: u64) -> Result<Vec<Log>, String> {
+        if self.locks.contains_key(&id) {
+            return Err(format!("Hash-time-lock {} already exists", id));
+        }
+        if htlc.timeout < height {
+            return Err(format!("Timeout {} has already passed", htlc.timeout));
+        }
+        if htlc.hash_lock.len() != 64 || security::from_hex(&htlc.hash_lock).is_none() {
+            return Err("Hash locks are hex SHA-256 digests".to_string());
+        }
+        htlc.hash_lock.make_ascii_lowercase();
+        let log = Log {
+            address: id.clone(),
+            topics: vec!["Locked".to_string(), htlc.sender.clone(), htlc.receiver.clone(), htlc.hash_lock.clone()],
+            data: htlc.amount.to_string(),
+        };
+        self.locks.insert(id, htlc);
+        Ok(vec![log])
+    }
+
+    // Releases the funds to the receiver. The preimage is logged so the counterparty of a swap
+    // can read it and claim on the other chain.
+    pub fn claim(&mut self, id: &str, preimage: &str, height: u64) -> Result<(String, u64, Vec<Log>), String> {
+        let htlc = self.get(id)?;
+        let bytes = security::from_hex(preimage).ok_or("Preimages are hex encoded")?;
+        if hash_lock(&bytes) != htlc.hash_lock {
+            return Err("Preimage does not match the hash lock".to_string());
+        }
+        if height > htlc.timeout {
+            return Err(format!("Hash-time-lock {} expired at block {}", id, htlc.timeout));
+        }
+        let htlc = self.locks.remove(id).unwrap();
+        let log = Log {
+            address: id.to_string(),
+            topics: vec!["Claimed".to_string(), htlc.receiver.clone()],
+            data: preimage.to_string(),
+        };
+        Ok((htlc.receiver, htlc.amount, vec![log]))
+    }
+
+    // Returns the funds to the sender once the timeout has passed
+    pub fn refund(&mut self, id: &str, height: u64) -> Result<(String, u64, Vec<Log>), String> {
+        let htlc = self.get(id)?;
+        if height <= htlc.timeout {
+            return Err(format!("Hash-time-lock {} can be claimed until block {}", id, htlc.timeout));
+        }
+        let htlc = self.locks.remove(id).unwrap();
+        let log = Log {
+            address: id.to_string(),
+            topics: vec!["Refunded".to_string(), htlc.sender.clone()],
+            data: htlc.amount.to_string(),
+        };
+        Ok((htlc.sender, htlc.amount, vec![log]))
+    }
+}
@@ -0,0 +1,193 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashSet;
+use crate::core::block::BlockHeader;
+use crate::core::htlc::{Htlc, HtlcRegistry};
+use crate::core::merkle::{hash_str, MerkleProof};
+use crate::core::nft::{Collection, NftRegistry};
+use crate::core::poa::Clique;
+use crate::core::token::TokenLedger;
+use crate::core::transaction::Transaction;
+use crate::security;
+use crate::smart_contracts::SmartContract;
+
+// Leaf format shared by full nodes building the state root and light clients checking it
+pub fn account_leaf(address: &str, balance: u64) -> String {
+    hash_str(&format!("{}:{}", address, balance))
+}
+
+pub fn nonce_leaf(address: &str, nonce: u64) -> String {
+    hash_str(&format!("nonce:{}:{}", address, nonce))
+}
+
+// Commits to the contract's code, storage and metadata
+pub fn contract_leaf(address: &str, contract: &SmartContract) -> String {
+    let contract = serde_json::to_value(contract).expect("Failed to serialize contract");
+    hash_str(&format!("contract:{}:{}", address, contract))
+}
+
+pub fn token_leaf(id: &str, ledger: &TokenLedger) -> String {
+    let token = serde_json::to_value(&ledger.tokens[id]).expect("Failed to serialize token");
+    hash_str(&format!("token:{}:{}", id, token))
+}
+
+pub fn token_balance_leaf(address: &str, token: &str, balance: u64) -> String {
+    hash_str(&format!("token_balance:{}:{}:{}", address, token, balance))
+}
+
+// Commits to the collection's settings and every token's owner, metadata and approvals
+pub fn collection_leaf(id: &str, collection: &Collection) -> String {
+    let collection = serde_json::to_value(collection).expect("Failed to serialize collection");
+    hash_str(&format!("collection:{}:{}", id, collection))
+}
+
+pub fn htlc_leaf(id: &str, htlc: &Htlc) -> String {
+    let htlc = serde_json::to_value(htlc).expect("Failed to serialize hash-time-lock");
+    hash_str(&format!("htlc:{}:{}", id, htlc))
+}
+
+// Leaves of the state root: accounts, then nonces, then contracts, each sorted by address, then
+// tokens and token balances in ledger order, then NFT collections and hash-time-locks by id
+pub fn state_leaves<'a>(
+    balances: impl IntoIterator<Item = (&'a String, &'a u64)>,
+    nonces: impl IntoIterator<Item = (&'a String, &'a u64)>,
+    contracts: impl IntoIterator<Item = (&'a String, &'a SmartContract)>,
+    tokens: &TokenLedger,
+    nfts: &NftRegistry,
+    htlcs: &HtlcRegistry,
+) -> Vec<String> {
+    let mut accounts: Vec<_> = balances.into_iter().collect();
+    accounts.sort();
+    let mut nonces: Vec<_> = nonces.into_iter().collect();
+    nonces.sort();
+    let mut contracts: Vec<_> = contracts.into_iter().collect();
+    contracts.sort_by(|a, b| a.0.cmp(b.0));
+
+    accounts.into_iter().map(|(address, balance)| account_leaf(address, *balance))
+        .chain(nonces.into_iter().map(|(address, nonce)| nonce_leaf(address, *nonce)))
+        .chain(contracts.into_iter().map(|(address, contract)| contract_leaf(address, contract)))
+        .chain(tokens.tokens.keys().map(|id| token_leaf(id, tokens)))
+        .chain(tokens.balances.iter().flat_map(|(address, balances)| {
+            balances.iter().map(move |(token, balance)| token_balance_leaf(address, token, *balance))
+        }))
+        .chain(nfts.collections.iter().map(|(id, collection)| collection_leaf(id, collection)))
+        .chain(htlcs.locks.iter().map(|(id, htlc)| htlc_leaf(id, htlc)))
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransactionProof {
+    pub block_index: u64,
+    pub transaction: Transaction,
+    pub proof: MerkleProof,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccountProof {
+    pub block_index: u64,
+    pub address: String,
+    pub balance: u64,
+    pub proof: MerkleProof,
+}
+
+// Header-only view of the chain. Bodies are never downloaded; balances and payments are
+// confirmed through Merkle proofs served by full nodes.
+pub struct LightClient {
+    pub headers: Vec<BlockHeader>,
+    pub difficulty: usize,
+    pub validators: Option<HashSet<String>>, // Trusted proof-of-stake validators, if known
+    pub authorities: Option<Clique>, // Signer votes live in block bodies, so the set stays fixed here
+}
+
+impl LightClient {
+    pub fn new(genesis: BlockHeader, difficulty: usize) -> Self {
+        LightClient {
+            headers: vec![genesis],
+            difficulty,
+            validators: None,
+            authorities: None,
+        }
+    }
+
+    pub fn tip(&self) -> &BlockHeader {
+        self.headers.last().expect("Light client always holds the genesis header")
+    }
+
+    // The first header may be a trusted checkpoint rather than genesis
+    pub fn header(&self, index: u64) -> Option<&BlockHeader> {
+        let offset = index.checked_sub(self.headers[0].index)?;
+        self.headers.get(offset as usize)
+    }
+
+    pub fn verify_seal(&self, header: &BlockHeader) -> Result<(), String> {
+        if let Some(authorities) = &self.authorities {
+            return if authorities.verify_seal(header) {
+                Ok(())
+            } else {
+                Err(format!("Invalid authority seal on block {}", header.index))
+            };
+        }
+
+        // A signer only stands in for proof of work when it is a known validator
+        match &self.validators {
+            Some(validators) if !header.signer.is_empty() => {
+                if !validators.contains(&header.signer) {
+                    return Err(format!("Block {} was produced by unknown validator {}", header.index, header.signer));
+                }
+                if !security::verify_signature(&header.signer, header.hash.as_bytes(), &header.seal) {
+                    return Err(format!("Invalid validator seal on block {}", header.index));
+                }
+            }
+            _ => {
+                if !header.hash.starts_with(&"0".repeat(self.difficulty)) {
+                    return Err(format!("Block {} does not meet the proof-of-work target", header.index));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn verify_header(&self, header: &BlockHeader) -> Result<(), String> {
+        let tip = self.tip();
+        if header.index != tip.index + 1 {
+            return Err(format!("Expected block {}, got {}", tip.index + 1, header.index));
+        }
+        if header.previous_hash != tip.hash {
+            return Err(format!("Block {} does not link to the current tip", header.index));
+        }
+        if header.hash != header.calculate_hash() {
+            return Err(format!("Block {} hash does not match its header", header.index));
+        }
+        self.verify_seal(header)
+    }
+
+    // Appends the headers past the current tip, stopping at the first invalid one.
+    // Returns the number of headers added.
+    pub fn sync_headers(&mut self, headers: Vec<BlockHeader>) -> Result<usize, String> {
+        let mut added = 0;
+        for header in headers {
+            if header.index <= self.tip().index {
+                continue;
+            }
+            self.verify_header(&header)?;
+            self.headers.push(header);
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    pub fn verify_transaction(&self, proof: &TransactionProof) -> bool {
+        match self.header(proof.block_index) {
+            Some(header) => proof.proof.leaf == proof.transaction.id() && proof.proof.verify(&header.tx_root),
+            None => false,
+        }
+    }
+
+    pub fn verify_account(&self, proof: &AccountProof) -> bool {
+        match self.header(proof.block_index) {
+            Some(header) => {
+                proof.proof.leaf == account_leaf(&proof.address, proof.balance) && proof.proof.verify(&header.state_root)
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use sha2::{Sha256, Digest};
+use serde::{Serialize, Deserialize};
+
+pub fn hash_str(data: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    format!("{:x}", hasher.finalize())
+}
+
+fn hash_pair(left: &str, right: &str) -> String {
+    hash_str(&format!("{}{}", left, right))
+}
+
+fn next_level(level: &[String]) -> Vec<String> {
+    level.chunks(2)
+        .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+// Binary Merkle root over pre-hashed leaves; odd nodes are paired with themselves
+pub fn merkle_root(leaves: &[String]) -> String {
+    if leaves.is_empty() {
+        return hash_str("");
+    }
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.remove(0)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MerkleProof {
+    pub index: usize,
+    pub leaf: String,
+    pub siblings: Vec<String>,
+}
+
+impl MerkleProof {
+    pub fn build(leaves: &[String], index: usize) -> Option<MerkleProof> {
+        let leaf = leaves.get(index)?.clone();
+        let mut siblings = Vec::new();
+        let mut level = leaves.to_vec();
+        let mut position = index;
+        while level.len() > 1 {
+            let sibling = if position.is_multiple_of(2) {
+                level.get(position + 1).unwrap_or(&level[position])
+            } else {
+                &level[position - 1]
+            };
+            siblings.push(sibling.clone());
+            level = next_level(&level);
+            position /= 2;
+        }
+        Some(MerkleProof { index, leaf, siblings })
+    }
+
+    pub fn verify(&self, root: &str) -> bool {
+        let mut hash = self.leaf.clone();
+        let mut position = self.index;
+        for sibling in &self.siblings {
+            hash = if position.is_multiple_of(2) {
+                hash_pair(&hash, sibling)
+            } else {
+                hash_pair(sibling, &hash)
+            };
+            position /= 2;
+        }
+        hash == root
+    }
+}
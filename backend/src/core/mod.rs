@@ -1,3 +1,16 @@
 pub mod block;
 pub mod blockchain;
+pub mod checkpoint;
+pub mod events;
+pub mod fees;
+pub mod htlc;
+pub mod light_client;
+pub mod merkle;
+pub mod nft;
+pub mod oracle;
+pub mod poa;
+pub mod pruning;
+pub mod receipt;
+pub mod simulation;
+pub mod token;
 pub mod transaction; 
\ No newline at end of file
@@ -0,0 +1,208 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use crate::core::merkle::hash_str;
+use crate::core::receipt::Log;
+
+// Royalties are expressed in hundredths of a percent of the sale price
+pub const MAX_ROYALTY_BASIS_POINTS: u16 = 10_000;
+
+// Collections live at an id derived from the creating account and its nonce, like tokens
+pub fn collection_id(creator: &str, nonce: u64) -> String {
+    hash_str(&format!("collection:{}:{}", creator, nonce))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Royalty {
+    pub recipient: String,
+    pub basis_points: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Nft {
+    pub owner: String,
+    pub uri: String, // Metadata, e.g. an IPFS or HTTPS link to JSON
+    pub approved: Option<String>, // May transfer this token once; cleared on every transfer
+}
+
+// A set of non-fungible tokens in the style of ERC-721, with ERC-2981 royalty info. Ids are
+// assigned in mint order starting from 1 and never reused, even after a burn.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Collection {
+    pub name: String,
+    pub symbol: String,
+    pub creator: String, // Only account allowed to mint
+    pub royalty: Option<Royalty>,
+    pub tokens: BTreeMap<u64, Nft>,
+    pub operators: BTreeMap<String, BTreeSet<String>>, // Owner, then accounts managing all of its tokens
+    pub minted: u64,
+}
+
+impl Collection {
+    fn nft(&self, token_id: u64) -> Result<&Nft, String> {
+        self.tokens.get(&token_id).ok_or_else(|| format!("{} #{} does not exist", self.symbol, token_id))
+    }
+
+    fn is_operator(&self, owner: &str, account: &str) -> bool {
+        self.operators.get(owner).is_some_and(|operators| operators.contains(account))
+    }
+
+    // The owner, the token's approved account or one of the owner's operators
+    fn can_transfer(&self, token_id: u64, account: &str) -> Result<bool, String> {
+        let nft = self.nft(token_id)?;
+        Ok(nft.owner == account || nft.approved.as_deref() == Some(account) || self.is_operator(&nft.owner, account))
+    }
+}
+
+// Every NFT collection, part of the chain state like the token ledger
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct NftRegistry {
+    pub collections: BTreeMap<String, Collection>,
+}
+
+// Logs follow ERC-721, with the token id as the last topic. Mints come from and burns go to the
+// empty address.
+fn transfer_log(collection: &str, from: &str, to: &str, token_id: u64) -> Log {
+    Log {
+        address: collection.to_string(),
+        topics: vec!["Transfer".to_string(), from.to_string(), to.to_string(), token_id.to_string()],
+        data: String::new(),
+    }
+}
+
+// The empty address marks burns in the logs, so no token may be sent to it
+fn check_recipient(to: &str) -> Result<(), String> {
+    if to.is_empty() {
+        return Err("NFTs need a recipient".to_string());
+    }
+    Ok(())
+}
+
+impl NftRegistry {
+    pub fn collection(&self, id: &str) -> Result<&Collection, String> {
+        self.collections.get(id).ok_or_else(|| format!("Unknown collection {}", id))
+    }
+
+    fn collection_mut(&mut self, id: &str) -> Result<&mut Collection, String> {
+        self.collections.get_mut(id).ok_or_else(|| format!("Unknown collection {}", id))
+    }
+
+    pub fn nft(&self, collection: &str, token_id: u64) -> Result<&Nft, String> {
+        self.collection(collection)?.nft(token_id)
+    }
+
+    // Id the next mint in the collection will get
+    pub fn next_token_id(&self, collection: &str) -> Result<u64, String> {
+        Ok(self.collection(collection)?.minted + 1)
+    }
+
+    // (collection, token id) of every token the account owns
+    pub fn tokens_of(&self, owner: &str) -> Vec<(String, u64)> {
+        self.collections.iter()
+            .flat_map(|(id, collection)| {
+                collection.tokens.iter()
+                    .filter(|(_, nft)| nft.owner == owner)
+                    .map(move |(token_id, _)| (id.clone(), *token_id))
+            })
+            .collect()
+    }
+
+    // Who is owed what if the token sells for `sale_price`
+    pub fn royalty_info(&self, collection: &str, token_id: u64, sale_price: u64) -> Result<Option<(String, u64)>, String> {
+        let collection = self.collection(collection)?;
+        collection.nft(token_id)?;
+        Ok(collection.royalty.as_ref().map(|royalty| {
+            let amount = sale_price as u128 * royalty.basis_points as u128 / MAX_ROYALTY_BASIS_POINTS as u128;
+            (royalty.recipient.clone(), amount as u64)
+        }))
+    }
+
+    pub fn create(&mut self, id: String, creator: &str, name: String, symbol: String, royalty: Option<Royalty>) -> Result<Vec<Log>, String> {
+        if self.collections.contains_key(&id) {
+            return Err(format!("Collection {} already exists", id));
+        }
+        if name.is_empty() || symbol.is_empty() {
+            return Err("Collections need a name and a symbol".to_string());
+        }
+        if royalty.as_ref().is_some_and(|royalty| royalty.basis_points > MAX_ROYALTY_BASIS_POINTS) {
+            return Err(format!("Royalties are at most {} basis points", MAX_ROYALTY_BASIS_POINTS));
+        }
+        self.collections.insert(id.clone(), Collection {
+            name,
+            symbol,
+            creator: creator.to_string(),
+            royalty,
+            tokens: BTreeMap::new(),
+            operators: BTreeMap::new(),
+            minted: 0,
+        });
+        Ok(vec![Log { address: id, topics: vec!["CollectionCreated".to_string(), creator.to_string()], data: String::new() }])
+    }
+
+    pub fn mint(&mut self, id: &str, caller: &str, to: &str, uri: String) -> Result<Vec<Log>, String> {
+        check_recipient(to)?;
+        let collection = self.collection_mut(id)?;
+        if collection.creator != caller {
+            return Err(format!("Only the creator of {} can mint", collection.symbol));
+        }
+        collection.minted += 1;
+        let token_id = collection.minted;
+        collection.tokens.insert(token_id, Nft { owner: to.to_string(), uri, approved: None });
+        Ok(vec![transfer_log(id, "", to, token_id)])
+    }
+
+    pub fn transfer(&mut self, id: &str, caller: &str, to: &str, token_id: u64) -> Result<Vec<Log>, String> {
+        check_recipient(to)?;
+        let collection = self.collection_mut(id)?;
+        if !collection.can_transfer(token_id, caller)? {
+            return Err(format!("{} may not transfer {} #{}", caller, collection.symbol, token_id));
+        }
+        let nft = collection.tokens.get_mut(&token_id).unwrap();
+        let from = std::mem::replace(&mut nft.owner, to.to_string());
+        nft.approved = None;
+        Ok(vec![transfer_log(id, &from, to, token_id)])
+    }
+
+    // Approves one account to transfer the token, or clears the approval with None
+    pub fn approve(&mut self, id: &str, caller: &str, token_id: u64, approved: Option<String>) -> Result<Vec<Log>, String> {
+        let collection = self.collection_mut(id)?;
+        let owner = collection.nft(token_id)?.owner.clone();
+        if owner != caller && !collection.is_operator(&owner, caller) {
+            return Err(format!("{} may not approve for {} #{}", caller, collection.symbol, token_id));
+        }
+        let log = Log {
+            address: id.to_string(),
+            topics: vec!["Approval".to_string(), owner, approved.clone().unwrap_or_default(), token_id.to_string()],
+            data: String::new(),
+        };
+        collection.tokens.get_mut(&token_id).unwrap().approved = approved;
+        Ok(vec![log])
+    }
+
+    // Lets the operator transfer and approve every token the owner holds in the collection
+    pub fn set_operator(&mut self, id: &str, owner: &str, operator: &str, approved: bool) -> Result<Vec<Log>, String> {
+        let collection = self.collection_mut(id)?;
+        let operators = collection.operators.entry(owner.to_string()).or_default();
+        if approved {
+            operators.insert(operator.to_string());
+        } else {
+            operators.remove(operator);
+            if operators.is_empty() {
+                collection.operators.remove(owner);
+            }
+        }
+        Ok(vec![Log {
+            address: id.to_string(),
+            topics: vec!["ApprovalForAll".to_string(), owner.to_string(), operator.to_string()],
+            data: approved.to_string(),
+        }])
+    }
+
+    pub fn burn(&mut self, id: &str, caller: &str, token_id: u64) -> Result<Vec<Log>, String> {
+        let collection = self.collection_mut(id)?;
+        if !collection.can_transfer(token_id, caller)? {
+            return Err(format!("{} may not burn {} #{}", caller, collection.symbol, token_id));
+        }
+        let nft = collection.tokens.remove(&token_id).unwrap();
+        Ok(vec![transfer_log(id, &nft.owner, "", token_id)])
+    }
+}
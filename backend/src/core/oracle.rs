@@ -0,0 +1,113 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use ring::signature::Ed25519KeyPair;
+use crate::core::transaction::Transaction;
+use crate::security;
+
+// Data feed aggregated from a fixed set of reporters. Each round takes one report per
+// reporter; once a quorum has reported, their median becomes the finalized value and the next
+// round starts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Feed {
+    pub reporters: BTreeSet<String>, // Hex public keys allowed to report
+    pub quorum: usize,
+    pub round: u64,
+    pub reports: BTreeMap<String, i64>, // Reports for the current round by reporter
+    pub value: Option<i64>,
+    pub finalized_at: u64, // Height of the block that finalized the value
+}
+
+// Feeds are part of the chain state, so every node reads the same values no matter when it
+// executes a block
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Oracle {
+    pub feeds: BTreeMap<String, Feed>,
+}
+
+impl Oracle {
+    pub fn create_feed(&mut self, name: &str, reporters: Vec<String>, quorum: usize) -> Result<(), String> {
+        let reporters: BTreeSet<String> = reporters.into_iter().collect();
+        if quorum == 0 || quorum > reporters.len() {
+            return Err(format!("Quorum must be between 1 and {}", reporters.len()));
+        }
+        if self.feeds.contains_key(name) {
+            return Err(format!("Feed {} already exists", name));
+        }
+        self.feeds.insert(name.to_string(), Feed {
+            reporters,
+            quorum,
+            round: 0,
+            reports: BTreeMap::new(),
+            value: None,
+            finalized_at: 0,
+        });
+        Ok(())
+    }
+
+    pub fn value(&self, name: &str) -> Option<i64> {
+        self.feeds.get(name)?.value
+    }
+
+    pub fn is_reporter(&self, name: &str, reporter: &str) -> bool {
+        self.feeds.get(name).is_some_and(|feed| feed.reporters.contains(reporter))
+    }
+
+    // Records a report, returning the new value if it completed the round's quorum
+    pub fn submit(&mut self, name: &str, reporter: &str, round: u64, value: i64, height: u64) -> Result<Option<i64>, String> {
+        let feed = self.feeds.get_mut(name).ok_or_else(|| format!("Unknown feed {}", name))?;
+        if !feed.reporters.contains(reporter) {
+            return Err(format!("{} is not a reporter for {}", reporter, name));
+        }
+        if round != feed.round {
+            return Err(format!("Report is for round {} but {} is at round {}", round, name, feed.round));
+        }
+        if feed.reports.contains_key(reporter) {
+            return Err(format!("{} already reported for round {}", reporter, round));
+        }
+        feed.reports.insert(reporter.to_string(), value);
+        if feed.reports.len() < feed.quorum {
+            return Ok(None);
+        }
+
+        let value = median(feed.reports.values().copied().collect());
+        feed.value = Some(value);
+        feed.finalized_at = height;
+        feed.round += 1;
+        feed.reports.clear();
+        Ok(Some(value))
+    }
+}
+
+// Lower median, so the result is always one of the reported values
+fn median(mut values: Vec<i64>) -> i64 {
+    values.sort_unstable();
+    values[(values.len() - 1) / 2]
+}
+
+// Where a reporter gets the values it submits, e.g. an exchange or weather API
+pub trait DataSource {
+    fn fetch(&self, feed: &str) -> Result<i64, String>;
+}
+
+// Runs off chain: reads the data source and signs report transactions with the reporter's key
+pub struct Reporter<S: DataSource> {
+    key_pair: Ed25519KeyPair,
+    source: S,
+}
+
+impl<S: DataSource> Reporter<S> {
+    pub fn new(key_pair: Ed25519KeyPair, source: S) -> Self {
+        Reporter { key_pair, source }
+    }
+
+    pub fn address(&self) -> String {
+        security::public_key_hex(&self.key_pair)
+    }
+
+    pub fn report(&self, feed: &str, round: u64, gas_price: u64, nonce: u64) -> Result<Transaction, String> {
+        let value = self.source.fetch(feed)?;
+        let mut transaction = Transaction::report(self.address(), feed.to_string(), round, value, gas_price, nonce);
+        transaction.sign(&self.key_pair);
+        Ok(transaction)
+    }
+}
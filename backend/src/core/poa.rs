@@ -0,0 +1,150 @@
+use crate::core::block::{Block, BlockHeader};
+use crate::core::transaction::{Transaction, TransactionKind};
+use crate::security;
+use ring::signature::Ed25519KeyPair;
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+
+// Clique-style proof-of-authority: a fixed set of signers take turns sealing blocks
+// and vote signers in and out with on-chain `Vote` transactions.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Clique {
+    pub genesis_signers: Vec<String>,
+    pub signers: Vec<String>,
+    pub votes: HashMap<String, HashMap<String, bool>>, // candidate -> (voter -> authorize)
+}
+
+impl Clique {
+    pub fn new(mut signers: Vec<String>) -> Self {
+        signers.sort();
+        signers.dedup();
+        Clique {
+            genesis_signers: signers.clone(),
+            signers,
+            votes: HashMap::new(),
+        }
+    }
+
+    pub fn is_signer(&self, address: &str) -> bool {
+        self.signers.iter().any(|signer| signer == address)
+    }
+
+    pub fn in_turn_signer(&self, height: u64) -> Option<&String> {
+        if self.signers.is_empty() {
+            return None;
+        }
+        self.signers.get((height % self.signers.len() as u64) as usize)
+    }
+
+    // A signer may seal at most one of any `signers / 2 + 1` consecutive blocks
+    pub fn recently_signed(&self, chain: &[Block], signer: &str) -> bool {
+        let limit = self.signers.len() / 2;
+        chain.iter()
+            .rev()
+            .take(limit)
+            .any(|block| block.index > 0 && block.signer == signer)
+    }
+
+    pub fn can_seal(&self, chain: &[Block], signer: &str) -> Result<(), String> {
+        if !self.is_signer(signer) {
+            return Err(format!("{} is not an authorized signer", signer));
+        }
+        if self.recently_signed(chain, signer) {
+            return Err(format!("{} has signed recently and must wait its turn", signer));
+        }
+        Ok(())
+    }
+
+    pub fn seal(&self, block: &mut Block, keypair: &Ed25519KeyPair) {
+        block.signer = security::public_key_hex(keypair);
+        block.hash = block.calculate_hash();
+        block.seal = security::to_hex(security::sign_data(keypair, block.hash.as_bytes()).as_ref());
+    }
+
+    pub fn verify_seal(&self, header: &BlockHeader) -> bool {
+        header.hash == header.calculate_hash()
+            && self.is_signer(&header.signer)
+            && security::verify_signature(&header.signer, header.hash.as_bytes(), &header.seal)
+    }
+
+    pub fn is_valid_vote(&self, transaction: &Transaction) -> bool {
+        match &transaction.kind {
+            TransactionKind::Vote { candidate, authorize } => {
+                self.is_signer(&transaction.sender)
+                    && security::from_hex(candidate).is_some()
+                    && self.is_signer(candidate) != *authorize
+            }
+            _ => false,
+        }
+    }
+
+    // Records a vote and applies it once a majority of signers agree.
+    // Returns true when the signer set changed.
+    pub fn cast_vote(&mut self, voter: &str, candidate: &str, authorize: bool) -> bool {
+        if !self.is_signer(voter) || self.is_signer(candidate) == authorize {
+            return false;
+        }
+
+        let tally = self.votes.entry(candidate.to_string()).or_default();
+        tally.insert(voter.to_string(), authorize);
+        let in_favour = tally.values().filter(|&&vote| vote == authorize).count();
+        if in_favour <= self.signers.len() / 2 {
+            return false;
+        }
+
+        self.votes.remove(candidate);
+        if authorize {
+            self.signers.push(candidate.to_string());
+            self.signers.sort();
+        } else {
+            self.signers.retain(|signer| signer != candidate);
+            for tally in self.votes.values_mut() {
+                tally.remove(candidate);
+            }
+        }
+        println!("Signer set updated: {:?}", self.signers);
+        true
+    }
+
+    // As in Clique, a block holds at most one vote and only from the signer that sealed it, so a
+    // sealer cannot replay votes other signers sent to the pool
+    pub fn check_votes(&self, signer: &str, transactions: &[Transaction]) -> Result<(), String> {
+        let votes: Vec<&Transaction> = transactions.iter().filter(|transaction| is_vote(transaction)).collect();
+        if votes.len() > 1 {
+            return Err("A block may carry only one vote".to_string());
+        }
+        match votes.first() {
+            Some(vote) if vote.sender != signer => Err(format!("Vote from {} in a block sealed by {}", vote.sender, signer)),
+            _ => Ok(()),
+        }
+    }
+
+    // Counts the sealing signer's vote; votes from anyone else in the body are ignored
+    pub fn apply_votes(&mut self, signer: &str, transactions: &[Transaction]) {
+        let vote = transactions.iter().find(|transaction| transaction.sender == signer && is_vote(transaction));
+        if let Some(TransactionKind::Vote { candidate, authorize }) = vote.map(|vote| &vote.kind) {
+            self.cast_vote(signer, candidate, *authorize);
+        }
+    }
+
+    // Replays the signer set from genesis and checks every block seal along the way
+    pub fn verify_chain(&self, chain: &[Block]) -> bool {
+        let mut clique = Clique::new(self.genesis_signers.clone());
+        for i in 1..chain.len() {
+            let block = &chain[i];
+            let transactions = block.transactions();
+            if !clique.verify_seal(&block.header())
+                || clique.recently_signed(&chain[..i], &block.signer)
+                || clique.check_votes(&block.signer, &transactions).is_err()
+            {
+                return false;
+            }
+            clique.apply_votes(&block.signer, &transactions);
+        }
+        true
+    }
+}
+
+fn is_vote(transaction: &Transaction) -> bool {
+    matches!(transaction.kind, TransactionKind::Vote { .. })
+}
@@ -0,0 +1,34 @@
+use serde::{Serialize, Deserialize};
+
+// How much history a node keeps in its persistent store
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum NodeMode {
+    // Every block body and the state after every block
+    #[default]
+    Archive,
+    // Bodies and state for the most recent `retain` blocks; older blocks keep only their headers
+    Full { retain: u64 },
+    // Headers only; state is kept just long enough to reorg within the finality window
+    Light,
+}
+
+impl NodeMode {
+    // Lowest block index whose body is retained, given the current tip
+    pub fn body_floor(&self, tip: u64, finality_window: u64) -> u64 {
+        match self {
+            NodeMode::Archive => 0,
+            NodeMode::Full { retain } => (tip + 1).saturating_sub((*retain).max(finality_window)),
+            NodeMode::Light => tip + 1,
+        }
+    }
+
+    // Lowest block index whose post-state is retained. Never prunes inside the finality
+    // window, so a reorg can always roll back to any block a fork may branch from.
+    pub fn state_floor(&self, tip: u64, finality_window: u64) -> u64 {
+        match self {
+            NodeMode::Archive => 0,
+            NodeMode::Full { retain } => (tip + 1).saturating_sub((*retain).max(finality_window)),
+            NodeMode::Light => (tip + 1).saturating_sub(finality_window),
+        }
+    }
+}
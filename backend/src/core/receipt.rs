@@ -0,0 +1,52 @@
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ExecutionStatus {
+    Success,
+    Failed(String),
+}
+
+// Entry emitted during execution, e.g. by a contract
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Log {
+    pub address: String,
+    pub topics: Vec<String>,
+    pub data: String,
+}
+
+// Outcome of an included transaction
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Receipt {
+    pub transaction_id: String,
+    pub status: ExecutionStatus,
+    pub fee: u64, // Paid by the sender: the burned part plus the producer's tip
+    #[serde(default)]
+    pub burned: u64,
+    pub gas_used: u64,
+    pub return_value: Option<String>,
+    pub logs: Vec<Log>,
+    pub block_index: u64,
+    pub block_hash: String,
+}
+
+impl Receipt {
+    // Receipts are created during execution. The fee is settled once the transaction has run,
+    // and the block fields are filled in once the block is sealed.
+    pub fn new(transaction_id: String, status: ExecutionStatus) -> Self {
+        Receipt {
+            transaction_id,
+            status,
+            fee: 0,
+            burned: 0,
+            gas_used: 0,
+            return_value: None,
+            logs: Vec::new(),
+            block_index: 0,
+            block_hash: String::new(),
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status == ExecutionStatus::Success
+    }
+}
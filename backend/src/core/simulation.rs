@@ -0,0 +1,111 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use crate::core::blockchain::StateSnapshot;
+use crate::core::receipt::Receipt;
+use crate::smart_contracts::{StorageValue, Word};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Change<T> {
+    pub before: T,
+    pub after: T,
+}
+
+// What executing a transaction changed, listing only the entries that differ
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct StateDiff {
+    pub balances: BTreeMap<String, Change<u64>>,
+    pub nonces: BTreeMap<String, Change<u64>>,
+    pub storage: BTreeMap<String, BTreeMap<Word, Change<Option<StorageValue>>>>, // By contract, then slot
+    #[serde(default)]
+    pub token_balances: BTreeMap<String, BTreeMap<String, Change<u64>>>, // By account, then token
+    #[serde(default)]
+    pub nft_owners: BTreeMap<String, BTreeMap<u64, Change<Option<String>>>>, // By collection, then token id
+    pub deployed: Vec<String>, // Contracts the transaction created
+}
+
+impl StateDiff {
+    pub fn between(before: &StateSnapshot, after: &StateSnapshot) -> Self {
+        let mut storage = BTreeMap::new();
+        for (address, contract) in &after.contracts {
+            let old = before.contracts.get(address).map(|contract| &contract.state);
+            let slots: BTreeSet<&Word> = contract.state.iter()
+                .map(|(key, _)| key)
+                .chain(old.into_iter().flat_map(|state| state.iter().map(|(key, _)| key)))
+                .collect();
+            let slot_changes: BTreeMap<Word, Change<Option<StorageValue>>> = slots.into_iter()
+                .map(|key| (*key, Change {
+                    before: old.and_then(|state| state.get(key)).cloned(),
+                    after: contract.state.get(key).cloned(),
+                }))
+                .filter(|(_, change)| change.before != change.after)
+                .collect();
+            if !slot_changes.is_empty() {
+                storage.insert(address.clone(), slot_changes);
+            }
+        }
+        let mut deployed: Vec<String> = after.contracts.keys()
+            .filter(|address| !before.contracts.contains_key(*address))
+            .cloned()
+            .collect();
+        deployed.sort();
+        let mut token_balances = BTreeMap::new();
+        for address in before.tokens.balances.keys().chain(after.tokens.balances.keys()) {
+            let empty = BTreeMap::new();
+            let account_changes = changes(
+                before.tokens.balances.get(address).unwrap_or(&empty),
+                after.tokens.balances.get(address).unwrap_or(&empty),
+            );
+            if !account_changes.is_empty() {
+                token_balances.insert(address.clone(), account_changes);
+            }
+        }
+        let mut nft_owners = BTreeMap::new();
+        for id in before.nfts.collections.keys().chain(after.nfts.collections.keys()) {
+            let owner = |snapshot: &StateSnapshot, token_id: &u64| {
+                snapshot.nfts.nft(id, *token_id).ok().map(|nft| nft.owner.clone())
+            };
+            let token_ids: BTreeSet<&u64> = [before, after].iter()
+                .filter_map(|snapshot| snapshot.nfts.collections.get(id))
+                .flat_map(|collection| collection.tokens.keys())
+                .collect();
+            let owner_changes: BTreeMap<u64, Change<Option<String>>> = token_ids.into_iter()
+                .map(|token_id| (*token_id, Change { before: owner(before, token_id), after: owner(after, token_id) }))
+                .filter(|(_, change)| change.before != change.after)
+                .collect();
+            if !owner_changes.is_empty() {
+                nft_owners.insert(id.clone(), owner_changes);
+            }
+        }
+        StateDiff {
+            balances: changes(&before.balances, &after.balances),
+            nonces: changes(&before.nonces, &after.nonces),
+            storage,
+            token_balances,
+            nft_owners,
+            deployed,
+        }
+    }
+}
+
+// Missing entries count as zero
+fn changes<'a>(
+    before: impl IntoIterator<Item = (&'a String, &'a u64)>,
+    after: impl IntoIterator<Item = (&'a String, &'a u64)>,
+) -> BTreeMap<String, Change<u64>> {
+    let mut changes: BTreeMap<String, Change<u64>> = before.into_iter()
+        .map(|(key, value)| (key.clone(), Change { before: *value, after: 0 }))
+        .collect();
+    for (key, value) in after {
+        changes.entry(key.clone()).or_insert(Change { before: 0, after: 0 }).after = *value;
+    }
+    changes.retain(|_, change| change.before != change.after);
+    changes
+}
+
+// Outcome of a transaction run against the current state without committing it. The receipt
+// carries the result, gas used, fee and events.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Simulation {
+    pub receipt: Receipt,
+    pub diff: StateDiff,
+}
@@ -0,0 +1,222 @@
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use crate::core::merkle::hash_str;
+use crate::core::receipt::Log;
+
+// Tokens live at an id derived from the creating account and its nonce, like contracts
+pub fn token_id(creator: &str, nonce: u64) -> String {
+    hash_str(&format!("token:{}:{}", creator, nonce))
+}
+
+// Amount of a token moved by a transfer, next to the native amount
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TokenAmount {
+    pub token: String,
+    pub amount: u64,
+}
+
+// A fungible token in the style of ERC-20. Supply is counted in the smallest unit; `decimals`
+// only tells wallets where to put the point.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Token {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+    pub owner: String, // Only account allowed to mint
+    pub total_supply: u64,
+    pub max_supply: Option<u64>,
+    pub allowances: BTreeMap<String, BTreeMap<String, u64>>, // Holder, then spender
+}
+
+// What a CreateToken transaction asks for; the initial supply goes to the creator
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TokenParams {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+    pub supply: u64,
+    pub max_supply: Option<u64>,
+}
+
+impl TokenParams {
+    pub fn new(name: &str, symbol: &str, decimals: u8, supply: u64) -> Self {
+        TokenParams { name: name.to_string(), symbol: symbol.to_string(), decimals, supply, max_supply: None }
+    }
+}
+
+// Every token and every account's token balances. Part of the account state, so it is
+// snapshotted, committed to by the state root and rolled back with the rest of it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TokenLedger {
+    pub tokens: BTreeMap<String, Token>,
+    pub balances: BTreeMap<String, BTreeMap<String, u64>>, // Account, then token id
+}
+
+// Logs follow ERC-20: Transfer(from, to) and Approval(holder, spender) with the amount as data.
+// Mints come from and burns go to the empty address.
+fn transfer_log(token: &str, from: &str, to: &str, amount: u64) -> Log {
+    Log { address: token.to_string(), topics: vec!["Transfer".to_string(), from.to_string(), to.to_string()], data: amount.to_string() }
+}
+
+// The empty address marks mints and burns in the logs, so no token may be sent to it
+fn check_recipient(to: &str) -> Result<(), String> {
+    if to.is_empty() {
+        return Err("Tokens need a recipient".to_string());
+    }
+    Ok(())
+}
+
+impl TokenLedger {
+    pub fn token(&self, id: &str) -> Result<&Token, String> {
+        self.tokens.get(id).ok_or_else(|| format!("Unknown token {}", id))
+    }
+
+    fn token_mut(&mut self, id: &str) -> Result<&mut Token, String> {
+        self.tokens.get_mut(id).ok_or_else(|| format!("Unknown token {}", id))
+    }
+
+    pub fn balance(&self, account: &str, token: &str) -> u64 {
+        self.balances.get(account).and_then(|balances| balances.get(token)).copied().unwrap_or(0)
+    }
+
+    // Zero balances are dropped so equal states serialize the same way
+    fn set_balance(&mut self, account: &str, token: &str, balance: u64) {
+        let balances = self.balances.entry(account.to_string()).or_default();
+        if balance == 0 {
+            balances.remove(token);
+            if balances.is_empty() {
+                self.balances.remove(account);
+            }
+        } else {
+            balances.insert(token.to_string(), balance);
+        }
+    }
+
+    pub fn allowance(&self, token: &str, holder: &str, spender: &str) -> u64 {
+        self.tokens.get(token)
+            .and_then(|token| token.allowances.get(holder))
+            .and_then(|allowances| allowances.get(spender))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    // Registers the token with its creator as owner, who receives the initial supply
+    pub fn create(&mut self, id: String, creator: &str, params: TokenParams) -> Result<Vec<Log>, String> {
+        if self.tokens.contains_key(&id) {
+            return Err(format!("Token {} already exists", id));
+        }
+        if params.symbol.is_empty() || params.name.is_empty() {
+            return Err("Tokens need a name and a symbol".to_string());
+        }
+        if params.max_supply.is_some_and(|max_supply| params.supply > max_supply) {
+            return Err("Initial supply exceeds the maximum supply".to_string());
+        }
+        self.tokens.insert(id.clone(), Token {
+            name: params.name,
+            symbol: params.symbol,
+            decimals: params.decimals,
+            owner: creator.to_string(),
+            total_supply: 0,
+            max_supply: params.max_supply,
+            allowances: BTreeMap::new(),
+        });
+        self.mint(&id, creator, creator, params.supply)
+    }
+
+    pub fn mint(&mut self, id: &str, caller: &str, to: &str, amount: u64) -> Result<Vec<Log>, String> {
+        check_recipient(to)?;
+        let token = self.token_mut(id)?;
+        if token.owner != caller {
+            return Err(format!("Only the owner of {} can mint", token.symbol));
+        }
+        let total_supply = token.total_supply.checked_add(amount).ok_or("Supply overflows")?;
+        if token.max_supply.is_some_and(|max_supply| total_supply > max_supply) {
+            return Err(format!("Minting {} would exceed the maximum supply of {}", amount, token.symbol));
+        }
+        token.total_supply = total_supply;
+        let balance = self.balance(to, id) + amount; // Bounded by the total supply
+        self.set_balance(to, id, balance);
+        Ok(vec![transfer_log(id, "", to, amount)])
+    }
+
+    pub fn burn(&mut self, id: &str, from: &str, amount: u64) -> Result<Vec<Log>, String> {
+        let balance = self.balance(from, id);
+        let token = self.token_mut(id)?;
+        if balance < amount {
+            return Err(format!("Insufficient {} balance: {} has {}, needs {}", token.symbol, from, balance, amount));
+        }
+        token.total_supply -= amount;
+        self.set_balance(from, id, balance - amount);
+        Ok(vec![transfer_log(id, from, "", amount)])
+    }
+
+    pub fn transfer(&mut self, id: &str, from: &str, to: &str, amount: u64) -> Result<Vec<Log>, String> {
+        check_recipient(to)?;
+        let token = self.token(id)?;
+        let balance = self.balance(from, id);
+        if balance < amount {
+            return Err(format!("Insufficient {} balance: {} has {}, needs {}", token.symbol, from, balance, amount));
+        }
+        self.set_balance(from, id, balance - amount);
+        let received = self.balance(to, id) + amount;
+        self.set_balance(to, id, received);
+        Ok(vec![transfer_log(id, from, to, amount)])
+    }
+
+    // Checks the account holds enough of every token, counting repeated tokens together
+    pub fn check_transfer(&self, from: &str, amounts: &[TokenAmount]) -> Result<(), String> {
+        let mut needed: BTreeMap<&str, u64> = BTreeMap::new();
+        for amount in amounts {
+            self.token(&amount.token)?;
+            let total = needed.entry(&amount.token).or_insert(0);
+            *total = total.checked_add(amount.amount).ok_or("Token amount overflows")?;
+        }
+        for (token, amount) in needed {
+            let balance = self.balance(from, token);
+            if balance < amount {
+                return Err(format!("Insufficient {} balance: {} has {}, needs {}", self.tokens[token].symbol, from, balance, amount));
+            }
+        }
+        Ok(())
+    }
+
+    // Moves every amount or none of them
+    pub fn transfer_all(&mut self, from: &str, to: &str, amounts: &[TokenAmount]) -> Result<Vec<Log>, String> {
+        check_recipient(to)?;
+        self.check_transfer(from, amounts)?;
+        let mut logs = Vec::new();
+        for amount in amounts {
+            logs.extend(self.transfer(&amount.token, from, to, amount.amount)?);
+        }
+        Ok(logs)
+    }
+
+    // Sets, rather than adds to, what the spender may move out of the holder's balance
+    pub fn approve(&mut self, id: &str, holder: &str, spender: &str, amount: u64) -> Result<Vec<Log>, String> {
+        let token = self.token_mut(id)?;
+        let allowances = token.allowances.entry(holder.to_string()).or_default();
+        if amount == 0 {
+            allowances.remove(spender);
+            if allowances.is_empty() {
+                token.allowances.remove(holder);
+            }
+        } else {
+            allowances.insert(spender.to_string(), amount);
+        }
+        Ok(vec![Log {
+            address: id.to_string(),
+            topics: vec!["Approval".to_string(), holder.to_string(), spender.to_string()],
+            data: amount.to_string(),
+        }])
+    }
+
+    pub fn transfer_from(&mut self, id: &str, spender: &str, holder: &str, to: &str, amount: u64) -> Result<Vec<Log>, String> {
+        let allowance = self.allowance(id, holder, spender);
+        if allowance < amount {
+            return Err(format!("Allowance of {} from {} is {}, needs {}", spender, holder, allowance, amount));
+        }
+        let logs = self.transfer(id, holder, to, amount)?;
+        self.approve(id, holder, spender, allowance - amount)?;
+        Ok(logs)
+    }
+}
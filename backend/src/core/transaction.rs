@@ -2,42 +2,314 @@ use serde::{Serialize, Deserialize};
 use ring::signature::{Ed25519KeyPair, Signature, UnparsedPublicKey, ED25519};
 use std::fmt;
 use std::collections::VecDeque;
+use crate::security;
+use crate::core::htlc::HtlcTerms;
+use crate::core::merkle::hash_str;
+use crate::core::nft::Royalty;
+use crate::core::token::{TokenAmount, TokenParams};
+use crate::smart_contracts::{ReentrancyPolicy, Value};
+
+// Gas every transaction uses before any contract code runs; a plain transfer uses only this
+pub const INTRINSIC_GAS: u64 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub enum TransactionKind {
+    #[default]
+    Transfer,
+    // Proof-of-authority vote to add (authorize) or remove a signer
+    Vote { candidate: String, authorize: bool },
+    // Deploys a contract at an address derived from the sender and nonce; `amount` endows it
+    Deploy {
+        code: String,
+        #[serde(default)]
+        reentrancy: ReentrancyPolicy,
+        // Blocks of timelock on upgrades; the deployer is the admin either way
+        #[serde(default)]
+        upgrade_delay: Option<u64>,
+    },
+    // Calls a function on the contract at `receiver`; `amount` is sent along with the call
+    Call { function: String, args: Vec<Value> },
+    // Upgrades the contract at `receiver`, or schedules the upgrade if it has a timelock. The
+    // migration function runs on the new code in the same transaction.
+    Upgrade {
+        code: String,
+        #[serde(default)]
+        migration: Option<String>,
+    },
+    // Applies the upgrade scheduled for the contract at `receiver` once its timelock expires
+    ApplyUpgrade,
+    // Lifecycle of the contract at `receiver`. Terminating it pays its balance to the beneficiary.
+    Pause,
+    Resume,
+    Terminate { beneficiary: String },
+    // Role management on the contract at `receiver`, signed by one of its admins
+    GrantRole { role: String, account: String },
+    RevokeRole { role: String, account: String },
+    RequireRole { function: String, role: Option<String> },
+    // Value for the current round of an oracle feed, signed by one of its reporters
+    Report { feed: String, round: u64, value: i64 },
+    // Creates a token at an id derived from the sender and nonce; the sender owns it and
+    // receives the initial supply
+    CreateToken {
+        name: String,
+        symbol: String,
+        decimals: u8,
+        supply: u64,
+        #[serde(default)]
+        max_supply: Option<u64>,
+    },
+    // Token operations. Minted tokens and those moved by TransferTokenFrom go to `receiver`,
+    // and approvals are granted to it.
+    MintToken { token: String, amount: u64 },
+    BurnToken { token: String, amount: u64 },
+    ApproveToken { token: String, amount: u64 },
+    TransferTokenFrom { token: String, holder: String, amount: u64 },
+    // Creates an NFT collection at an id derived from the sender and nonce; only the sender can
+    // mint in it
+    CreateCollection {
+        name: String,
+        symbol: String,
+        #[serde(default)]
+        royalty: Option<Royalty>,
+    },
+    // NFT operations. Minted and transferred NFTs go to `receiver`, and approvals and operator
+    // rights are granted to it; ApproveNft with an empty receiver clears the approval.
+    MintNft { collection: String, uri: String },
+    TransferNft { collection: String, token_id: u64 },
+    ApproveNft { collection: String, token_id: u64 },
+    SetNftOperator { collection: String, approved: bool },
+    BurnNft { collection: String, token_id: u64 },
+    // Holds `amount` for `receiver` under a hash-time-lock at an id derived from the sender and
+    // nonce. The receiver gets it by revealing the preimage by block `timeout`; after that the
+    // sender can take it back. Anyone may submit the claim or refund.
+    LockHtlc { hash_lock: String, timeout: u64 },
+    ClaimHtlc { htlc: String, preimage: String },
+    RefundHtlc { htlc: String },
+}
+
+impl TransactionKind {
+    // Kinds that act with the authority of a contract's admin, so only a signed sender counts
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            TransactionKind::Upgrade { .. }
+                | TransactionKind::ApplyUpgrade
+                | TransactionKind::Pause
+                | TransactionKind::Resume
+                | TransactionKind::Terminate { .. }
+                | TransactionKind::GrantRole { .. }
+                | TransactionKind::RevokeRole { .. }
+                | TransactionKind::RequireRole { .. }
+        )
+    }
+}
+
+// Function a Call transaction runs and the arguments it passes
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContractCall {
+    pub function: String,
+    pub args: Vec<Value>,
+}
+
+impl ContractCall {
+    pub fn new(function: &str, args: Vec<Value>) -> Self {
+        ContractCall { function: function.to_string(), args }
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Transaction {
     pub sender: String,
     pub receiver: String,
     pub amount: u64,
-    pub fee: u64,
+    pub gas_limit: u64,
+    pub gas_price: u64, // Most paid per unit of gas, base fee included
+    #[serde(default)]
+    pub priority_fee: u64, // Tip per unit of gas to the block producer, on top of the base fee
+    #[serde(default)]
+    pub tokens: Vec<TokenAmount>, // Moved from sender to receiver with `amount`; transfers only
+    #[serde(default)]
+    pub valid_after_height: Option<u64>, // Only blocks above this height may include it
+    #[serde(default)]
+    pub valid_until_height: Option<u64>, // Last block that may include it
     pub nonce: u64,
-    #[serde(skip)]
-    pub signatures: Vec<Signature>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<String>, // Hex-encoded, so blocks carry them to the nodes that import them
     pub required_signatures: usize,
+    #[serde(default)]
+    pub kind: TransactionKind,
 }
 
 impl Transaction {
-    pub fn new(sender: String, receiver: String, amount: u64, fee: u64, required_signatures: usize) -> Self {
+    pub fn new(sender: String, receiver: String, amount: u64, gas_limit: u64, gas_price: u64, required_signatures: usize) -> Self {
         Transaction {
             sender,
             receiver,
             amount,
-            fee,
+            gas_limit,
+            gas_price,
+            priority_fee: 0,
+            tokens: Vec::new(),
+            valid_after_height: None,
+            valid_until_height: None,
             nonce: 0,
             signatures: Vec::new(),
             required_signatures,
+            kind: TransactionKind::Transfer,
+        }
+    }
+
+    pub fn deploy(sender: String, code: String, amount: u64, gas_limit: u64, gas_price: u64, nonce: u64) -> Self {
+        Transaction {
+            nonce,
+            kind: TransactionKind::Deploy { code, reentrancy: ReentrancyPolicy::default(), upgrade_delay: None },
+            ..Transaction::new(sender, String::new(), amount, gas_limit, gas_price, 0)
+        }
+    }
+
+    pub fn call(sender: String, contract: String, call: ContractCall, amount: u64, gas_limit: u64, gas_price: u64, nonce: u64) -> Self {
+        Transaction {
+            nonce,
+            kind: TransactionKind::Call { function: call.function, args: call.args },
+            ..Transaction::new(sender, contract, amount, gas_limit, gas_price, 0)
+        }
+    }
+
+    pub fn upgrade(sender: String, contract: String, code: String, migration: Option<String>, gas_limit: u64, gas_price: u64, nonce: u64) -> Self {
+        Transaction {
+            nonce,
+            kind: TransactionKind::Upgrade { code, migration },
+            ..Transaction::new(sender, contract, 0, gas_limit, gas_price, 0)
+        }
+    }
+
+    pub fn transfer_token(sender: String, receiver: String, token: String, amount: u64, gas_limit: u64, gas_price: u64, nonce: u64) -> Self {
+        Transaction {
+            nonce,
+            tokens: vec![TokenAmount { token, amount }],
+            ..Transaction::new(sender, receiver, 0, gas_limit, gas_price, 0)
+        }
+    }
+
+    pub fn create_token(sender: String, params: TokenParams, gas_limit: u64, gas_price: u64, nonce: u64) -> Self {
+        Transaction {
+            nonce,
+            kind: TransactionKind::CreateToken {
+                name: params.name,
+                symbol: params.symbol,
+                decimals: params.decimals,
+                supply: params.supply,
+                max_supply: params.max_supply,
+            },
+            ..Transaction::new(sender, String::new(), 0, gas_limit, gas_price, 0)
+        }
+    }
+
+    pub fn transfer_nft(sender: String, receiver: String, collection: String, token_id: u64, gas_limit: u64, gas_price: u64, nonce: u64) -> Self {
+        Transaction {
+            nonce,
+            kind: TransactionKind::TransferNft { collection, token_id },
+            ..Transaction::new(sender, receiver, 0, gas_limit, gas_price, 0)
+        }
+    }
+
+    pub fn lock_htlc(sender: String, receiver: String, amount: u64, terms: HtlcTerms, gas_limit: u64, gas_price: u64, nonce: u64) -> Self {
+        Transaction {
+            nonce,
+            kind: TransactionKind::LockHtlc { hash_lock: terms.hash_lock, timeout: terms.timeout },
+            ..Transaction::new(sender, receiver, amount, gas_limit, gas_price, 0)
+        }
+    }
+
+    // Reports pay for their gas like any other transaction
+    pub fn report(reporter: String, feed: String, round: u64, value: i64, gas_price: u64, nonce: u64) -> Self {
+        Transaction {
+            nonce,
+            kind: TransactionKind::Report { feed, round, value },
+            ..Transaction::new(reporter, String::new(), 0, INTRINSIC_GAS, gas_price, 0)
+        }
+    }
+
+    // Most the sender can be charged for gas; None if it overflows
+    pub fn max_fee(&self) -> Option<u64> {
+        self.gas_limit.checked_mul(self.gas_price)
+    }
+
+    // Votes come from the authority set and a block holds at most one, so they are exempt from
+    // the base fee
+    pub fn is_system(&self) -> bool {
+        matches!(self.kind, TransactionKind::Vote { .. })
+    }
+
+    // Votes, reports, admin actions and token and NFT operations act with the authority of the
+    // key their sender names
+    pub fn needs_signed_sender(&self) -> bool {
+        self.kind.is_admin()
+            || !self.tokens.is_empty()
+            || matches!(
+                self.kind,
+                TransactionKind::Vote { .. }
+                    | TransactionKind::Report { .. }
+                    | TransactionKind::CreateToken { .. }
+                    | TransactionKind::MintToken { .. }
+                    | TransactionKind::BurnToken { .. }
+                    | TransactionKind::ApproveToken { .. }
+                    | TransactionKind::TransferTokenFrom { .. }
+                    | TransactionKind::CreateCollection { .. }
+                    | TransactionKind::MintNft { .. }
+                    | TransactionKind::TransferNft { .. }
+                    | TransactionKind::ApproveNft { .. }
+                    | TransactionKind::SetNftOperator { .. }
+                    | TransactionKind::BurnNft { .. }
+            )
+    }
+
+    // Whether a block at this height may include the transaction
+    pub fn is_valid_at(&self, height: u64) -> bool {
+        self.valid_after_height.is_none_or(|after| height > after) && !self.is_expired_at(height)
+    }
+
+    pub fn is_expired_at(&self, height: u64) -> bool {
+        self.valid_until_height.is_some_and(|until| height > until)
+    }
+
+    // Gas left for contract code once the intrinsic gas is paid
+    pub fn execution_gas(&self) -> u64 {
+        self.gas_limit.saturating_sub(INTRINSIC_GAS)
+    }
+
+    // Signatures are left out, so the id is stable across signing
+    pub fn id(&self) -> String {
+        let unsigned = Transaction { signatures: Vec::new(), ..self.clone() };
+        hash_str(&serde_json::to_string(&unsigned).expect("Failed to serialize transaction"))
+    }
+
+    pub fn signing_message(&self) -> String {
+        let mut message = format!("{}{}{}{}{}{}", self.sender, self.receiver, self.amount, self.gas_limit, self.gas_price, self.priority_fee);
+        if !self.tokens.is_empty() {
+            message = format!("{}{}", message, serde_json::to_string(&self.tokens).unwrap());
+        }
+        if self.valid_after_height.is_some() || self.valid_until_height.is_some() {
+            message = format!("{}{:?}{:?}", message, self.valid_after_height, self.valid_until_height);
+        }
+        // The nonce keeps anything but a plain transfer from being replayed
+        match &self.kind {
+            TransactionKind::Transfer if self.tokens.is_empty() => message,
+            kind => format!("{}{}{}", message, self.nonce, serde_json::to_string(kind).unwrap()),
         }
     }
 
     pub fn sign(&mut self, keypair: &Ed25519KeyPair) {
-        let message = format!("{}{}{}{}", self.sender, self.receiver, self.amount, self.fee);
-        self.signatures.push(keypair.sign(message.as_bytes()));
+        let message = self.signing_message();
+        self.add_signature(keypair.sign(message.as_bytes()));
     }
 
     pub fn verify(&self, public_key: &[u8]) -> bool {
         for signature in &self.signatures {
-            let message = format!("{}{}{}{}", self.sender, self.receiver, self.amount, self.fee);
+            let message = self.signing_message();
             let public_key = UnparsedPublicKey::new(&ED25519, public_key);
-            if !public_key.verify(message.as_bytes(), signature.as_ref()).is_ok() {
+            let signature = security::from_hex(signature).unwrap_or_default();
+            if public_key.verify(message.as_bytes(), &signature).is_err() {
                 return false;
             }
         }
@@ -45,7 +317,7 @@ impl Transaction {
     }
 
     pub fn add_signature(&mut self, signature: Signature) {
-        self.signatures.push(signature);
+        self.signatures.push(security::to_hex(signature.as_ref()));
     }
 
     pub fn verify_signatures(&self, public_keys: &[&[u8]]) -> bool {
@@ -54,9 +326,10 @@ impl Transaction {
         }
 
         for (i, signature) in self.signatures.iter().enumerate() {
-            let message = format!("{}{}{}{}", self.sender, self.receiver, self.amount, self.fee);
+            let message = self.signing_message();
             let public_key = UnparsedPublicKey::new(&ED25519, public_keys[i]);
-            if !public_key.verify(message.as_bytes(), signature.as_ref()).is_ok() {
+            let signature = security::from_hex(signature).unwrap_or_default();
+            if public_key.verify(message.as_bytes(), &signature).is_err() {
                 return false;
             }
         }
@@ -71,6 +344,14 @@ impl Transaction {
     pub fn is_fully_signed(&self) -> bool {
         self.signatures.len() >= self.required_signatures
     }
+
+    // Checks the signatures against the sender address, for senders that are hex public keys
+    pub fn verify_sender(&self) -> bool {
+        match security::from_hex(&self.sender) {
+            Some(public_key) => !self.signatures.is_empty() && self.verify(&public_key),
+            None => false,
+        }
+    }
 }
 
 // Implement PartialEq manually, excluding the signature field
@@ -89,6 +370,10 @@ impl fmt::Debug for Transaction {
             .field("sender", &self.sender)
             .field("receiver", &self.receiver)
             .field("amount", &self.amount)
+            .field("tokens", &self.tokens)
+            .field("valid_after_height", &self.valid_after_height)
+            .field("valid_until_height", &self.valid_until_height)
+            .field("kind", &self.kind)
             .finish()
     }
 }
@@ -97,6 +382,12 @@ pub struct TransactionPool {
     pub transactions: VecDeque<Transaction>,
 }
 
+impl Default for TransactionPool {
+    fn default() -> Self {
+        TransactionPool::new()
+    }
+}
+
 impl TransactionPool {
     pub fn new() -> Self {
         TransactionPool {
@@ -105,7 +396,7 @@ impl TransactionPool {
     }
 
     pub fn add_transaction(&mut self, transaction: Transaction) {
-        if transaction.amount > 0 {
+        if transaction.amount > 0 || !transaction.tokens.is_empty() || transaction.kind != TransactionKind::Transfer {
             self.transactions.push_back(transaction);
         }
     }
@@ -1 +1,10 @@
-pub mod storage; 
\ No newline at end of file
+pub mod api;
+pub mod core;
+pub mod monitoring;
+pub mod network;
+pub mod security;
+pub mod smart_contracts;
+pub mod storage;
+
+#[cfg(test)]
+mod tests;
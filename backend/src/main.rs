@@ -1,24 +1,10 @@
-mod api;
-mod core;
-mod monitoring;
-mod network;
-mod smart_contracts;
-
-
-#[cfg(test)]
-mod tests;
-
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tokio::runtime::Runtime;
-use prometheus::Registry;
-use crate::network::Network;
-use crate::api::start_api;
-use crate::monitoring::{Metrics, serve_metrics};
-use crate::core::blockchain::Blockchain;
-use crate::core::transaction::Transaction;
-use warp::Filter;
-use crate::smart_contracts::SmartContract;
-use blockchain_project::storage::Storage;
+use blockchain_project::network::Network;
+use blockchain_project::api::start_api;
+use blockchain_project::security;
+use blockchain_project::core::blockchain::Blockchain;
+use blockchain_project::core::transaction::{Transaction, TransactionKind};
 
 fn main() {
     std::env::set_var("RUST_BACKTRACE", "1");
@@ -32,15 +18,21 @@ fn main() {
     // Initialize Alice's balance
     blockchain.balances.insert("Alice".to_string(), 100);
 
-    // Add a sample transaction with a fee
+    // Add a sample transaction paying for its gas
     blockchain.add_transaction(Transaction {
         sender: "Alice".to_string(),
         receiver: "Bob".to_string(),
         amount: 50,
-        fee: 1,
+        gas_limit: 1,
+        gas_price: 1,
+        priority_fee: 0,
+        tokens: Vec::new(),
+        valid_after_height: None,
+        valid_until_height: None,
         nonce: 1,
         required_signatures: 1,
         signatures: Vec::new(),
+        kind: TransactionKind::Transfer,
     });
 
     // Choose consensus mechanism
@@ -49,12 +41,17 @@ fn main() {
     if use_pow {
         blockchain.add_block_with_pow();
     } else {
-        blockchain.add_block_with_pos();
+        let keypair = security::generate_keypair();
+        if let Err(e) = blockchain.add_block_with_pos(&keypair) {
+            println!("{}", e);
+        }
     }
 
     // Validate the blockchain
     println!("Is blockchain valid? {}", blockchain.is_chain_valid());
 
+    let blockchain = Arc::new(Mutex::new(blockchain));
+
     // Initialize the network
     let network = Arc::new(Network::new());
 
@@ -68,7 +65,7 @@ fn main() {
 
     // Start the API server
     rt.block_on(async {
-        start_api(network.clone()).await;
+        start_api(network.clone(), blockchain.clone()).await;
     });
 
     // Block the main thread until the runtime is shut down
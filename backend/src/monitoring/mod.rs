@@ -7,6 +7,12 @@ pub struct Metrics {
     pub transaction_count: IntCounter,
 }
 
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
 impl Metrics {
     pub fn new() -> Self {
         let block_count = IntCounter::new("block_count", "Number of blocks mined").unwrap();
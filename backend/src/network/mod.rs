@@ -8,6 +8,12 @@ pub struct Network {
     peers: Arc<Mutex<HashSet<String>>>,
 }
 
+impl Default for Network {
+    fn default() -> Self {
+        Network::new()
+    }
+}
+
 impl Network {
     pub fn new() -> Self {
         Network {
@@ -1,10 +1,39 @@
-use ring::signature::{Ed25519KeyPair, Signature, KeyPair, ED25519};
+use ring::signature::{Ed25519KeyPair, Signature, KeyPair, UnparsedPublicKey, ED25519};
 
 pub fn generate_keypair() -> Ed25519KeyPair {
     let rng = ring::rand::SystemRandom::new();
-    Ed25519KeyPair::generate_pkcs8(&rng).unwrap()
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+    Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap()
 }
 
 pub fn sign_data(keypair: &Ed25519KeyPair, data: &[u8]) -> Signature {
     keypair.sign(data)
-} 
\ No newline at end of file
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Addresses of key-bound accounts are the hex-encoded Ed25519 public key
+pub fn public_key_hex(keypair: &Ed25519KeyPair) -> String {
+    to_hex(keypair.public_key().as_ref())
+}
+
+pub fn verify_signature(public_key_hex: &str, data: &[u8], signature_hex: &str) -> bool {
+    match (from_hex(public_key_hex), from_hex(signature_hex)) {
+        (Some(public_key), Some(signature)) => UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(data, &signature)
+            .is_ok(),
+        _ => false,
+    }
+}
@@ -0,0 +1,196 @@
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use crate::smart_contracts::state::Word;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ValueType {
+    I32,
+    I64,
+    Bool,
+}
+
+impl ValueType {
+    pub fn parse(name: &str) -> Option<ValueType> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "i32" => Some(ValueType::I32),
+            "i64" => Some(ValueType::I64),
+            "bool" => Some(ValueType::Bool),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ValueType::I32 => "i32",
+            ValueType::I64 => "i64",
+            ValueType::Bool => "bool",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    Bool(bool),
+}
+
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::I32(_) => ValueType::I32,
+            Value::I64(_) => ValueType::I64,
+            Value::Bool(_) => ValueType::Bool,
+        }
+    }
+
+    // Interprets a raw word as the given type
+    pub fn from_i32(ty: ValueType, raw: i32) -> Value {
+        match ty {
+            ValueType::I32 => Value::I32(raw),
+            ValueType::I64 => Value::I64(raw as i64),
+            ValueType::Bool => Value::Bool(raw != 0),
+        }
+    }
+
+    // Narrows to a single word; None if an i64 does not fit
+    pub fn as_i32(&self) -> Option<i32> {
+        match self {
+            Value::I32(value) => Some(*value),
+            Value::I64(value) => i32::try_from(*value).ok(),
+            Value::Bool(value) => Some(*value as i32),
+        }
+    }
+
+    pub fn to_word(&self) -> Word {
+        match self {
+            Value::I32(value) => Word::from(*value),
+            Value::I64(value) => Word::from(*value),
+            Value::Bool(value) => Word::from(*value as i32),
+        }
+    }
+
+    // Narrows a stack word to the declared type; None if it does not fit
+    pub fn from_word(ty: ValueType, word: &Word) -> Option<Value> {
+        match ty {
+            ValueType::I32 => word.to_i32().map(Value::I32),
+            ValueType::I64 => word.to_i64().map(Value::I64),
+            ValueType::Bool => Some(Value::Bool(!word.is_zero())),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::I32(value) => write!(f, "{}", value),
+            Value::I64(value) => write!(f, "{}", value),
+            Value::Bool(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+// Errors a caller can match on instead of parsing messages
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ContractError {
+    UnknownFunction(String),
+    InvalidArguments { function: String, reason: String },
+    Execution(String),
+    Reverted(Word), // Code the contract passed to REVERT
+}
+
+impl fmt::Display for ContractError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ContractError::UnknownFunction(name) => write!(f, "Function not found: {}", name),
+            ContractError::InvalidArguments { function, reason } => write!(f, "Invalid arguments to {}: {}", function, reason),
+            ContractError::Execution(message) => write!(f, "{}", message),
+            ContractError::Reverted(code) => write!(f, "Execution reverted with code {}", code),
+        }
+    }
+}
+
+impl std::error::Error for ContractError {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionAbi {
+    pub name: String,
+    pub params: Vec<ValueType>,
+    pub returns: Option<ValueType>,
+    #[serde(default)]
+    pub entry: u32, // Bytecode offset of the function body; unused for Wasm exports
+}
+
+impl FunctionAbi {
+    // Parses a signature such as `transfer(i32, bool) -> i32`
+    pub fn parse(signature: &str, entry: u32) -> Result<Self, String> {
+        let invalid = || format!("Invalid function signature '{}'", signature.trim());
+        let open = signature.find('(').ok_or_else(invalid)?;
+        let close = signature.find(')').ok_or_else(invalid)?;
+        let name = signature[..open].trim();
+        if close < open || name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(invalid());
+        }
+        let params = signature[open + 1..close]
+            .split(',')
+            .filter(|param| !param.trim().is_empty())
+            .map(|param| ValueType::parse(param).ok_or_else(|| format!("Unknown type '{}'", param.trim())))
+            .collect::<Result<Vec<_>, _>>()?;
+        let rest = signature[close + 1..].trim();
+        let returns = match rest.strip_prefix("->") {
+            Some(ty) => Some(ValueType::parse(ty).ok_or_else(|| format!("Unknown type '{}'", ty.trim()))?),
+            None if rest.is_empty() => None,
+            None => return Err(invalid()),
+        };
+        Ok(FunctionAbi { name: name.to_string(), params, returns, entry })
+    }
+
+    pub fn signature(&self) -> String {
+        let params: Vec<&str> = self.params.iter().map(|ty| ty.name()).collect();
+        match self.returns {
+            Some(ty) => format!("{}({}) -> {}", self.name, params.join(", "), ty.name()),
+            None => format!("{}({})", self.name, params.join(", ")),
+        }
+    }
+
+    pub fn check_args(&self, args: &[Value]) -> Result<(), ContractError> {
+        let invalid = |reason: String| ContractError::InvalidArguments { function: self.name.clone(), reason };
+        if args.len() != self.params.len() {
+            return Err(invalid(format!("expected {} arguments, got {}", self.params.len(), args.len())));
+        }
+        for (position, (arg, ty)) in args.iter().zip(&self.params).enumerate() {
+            if arg.value_type() != *ty {
+                return Err(invalid(format!("argument {} must be {}, got {}", position, ty.name(), arg.value_type().name())));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Function of another contract that bytecode calls with XCALL
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ContractImport {
+    pub address: String,
+    pub function: FunctionAbi,
+}
+
+// Functions a contract exposes to callers, those it calls on other contracts and the oracle
+// feeds it reads
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ContractAbi {
+    pub functions: Vec<FunctionAbi>,
+    #[serde(default)]
+    pub imports: Vec<ContractImport>,
+    #[serde(default)]
+    pub feeds: Vec<String>, // Read by FEED, by position
+}
+
+impl ContractAbi {
+    pub fn function(&self, name: &str) -> Option<&FunctionAbi> {
+        self.functions.iter().find(|function| function.name == name)
+    }
+
+    pub fn resolve(&self, name: &str) -> Result<&FunctionAbi, ContractError> {
+        self.function(name).ok_or_else(|| ContractError::UnknownFunction(name.to_string()))
+    }
+}
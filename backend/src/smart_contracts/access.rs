@@ -0,0 +1,49 @@
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+// Holders of this role administer the contract alongside its admin account
+pub const ADMIN_ROLE: &str = "admin";
+
+// Roles granted to addresses, and the role each restricted function requires. Functions
+// without a requirement can be called by anyone.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AccessControl {
+    pub members: BTreeMap<String, BTreeSet<String>>, // Role -> addresses holding it
+    pub requirements: BTreeMap<String, String>, // Function -> role needed to call it
+}
+
+impl AccessControl {
+    pub fn has_role(&self, role: &str, address: &str) -> bool {
+        self.members.get(role).is_some_and(|members| members.contains(address))
+    }
+
+    pub fn grant(&mut self, role: &str, address: &str) {
+        self.members.entry(role.to_string()).or_default().insert(address.to_string());
+    }
+
+    pub fn revoke(&mut self, role: &str, address: &str) {
+        if let Some(members) = self.members.get_mut(role) {
+            members.remove(address);
+            if members.is_empty() {
+                self.members.remove(role);
+            }
+        }
+    }
+
+    // None lifts the restriction
+    pub fn require(&mut self, function: &str, role: Option<&str>) {
+        match role {
+            Some(role) => self.requirements.insert(function.to_string(), role.to_string()),
+            None => self.requirements.remove(function),
+        };
+    }
+
+    pub fn check(&self, function: &str, caller: &str) -> Result<(), String> {
+        match self.requirements.get(function) {
+            Some(role) if !self.has_role(role, caller) => {
+                Err(format!("{} needs the {} role to call {}", caller, role, function))
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fmt;
+use crate::smart_contracts::abi::ContractAbi;
+use crate::smart_contracts::opcodes::{decode, Instruction, Opcode};
+use crate::smart_contracts::vm::{self, MAX_STACK_DEPTH};
+use crate::smart_contracts::wasm;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum FindingKind {
+    UnboundedLoop,
+    Reentrancy,
+    DisallowedImport,
+    UnreachableCode,
+    InvalidJump,
+    StackLimit,
+    MemoryLimit,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub kind: FindingKind,
+    pub severity: Severity,
+    pub location: Option<usize>, // Bytecode offset, or Wasm function index
+    pub message: String,
+}
+
+impl Finding {
+    pub fn new(kind: FindingKind, severity: Severity, location: Option<usize>, message: String) -> Self {
+        Finding { kind, severity, location, message }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}: {}", self.severity, self.message)
+    }
+}
+
+// Checks contract code without running it. Err means the code could not be read at all;
+// anything questionable about code that can be read is reported as a finding.
+pub fn analyze(code: &str, abi: &ContractAbi) -> Result<Vec<Finding>, String> {
+    if wasm::is_wasm(code) {
+        return wasm::analyze(code);
+    }
+    analyze_bytecode(&vm::compile(code)?, abi)
+}
+
+// Fails with every finding at or above the threshold
+pub fn enforce(findings: &[Finding], threshold: Severity) -> Result<(), String> {
+    let blocking: Vec<String> = findings.iter()
+        .filter(|finding| finding.severity >= threshold)
+        .map(|finding| finding.to_string())
+        .collect();
+    if blocking.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Code rejected by analysis: {}", blocking.join("; ")))
+    }
+}
+
+// (popped, pushed) words; DUP and SWAP pop what they need to be present and push it back
+fn stack_effect(instruction: &Instruction, abi: &ContractAbi) -> (usize, usize) {
+    let operand = instruction.operand as usize;
+    match instruction.opcode {
+        Opcode::Stop | Opcode::Jump | Opcode::Call | Opcode::Ret | Opcode::Revert => (0, 0),
+        Opcode::Push | Opcode::Feed => (0, 1),
+        Opcode::Pop | Opcode::JumpI | Opcode::Return => (1, 0),
+        Opcode::Dup => (operand + 1, operand + 2),
+        Opcode::Swap => (operand + 2, operand + 2),
+        Opcode::Neg | Opcode::IsZero | Opcode::SLoad | Opcode::ALen => (1, 1),
+        Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div | Opcode::Mod => (2, 1),
+        Opcode::Eq | Opcode::Lt | Opcode::Gt | Opcode::And | Opcode::Or => (2, 1),
+        Opcode::MapLoad | Opcode::ALoad => (2, 1),
+        Opcode::SStore | Opcode::APush => (2, 0),
+        Opcode::MapStore | Opcode::AStore => (3, 0),
+        Opcode::Log => (operand + 1, 0),
+        Opcode::XCall => {
+            let params = abi.imports.get(operand).map_or(0, |import| import.function.params.len());
+            (params + 2, 2)
+        }
+    }
+}
+
+// Follows every path from the entry points, tracking the stack height and whether an external
+// call has already been made on the path
+fn analyze_bytecode(code: &[u8], abi: &ContractAbi) -> Result<Vec<Finding>, String> {
+    let instructions = decode(code)?;
+    let index_of: HashMap<usize, usize> = instructions.iter()
+        .enumerate()
+        .map(|(index, instruction)| (instruction.offset, index))
+        .collect();
+    let mut findings = Vec::new();
+    let mut report = |kind, severity, offset: usize, message: String| {
+        let finding = Finding::new(kind, severity, Some(offset), message);
+        if !findings.contains(&finding) {
+            findings.push(finding);
+        }
+    };
+
+    for instruction in instructions.iter().filter(|instruction| instruction.opcode.is_jump()) {
+        if !index_of.contains_key(&(instruction.operand as usize)) {
+            report(FindingKind::InvalidJump, Severity::Error, instruction.offset, format!("Jump to {} is not an instruction", instruction.operand));
+        }
+    }
+
+    // A backward jump with no branch, call return or halt between its target and itself
+    // repeats until the gas runs out
+    for instruction in instructions.iter().filter(|instruction| instruction.opcode == Opcode::Jump) {
+        let (start, end) = (instruction.operand as usize, instruction.offset);
+        if start > end {
+            continue;
+        }
+        let exits = instructions.iter()
+            .filter(|inner| inner.offset >= start && inner.offset <= end)
+            .any(|inner| match inner.opcode {
+                Opcode::Stop | Opcode::Return | Opcode::Revert | Opcode::Ret => true,
+                Opcode::JumpI => true,
+                Opcode::Jump => (inner.operand as usize) < start || (inner.operand as usize) > end,
+                _ => false,
+            });
+        if !exits {
+            report(FindingKind::UnboundedLoop, Severity::Error, start, format!("Loop at offset {} never exits", start));
+        }
+    }
+
+    // Raw bytecode carries no ABI and starts at offset 0. Its parameters are unknown, so the
+    // stack can only be checked for overflow.
+    let known_params = !abi.functions.is_empty();
+    let entries: Vec<(usize, usize)> = if !known_params {
+        vec![(0, 0)]
+    } else {
+        abi.functions.iter().map(|function| (function.entry as usize, function.params.len())).collect()
+    };
+    let mut work: Vec<(usize, usize, bool)> = entries.iter()
+        .filter_map(|(entry, height)| index_of.get(entry).map(|position| (*position, *height, false)))
+        .collect();
+    let mut seen: HashMap<(usize, bool), usize> = HashMap::new();
+    let mut reachable = vec![false; instructions.len()];
+
+    while let Some((position, height, called_out)) = work.pop() {
+        let instruction = match instructions.get(position) {
+            Some(instruction) => instruction,
+            None => continue, // Ran off the end, which stops execution
+        };
+        match seen.get(&(position, called_out)) {
+            Some(previous) if height > *previous => {
+                report(FindingKind::StackLimit, Severity::Warning, instruction.offset, format!("Stack grows on every pass through offset {}", instruction.offset));
+                continue;
+            }
+            Some(_) => continue,
+            None => {}
+        }
+        seen.insert((position, called_out), height);
+        reachable[position] = true;
+
+        let (popped, pushed) = stack_effect(instruction, abi);
+        if height < popped && known_params {
+            report(FindingKind::StackLimit, Severity::Error, instruction.offset, format!("{} at offset {} can underflow the stack", instruction.opcode.mnemonic(), instruction.offset));
+            continue;
+        }
+        let height = (height + pushed).saturating_sub(popped);
+        if height > MAX_STACK_DEPTH {
+            report(FindingKind::StackLimit, Severity::Error, instruction.offset, format!("Stack exceeds {} items at offset {}", MAX_STACK_DEPTH, instruction.offset));
+            continue;
+        }
+        if instruction.opcode.is_storage_write() && called_out {
+            report(FindingKind::Reentrancy, Severity::Warning, instruction.offset, format!("Storage is written at offset {} after calling another contract", instruction.offset));
+        }
+        let called_out = called_out || instruction.opcode == Opcode::XCall;
+
+        let target = index_of.get(&(instruction.operand as usize)).copied();
+        match instruction.opcode {
+            Opcode::Stop | Opcode::Return | Opcode::Revert | Opcode::Ret => {}
+            Opcode::Jump => work.extend(target.map(|target| (target, height, called_out))),
+            // Subroutines are assumed to leave the stack as they found it
+            Opcode::JumpI | Opcode::Call => {
+                work.extend(target.map(|target| (target, height, called_out)));
+                work.push((position + 1, height, called_out));
+            }
+            _ => work.push((position + 1, height, called_out)),
+        }
+    }
+
+    // One finding per run of instructions no path reaches
+    let mut position = 0;
+    while position < instructions.len() {
+        if reachable[position] {
+            position += 1;
+            continue;
+        }
+        let start = instructions[position].offset;
+        while position < instructions.len() && !reachable[position] {
+            position += 1;
+        }
+        let end = instructions[position - 1].offset;
+        report(FindingKind::UnreachableCode, Severity::Info, start, format!("Code at offsets {}-{} is unreachable", start, end));
+    }
+    Ok(findings)
+}
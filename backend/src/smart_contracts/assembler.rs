@@ -0,0 +1,164 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+use crate::smart_contracts::abi::{ContractAbi, ContractImport, FunctionAbi, ValueType};
+use crate::smart_contracts::opcodes::{decode, Opcode};
+
+const FUNCTION_DIRECTIVE: &str = ".function";
+const IMPORT_DIRECTIVE: &str = ".import";
+const FEED_DIRECTIVE: &str = ".feed";
+
+fn strip_comment(line: &str) -> &str {
+    match line.find(';') {
+        Some(position) => &line[..position],
+        None => line,
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    assemble_with_abi(source).map(|(code, _)| code)
+}
+
+fn check_word_types(function: &FunctionAbi, line: usize) -> Result<(), String> {
+    if function.params.iter().chain(&function.returns).any(|ty| *ty == ValueType::I64) {
+        return Err(format!("Line {}: bytecode functions only take i32 and bool values", line));
+    }
+    Ok(())
+}
+
+// Assembles contract source into bytecode plus the functions it exports. One instruction per
+// line, `label:` marks a jump target, `;` starts a comment and `.function` declares an entry
+// point at the next instruction. `.import <address> <signature>` declares a function of another
+// contract that XCALL can reach by its position among the imports, and `.feed <name>` an oracle
+// feed that FEED reads the same way. Arguments arrive on the
+// stack, first argument deepest:
+//
+//     .function max(i32, i32) -> i32
+//         DUP 1
+//         DUP 1
+//         LT
+//         JUMPI done
+//         SWAP 0
+//     done:
+//         RETURN
+pub fn assemble_with_abi(source: &str) -> Result<(Vec<u8>, ContractAbi), String> {
+    let mut labels: HashMap<&str, u32> = HashMap::new();
+    let mut functions: Vec<FunctionAbi> = Vec::new();
+    let mut imports: Vec<ContractImport> = Vec::new();
+    let mut feeds: Vec<String> = Vec::new();
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+
+    for (number, raw) in source.lines().enumerate() {
+        let mut line = strip_comment(raw).trim();
+        if let Some(signature) = line.strip_prefix(FUNCTION_DIRECTIVE) {
+            let function = FunctionAbi::parse(signature, offset as u32).map_err(|e| format!("Line {}: {}", number + 1, e))?;
+            check_word_types(&function, number + 1)?;
+            if functions.iter().any(|existing| existing.name == function.name) {
+                return Err(format!("Line {}: duplicate function '{}'", number + 1, function.name));
+            }
+            functions.push(function);
+            continue;
+        }
+        if let Some(import) = line.strip_prefix(IMPORT_DIRECTIVE) {
+            let import = import.trim();
+            let split = import.find(char::is_whitespace).ok_or_else(|| format!("Line {}: expected an address and a signature", number + 1))?;
+            let function = FunctionAbi::parse(&import[split..], 0).map_err(|e| format!("Line {}: {}", number + 1, e))?;
+            check_word_types(&function, number + 1)?;
+            if imports.len() > u8::MAX as usize {
+                return Err(format!("Line {}: too many imports", number + 1));
+            }
+            imports.push(ContractImport { address: import[..split].to_string(), function });
+            continue;
+        }
+        if let Some(feed) = line.strip_prefix(FEED_DIRECTIVE) {
+            let feed = feed.trim();
+            if feed.is_empty() || feed.contains(char::is_whitespace) {
+                return Err(format!("Line {}: expected a feed name", number + 1));
+            }
+            if feeds.len() > u8::MAX as usize {
+                return Err(format!("Line {}: too many feeds", number + 1));
+            }
+            feeds.push(feed.to_string());
+            continue;
+        }
+        if let Some(position) = line.find(':') {
+            let label = line[..position].trim();
+            if label.is_empty() || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(format!("Line {}: invalid label '{}'", number + 1, label));
+            }
+            if labels.insert(label, offset as u32).is_some() {
+                return Err(format!("Line {}: duplicate label '{}'", number + 1, label));
+            }
+            line = line[position + 1..].trim();
+        }
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut parts = line.split_whitespace();
+        let mnemonic = parts.next().unwrap();
+        let opcode = Opcode::from_mnemonic(mnemonic)
+            .ok_or_else(|| format!("Line {}: unknown instruction '{}'", number + 1, mnemonic))?;
+        let operand = parts.next();
+        if parts.next().is_some() {
+            return Err(format!("Line {}: too many operands", number + 1));
+        }
+        match (opcode.immediate_size(), operand) {
+            (0, Some(_)) => return Err(format!("Line {}: {} takes no operand", number + 1, opcode.mnemonic())),
+            (size, None) if size > 0 => return Err(format!("Line {}: {} needs an operand", number + 1, opcode.mnemonic())),
+            _ => {}
+        }
+        instructions.push((number + 1, opcode, operand));
+        offset += 1 + opcode.immediate_size();
+    }
+
+    let mut code = Vec::with_capacity(offset);
+    for (line, opcode, operand) in instructions {
+        code.push(opcode.byte());
+        let operand = match operand {
+            Some(operand) => operand,
+            None => continue,
+        };
+        if opcode.is_jump() {
+            let target = match labels.get(operand) {
+                Some(target) => *target,
+                None => operand.parse::<u32>().map_err(|_| format!("Line {}: unknown label '{}'", line, operand))?,
+            };
+            code.extend_from_slice(&target.to_be_bytes());
+        } else if opcode == Opcode::Push {
+            let value = operand.parse::<i32>().map_err(|_| format!("Line {}: invalid value '{}'", line, operand))?;
+            code.extend_from_slice(&value.to_be_bytes());
+        } else {
+            let index = operand.parse::<u8>().map_err(|_| format!("Line {}: invalid operand '{}'", line, operand))?;
+            code.push(index);
+        }
+    }
+    Ok((code, ContractAbi { functions, imports, feeds }))
+}
+
+// Turns bytecode back into source that `assemble` accepts, with labels for jump targets
+pub fn disassemble(code: &[u8]) -> Result<String, String> {
+    let instructions = decode(code)?;
+    let starts: BTreeSet<u32> = instructions.iter().map(|instruction| instruction.offset as u32).collect();
+    let targets: BTreeSet<u32> = instructions.iter()
+        .filter(|instruction| instruction.opcode.is_jump() && starts.contains(&instruction.operand))
+        .map(|instruction| instruction.operand)
+        .collect();
+
+    let mut source = String::new();
+    for instruction in &instructions {
+        if targets.contains(&(instruction.offset as u32)) {
+            writeln!(source, "label_{}:", instruction.offset).unwrap();
+        }
+        let mnemonic = instruction.opcode.mnemonic();
+        match instruction.opcode {
+            opcode if opcode.is_jump() && targets.contains(&instruction.operand) => {
+                writeln!(source, "    {} label_{}", mnemonic, instruction.operand).unwrap()
+            }
+            Opcode::Push => writeln!(source, "    {} {}", mnemonic, instruction.operand as i32).unwrap(),
+            opcode if opcode.immediate_size() > 0 => writeln!(source, "    {} {}", mnemonic, instruction.operand).unwrap(),
+            _ => writeln!(source, "    {}", mnemonic).unwrap(),
+        }
+    }
+    Ok(source)
+}
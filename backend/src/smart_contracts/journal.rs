@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use crate::core::oracle::Oracle;
+use crate::core::receipt::Log;
+use crate::smart_contracts::abi::{ContractError, Value};
+use crate::smart_contracts::state::{StorageValue, Word};
+use crate::smart_contracts::vm::{self, ExternalCall, Host, VirtualMachine};
+use crate::smart_contracts::{wasm, CallContext, ContractStatus, ReentrancyPolicy, SmartContract};
+
+pub const MAX_CALL_DEPTH: usize = 64;
+
+// Undo record for a single write
+#[derive(Debug, Clone)]
+enum JournalEntry {
+    Storage { address: String, key: Word, previous: Option<StorageValue> },
+    Balance { address: String, previous: Option<u64> },
+}
+
+// Position in the journal that writes can be rolled back to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JournalMark {
+    entries: usize,
+    logs: usize,
+}
+
+// Contract storage and balances while a transaction executes. Writes go straight to the maps
+// and are recorded in the journal, so every call can be undone back to the mark taken when it
+// started, however deeply it is nested.
+#[derive(Debug, Default)]
+pub struct WorldState {
+    pub contracts: HashMap<String, SmartContract>,
+    pub balances: HashMap<String, u64>,
+    pub logs: Vec<Log>,
+    pub oracle: Oracle, // Finalized feed values; contracts only read them
+    journal: Vec<JournalEntry>,
+    frames: Vec<String>, // Addresses of the contracts currently executing, outermost first
+}
+
+impl WorldState {
+    pub fn new(contracts: HashMap<String, SmartContract>, balances: HashMap<String, u64>) -> Self {
+        WorldState {
+            contracts,
+            balances,
+            logs: Vec::new(),
+            oracle: Oracle::default(),
+            journal: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn into_parts(self) -> (HashMap<String, SmartContract>, HashMap<String, u64>, Vec<Log>) {
+        (self.contracts, self.balances, self.logs)
+    }
+
+    pub fn mark(&self) -> JournalMark {
+        JournalMark { entries: self.journal.len(), logs: self.logs.len() }
+    }
+
+    // Undoes every write made since the mark, newest first
+    pub fn revert_to(&mut self, mark: JournalMark) {
+        while self.journal.len() > mark.entries {
+            match self.journal.pop().unwrap() {
+                JournalEntry::Storage { address, key, previous } => {
+                    if let Some(contract) = self.contracts.get_mut(&address) {
+                        contract.state.set(key, previous).expect("Journaled values were stored before");
+                    }
+                }
+                JournalEntry::Balance { address, previous } => {
+                    match previous {
+                        Some(balance) => self.balances.insert(address, balance),
+                        None => self.balances.remove(&address),
+                    };
+                }
+            }
+        }
+        self.logs.truncate(mark.logs);
+    }
+
+    pub fn storage_value(&self, address: &str, key: &Word) -> Option<StorageValue> {
+        self.contracts.get(address)?.state.get(key).cloned()
+    }
+
+    // Unset slots, and slots of addresses without a contract, read as zero
+    pub fn storage_word(&self, address: &str, key: &Word) -> Result<Word, String> {
+        self.contracts.get(address).map_or(Ok(Word::ZERO), |contract| contract.state.word(key))
+    }
+
+    // None, a zero word or empty bytes clear the slot
+    pub fn storage_set(&mut self, address: &str, key: Word, value: Option<StorageValue>) -> Result<(), String> {
+        let contract = self.contracts.get_mut(address).ok_or_else(|| format!("No contract at {}", address))?;
+        let previous = contract.state.set(key, value)?;
+        self.journal.push(JournalEntry::Storage { address: address.to_string(), key, previous });
+        Ok(())
+    }
+
+    pub fn balance(&self, address: &str) -> u64 {
+        self.balances.get(address).copied().unwrap_or(0)
+    }
+
+    fn set_balance(&mut self, address: &str, balance: u64) {
+        let previous = self.balances.insert(address.to_string(), balance);
+        self.journal.push(JournalEntry::Balance { address: address.to_string(), previous });
+    }
+
+    pub fn transfer(&mut self, from: &str, to: &str, amount: u64) -> Result<(), String> {
+        if amount == 0 {
+            return Ok(());
+        }
+        let available = self.balance(from);
+        if available < amount {
+            return Err(format!("Insufficient balance: {} has {}, needs {}", from, available, amount));
+        }
+        self.set_balance(from, available - amount);
+        let received = self.balance(to) + amount;
+        self.set_balance(to, received);
+        Ok(())
+    }
+
+    pub fn emit(&mut self, log: Log) {
+        self.logs.push(log);
+    }
+
+    // Runs a function of the contract at `context.address` in a new frame, moving
+    // `context.value` from the caller to the contract first. If it fails, all of its writes,
+    // including the value and those of calls it made, are rolled back; gas used is recorded
+    // either way.
+    pub fn call(&mut self, context: &mut CallContext, function_name: &str, args: &[Value]) -> Result<Option<Value>, ContractError> {
+        if context.depth >= MAX_CALL_DEPTH {
+            return Err(ContractError::Execution(format!("Call depth exceeds {}", MAX_CALL_DEPTH)));
+        }
+        let mark = self.mark();
+        self.frames.push(context.address.clone());
+        let result = self.execute(context, function_name, args);
+        self.frames.pop();
+        if result.is_err() {
+            self.revert_to(mark);
+        }
+        result
+    }
+
+    fn execute(&mut self, context: &mut CallContext, function_name: &str, args: &[Value]) -> Result<Option<Value>, ContractError> {
+        let contract = self.contracts.get(&context.address)
+            .ok_or_else(|| ContractError::Execution(format!("No contract at {}", context.address)))?;
+        if contract.status != ContractStatus::Active {
+            return Err(ContractError::Execution(format!("Contract {} is {}", context.address, contract.status.name())));
+        }
+        let active = self.frames.iter().filter(|address| **address == context.address).count();
+        if active > 1 && contract.reentrancy == ReentrancyPolicy::Forbid {
+            return Err(ContractError::Execution(format!("Reentrant call into {}", context.address)));
+        }
+        let function = contract.abi.resolve(function_name)?.clone();
+        contract.acl.check(function_name, &context.caller).map_err(ContractError::Execution)?;
+        function.check_args(args)?;
+        let code = contract.code.clone();
+        let imports = contract.abi.imports.clone();
+        let feeds = contract.abi.feeds.clone();
+        self.transfer(&context.caller, &context.address, context.value).map_err(ContractError::Execution)?;
+
+        if wasm::is_wasm(&code) {
+            return wasm::execute(&code, &function, args, self, context).map_err(ContractError::Execution);
+        }
+        let bytecode = vm::compile(&code).map_err(ContractError::Execution)?;
+        let mut vm = VirtualMachine::new(context.gas_limit);
+        vm.imports = imports;
+        vm.feeds = feeds;
+        let result = vm.call(&bytecode, &function, args, &mut FrameHost { world: self, context });
+        context.gas_used = vm.gas_used;
+        result.map_err(|e| vm.reverted.map_or(ContractError::Execution(e), ContractError::Reverted))
+    }
+}
+
+// Storage of the contract running in the current frame, and the calls it makes
+struct FrameHost<'a> {
+    world: &'a mut WorldState,
+    context: &'a CallContext,
+}
+
+impl Host for FrameHost<'_> {
+    fn sload(&mut self, key: &Word) -> Result<Word, String> {
+        self.world.storage_word(&self.context.address, key)
+    }
+
+    fn sstore(&mut self, key: Word, value: Word) -> Result<(), String> {
+        self.world.storage_set(&self.context.address, key, Some(StorageValue::Word(value)))
+    }
+
+    fn call(&mut self, call: ExternalCall) -> (Result<Option<Value>, ContractError>, u64) {
+        let mut child = self.context.child(call.address, call.value, call.gas_limit);
+        let result = self.world.call(&mut child, &call.function, &call.args);
+        (result, child.gas_used)
+    }
+
+    fn emit(&mut self, topics: Vec<String>, data: String) {
+        self.world.emit(Log { address: self.context.address.clone(), topics, data });
+    }
+
+    fn feed(&self, name: &str) -> Option<i64> {
+        self.world.oracle.value(name)
+    }
+}
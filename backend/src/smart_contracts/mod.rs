@@ -1,38 +1,214 @@
-use std::collections::HashMap;
-use std::collections::HashSet;
 use std::error::Error;
-use blockchain_project::storage::Storage;
-use reqwest;
+use crate::storage::Storage;
+use serde::{Serialize, Deserialize};
 
-mod vm;
+pub mod abi;
+pub mod access;
+pub mod analyzer;
+pub mod assembler;
+pub mod journal;
+pub mod opcodes;
+pub mod state;
+pub mod vm;
+pub mod wasm;
 
-pub use vm::VirtualMachine;
+pub use abi::{ContractAbi, ContractError, Value};
+pub use access::AccessControl;
+pub use analyzer::{Finding, Severity};
+pub use assembler::{assemble_with_abi, disassemble};
+pub use journal::WorldState;
+pub use state::{ContractStorage, StorageValue, Word};
 
+// Gas for calls made directly on a contract, outside of any transaction
+pub const DEFAULT_GAS_LIMIT: u64 = 1000;
+
+// Frame a contract call runs in: who called it (an account or, for nested calls, the calling
+// contract), the contract's own address, the value sent along and the gas it may use
+#[derive(Debug, Clone, Default)]
+pub struct CallContext {
+    pub caller: String,
+    pub address: String,
+    pub value: u64,
+    pub gas_limit: u64,
+    pub gas_used: u64,
+    pub depth: usize, // 0 for calls made by a transaction
+}
+
+impl CallContext {
+    pub fn new(caller: String, address: String, gas_limit: u64) -> Self {
+        CallContext {
+            caller,
+            address,
+            gas_limit,
+            ..Default::default()
+        }
+    }
+
+    // Frame for a call this contract makes to another one
+    pub fn child(&self, address: String, value: u64, gas_limit: u64) -> Self {
+        CallContext {
+            caller: self.address.clone(),
+            address,
+            value,
+            gas_limit,
+            gas_used: 0,
+            depth: self.depth + 1,
+        }
+    }
+}
+
+// Whether a contract may be called again while one of its calls is still on the stack
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum ReentrancyPolicy {
+    #[default]
+    Forbid,
+    Allow,
+}
+
+// Paused contracts reject calls until resumed. Terminated ones have had their code and storage
+// reclaimed and can never run again.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum ContractStatus {
+    #[default]
+    Active,
+    Paused,
+    Terminated,
+}
+
+impl ContractStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ContractStatus::Active => "active",
+            ContractStatus::Paused => "paused",
+            ContractStatus::Terminated => "terminated",
+        }
+    }
+}
+
+// Code a contract ran before an upgrade replaced it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ContractVersion {
+    pub version: u32,
+    pub code: String,
+    pub abi: ContractAbi,
+    pub replaced_at: u64, // Height of the block that upgraded it
+}
+
+// Upgrade waiting for the contract's timelock to expire
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingUpgrade {
+    pub code: String,
+    pub migration: Option<String>,
+    pub ready_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SmartContract {
     pub code: String, // The code of the smart contract
-    pub state: HashMap<String, i32>, // The state of the smart contract
-    pub roles: HashSet<String>, // Set of roles allowed to execute certain functions
+    pub state: ContractStorage, // The state of the smart contract
+    #[serde(default)]
+    pub acl: AccessControl, // Roles held by addresses and the roles functions require
+    #[serde(default)]
+    pub abi: ContractAbi, // Functions the code exports
+    #[serde(default)]
+    pub reentrancy: ReentrancyPolicy,
+    #[serde(default)]
+    pub admin: Option<String>, // Account allowed to upgrade, pause and terminate; the deployer on chain
+    #[serde(default)]
+    pub upgrade_delay: u64, // Blocks an upgrade must wait between being scheduled and applied
+    #[serde(default)]
+    pub versions: Vec<ContractVersion>, // Replaced code, oldest first
+    #[serde(default)]
+    pub pending_upgrade: Option<PendingUpgrade>,
+    #[serde(default)]
+    pub status: ContractStatus,
+}
+
+// Wasm contracts export their functions; assembly declares them with `.function`. Raw hex
+// bytecode carries no ABI.
+fn derive_abi(code: &str) -> Result<ContractAbi, String> {
+    if wasm::is_wasm(code) {
+        wasm::abi(code)
+    } else if code.trim().starts_with("0x") {
+        vm::compile(code).map(|_| ContractAbi::default())
+    } else {
+        assemble_with_abi(code).map(|(_, abi)| abi)
+    }
 }
 
 impl SmartContract {
     pub fn new(code: String) -> Self {
         SmartContract {
+            abi: derive_abi(&code).unwrap_or_default(),
             code,
-            state: HashMap::new(),
-            roles: HashSet::new(),
+            state: ContractStorage::default(),
+            acl: AccessControl::default(),
+            reentrancy: ReentrancyPolicy::default(),
+            admin: None,
+            upgrade_delay: 0,
+            versions: Vec::new(),
+            pending_upgrade: None,
+            status: ContractStatus::Active,
         }
     }
 
+    // Validates code before it goes on chain. Wasm modules must be free of floats and threads;
+    // anything else must assemble for the bytecode VM.
+    pub fn deploy(code: String) -> Result<Self, String> {
+        if wasm::is_wasm(&code) {
+            wasm::validate(&code)?;
+        }
+        let abi = derive_abi(&code)?;
+        Ok(SmartContract {
+            code,
+            state: ContractStorage::default(),
+            acl: AccessControl::default(),
+            abi,
+            reentrancy: ReentrancyPolicy::default(),
+            admin: None,
+            upgrade_delay: 0,
+            versions: Vec::new(),
+            pending_upgrade: None,
+            status: ContractStatus::Active,
+        })
+    }
+
     pub fn execute(&mut self, function_name: &str, params: &[i32]) -> Result<i32, Box<dyn Error>> {
-        let mut vm = VirtualMachine::new(1000); // Set a default gas limit
-        vm.execute_with_gas(&self.code, params, 1000)?;
-        match function_name {
-            "add" => Ok(params.iter().sum()),
-            "multiply" => Ok(params.iter().product()),
-            _ => Err("Function not found".into()),
+        self.execute_with_gas(function_name, params, DEFAULT_GAS_LIMIT)
+    }
+
+    pub fn execute_with_gas(&mut self, function_name: &str, params: &[i32], gas_limit: u64) -> Result<i32, Box<dyn Error>> {
+        let mut context = CallContext::new(String::new(), String::new(), gas_limit);
+        self.execute_with_context(function_name, params, &mut context)
+    }
+
+    // Word-sized entry point: params are read as the function's declared types and the
+    // result is narrowed back to i32 (0 for functions without a return value)
+    pub fn execute_with_context(&mut self, function_name: &str, params: &[i32], context: &mut CallContext) -> Result<i32, Box<dyn Error>> {
+        let args: Vec<Value> = match self.abi.function(function_name) {
+            Some(function) if function.params.len() == params.len() => {
+                function.params.iter().zip(params).map(|(ty, param)| Value::from_i32(*ty, *param)).collect()
+            }
+            _ => params.iter().map(|param| Value::I32(*param)).collect(),
+        };
+        match self.call(function_name, &args, context)? {
+            None => Ok(0),
+            Some(value) => value.as_i32().ok_or_else(|| format!("Return value {} does not fit in i32", value).into()),
         }
     }
 
+    // Dispatches to the contract's own code through its ABI, outside of any chain state.
+    // State changes are kept only if the call succeeds.
+    pub fn call(&mut self, function_name: &str, args: &[Value], context: &mut CallContext) -> Result<Option<Value>, ContractError> {
+        let mut world = WorldState::default();
+        world.contracts.insert(context.address.clone(), self.clone());
+        let result = world.call(context, function_name, args);
+        if let Some(contract) = world.contracts.remove(&context.address) {
+            *self = contract;
+        }
+        result
+    }
+
     pub fn save_state(&self, storage: &Storage, contract_id: &str) {
         storage.store_state(contract_id, &self.state);
     }
@@ -41,90 +217,134 @@ impl SmartContract {
         self.state = storage.load_state(contract_id);
     }
 
-    pub fn emit_event(&self, event_name: &str, data: &str) {
-        println!("Event: {} - Data: {}", event_name, data);
-        // Add logic to notify listeners or filter events
+    // Versions are numbered from 1; the current code is the latest
+    pub fn version(&self) -> u32 {
+        self.versions.len() as u32 + 1
     }
 
-    pub fn upgrade(&mut self, new_code: String) {
-        self.code = new_code;
-        // Optionally, handle state migration if needed
+    pub fn code_at(&self, version: u32) -> Option<(&str, &ContractAbi)> {
+        if version == self.version() {
+            return Some((&self.code, &self.abi));
+        }
+        let index = version.checked_sub(1)? as usize;
+        self.versions.get(index).map(|old| (old.code.as_str(), &old.abi))
     }
 
-    pub fn add_role(&mut self, role: String) {
-        self.roles.insert(role);
+    pub fn is_admin(&self, account: &str) -> bool {
+        self.admin.as_deref() == Some(account) || self.acl.has_role(access::ADMIN_ROLE, account)
     }
 
-    pub fn execute_with_role(&mut self, role: &str, function_name: &str, params: &[i32]) -> Result<i32, Box<dyn Error>> {
-        if self.roles.contains(role) {
-            self.execute(function_name, params)
-        } else {
-            Err("Access denied".into())
+    // Replaces the code while keeping the state, archiving the code being replaced. Only the
+    // admin may upgrade, and the new code is checked as it would be for a deployment.
+    pub fn upgrade(&mut self, caller: &str, new_code: String, height: u64) -> Result<(), String> {
+        if !self.is_admin(caller) {
+            return Err(format!("{} is not allowed to upgrade this contract", caller));
         }
+        if self.status == ContractStatus::Terminated {
+            return Err("Terminated contracts cannot be upgraded".to_string());
+        }
+        let upgraded = SmartContract::deploy(new_code)?;
+        let version = self.version();
+        self.versions.push(ContractVersion {
+            version,
+            code: std::mem::replace(&mut self.code, upgraded.code),
+            abi: std::mem::replace(&mut self.abi, upgraded.abi),
+            replaced_at: height,
+        });
+        self.pending_upgrade = None;
+        Ok(())
     }
 
-    pub async fn fetch_external_data(&self, url: &str) -> Result<String, Box<dyn Error>> {
-        let response = reqwest::get(url).await?;
-        let data = response.text().await?;
-        Ok(data)
+    fn authorize_roles(&self, caller: &str) -> Result<(), String> {
+        if self.is_admin(caller) {
+            Ok(())
+        } else {
+            Err(format!("{} is not allowed to manage roles", caller))
+        }
     }
 
-    pub fn pause(&mut self) {
-        // Logic to pause the contract
+    pub fn grant_role(&mut self, caller: &str, role: &str, account: &str) -> Result<(), String> {
+        self.authorize_roles(caller)?;
+        self.acl.grant(role, account);
+        Ok(())
     }
 
-    pub fn resume(&mut self) {
-        // Logic to resume the contract
+    pub fn revoke_role(&mut self, caller: &str, role: &str, account: &str) -> Result<(), String> {
+        self.authorize_roles(caller)?;
+        self.acl.revoke(role, account);
+        Ok(())
     }
 
-    pub fn terminate(&mut self) {
-        // Logic to terminate the contract
+    // Restricts a function to holders of the role, or opens it to everyone with None
+    pub fn require_role(&mut self, caller: &str, function_name: &str, role: Option<&str>) -> Result<(), String> {
+        self.authorize_roles(caller)?;
+        self.acl.require(function_name, role);
+        Ok(())
     }
 
-    pub fn log_execution(&self, message: &str) {
-        println!("Contract Execution Log: {}", message);
+    // Runs the function as the given address, which must hold any role the function requires
+    pub fn execute_as(&mut self, caller: &str, function_name: &str, params: &[i32]) -> Result<i32, Box<dyn Error>> {
+        let mut context = CallContext::new(caller.to_string(), String::new(), DEFAULT_GAS_LIMIT);
+        self.execute_with_context(function_name, params, &mut context)
     }
 
-    pub fn execute_with_error_handling(&mut self, function_name: &str, params: &[i32]) -> Result<i32, Box<dyn Error>> {
-        match self.execute(function_name, params) {
-            Ok(result) => Ok(result),
-            Err(e) => {
-                self.log_execution(&format!("Error executing {}: {}", function_name, e));
-                Err(e)
-            }
+    // Lifecycle transitions are limited to the admin
+    fn authorize_lifecycle(&self, caller: &str) -> Result<(), String> {
+        if self.is_admin(caller) {
+            Ok(())
+        } else {
+            Err(format!("{} is not allowed to change the contract's status", caller))
         }
     }
 
-    pub fn audit(&self) -> Vec<String> {
-        let mut issues = Vec::new();
-        if self.code.contains("unsafe") {
-            issues.push("Unsafe operations detected".to_string());
+    fn transition(&mut self, caller: &str, from: ContractStatus, to: ContractStatus) -> Result<(), String> {
+        self.authorize_lifecycle(caller)?;
+        if self.status != from {
+            return Err(format!("Contract is {}, not {}", self.status.name(), from.name()));
         }
-        // Add more checks as needed
-        issues
+        self.status = to;
+        Ok(())
     }
-}
 
-pub struct EventManager {
-    subscribers: HashMap<String, Vec<Box<dyn Fn(&str)>>>,
-}
+    pub fn pause(&mut self, caller: &str) -> Result<(), String> {
+        self.transition(caller, ContractStatus::Active, ContractStatus::Paused)
+    }
 
-impl EventManager {
-    pub fn new() -> Self {
-        EventManager {
-            subscribers: HashMap::new(),
+    pub fn resume(&mut self, caller: &str) -> Result<(), String> {
+        self.transition(caller, ContractStatus::Paused, ContractStatus::Active)
+    }
+
+    // Frees the code and storage of an active or paused contract; the chain pays out its
+    // remaining balance. Earlier versions stay queryable.
+    pub fn terminate(&mut self, caller: &str) -> Result<(), String> {
+        self.authorize_lifecycle(caller)?;
+        if self.status == ContractStatus::Terminated {
+            return Err("Contract is already terminated".to_string());
         }
+        self.status = ContractStatus::Terminated;
+        self.code.clear();
+        self.state.clear();
+        self.abi = ContractAbi::default();
+        self.pending_upgrade = None;
+        Ok(())
     }
 
-    pub fn subscribe(&mut self, event_name: &str, callback: Box<dyn Fn(&str)>) {
-        self.subscribers.entry(event_name.to_string()).or_default().push(callback);
+    pub fn log_execution(&self, message: &str) {
+        println!("Contract Execution Log: {}", message);
     }
 
-    pub fn emit(&self, event_name: &str, data: &str) {
-        if let Some(callbacks) = self.subscribers.get(event_name) {
-            for callback in callbacks {
-                callback(data);
+    pub fn execute_with_error_handling(&mut self, function_name: &str, params: &[i32]) -> Result<i32, Box<dyn Error>> {
+        match self.execute(function_name, params) {
+            Ok(result) => Ok(result),
+            Err(e) => {
+                self.log_execution(&format!("Error executing {}: {}", function_name, e));
+                Err(e)
             }
         }
     }
-} 
\ No newline at end of file
+
+    // Static analysis of the deployed code; see analyzer::analyze
+    pub fn audit(&self) -> Result<Vec<Finding>, String> {
+        analyzer::analyze(&self.code, &self.abi)
+    }
+}
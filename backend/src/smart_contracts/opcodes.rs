@@ -0,0 +1,158 @@
+use crate::smart_contracts::state::STORAGE_WRITE_GAS;
+
+// Instruction set of the contract virtual machine. Every opcode is one byte, optionally
+// followed by a fixed-size big-endian immediate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Stop,
+    Push,   // i32 immediate
+    Pop,
+    Dup,    // u8 immediate: depth from the top, 0 is the top
+    Swap,   // u8 immediate: swaps the top with the item `depth + 1` below it
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Neg,
+    Eq,
+    Lt,
+    Gt,
+    IsZero,
+    And,
+    Or,
+    Jump,   // u32 immediate: absolute target
+    JumpI,  // u32 immediate: jumps if the popped condition is non-zero
+    Call,   // u32 immediate: internal subroutine call
+    Ret,
+    XCall,  // u8 immediate: index into the contract's imports; calls another contract
+    SLoad,
+    SStore,
+    Log,    // u8 immediate: number of topics; pops the data word, then the topics
+    Feed,   // u8 immediate: index into the contract's feeds; pushes the finalized value
+    MapLoad,  // Pops the map's slot, then the key
+    MapStore, // Pops the map's slot, the key, then the value
+    ALen,     // Pops the array's slot
+    ALoad,    // Pops the array's slot, then the index
+    AStore,   // Pops the array's slot, the index, then the value
+    APush,    // Pops the array's slot, then the value to append
+    Return,
+    Revert,
+}
+
+const OPCODES: [(Opcode, u8, &str); 34] = [
+    (Opcode::Stop, 0x00, "STOP"),
+    (Opcode::Push, 0x01, "PUSH"),
+    (Opcode::Pop, 0x02, "POP"),
+    (Opcode::Dup, 0x03, "DUP"),
+    (Opcode::Swap, 0x04, "SWAP"),
+    (Opcode::Add, 0x10, "ADD"),
+    (Opcode::Sub, 0x11, "SUB"),
+    (Opcode::Mul, 0x12, "MUL"),
+    (Opcode::Div, 0x13, "DIV"),
+    (Opcode::Mod, 0x14, "MOD"),
+    (Opcode::Neg, 0x15, "NEG"),
+    (Opcode::Eq, 0x20, "EQ"),
+    (Opcode::Lt, 0x21, "LT"),
+    (Opcode::Gt, 0x22, "GT"),
+    (Opcode::IsZero, 0x23, "ISZERO"),
+    (Opcode::And, 0x24, "AND"),
+    (Opcode::Or, 0x25, "OR"),
+    (Opcode::Jump, 0x30, "JUMP"),
+    (Opcode::JumpI, 0x31, "JUMPI"),
+    (Opcode::Call, 0x32, "CALL"),
+    (Opcode::Ret, 0x33, "RET"),
+    (Opcode::XCall, 0x34, "XCALL"),
+    (Opcode::SLoad, 0x40, "SLOAD"),
+    (Opcode::SStore, 0x41, "SSTORE"),
+    (Opcode::Log, 0x42, "LOG"),
+    (Opcode::Feed, 0x43, "FEED"),
+    (Opcode::MapLoad, 0x44, "MAPLOAD"),
+    (Opcode::MapStore, 0x45, "MAPSTORE"),
+    (Opcode::ALen, 0x46, "ALEN"),
+    (Opcode::ALoad, 0x47, "ALOAD"),
+    (Opcode::AStore, 0x48, "ASTORE"),
+    (Opcode::APush, 0x49, "APUSH"),
+    (Opcode::Return, 0xf0, "RETURN"),
+    (Opcode::Revert, 0xfd, "REVERT"),
+];
+
+impl Opcode {
+    pub fn from_byte(byte: u8) -> Option<Opcode> {
+        OPCODES.iter().find(|(_, code, _)| *code == byte).map(|(opcode, _, _)| *opcode)
+    }
+
+    pub fn from_mnemonic(mnemonic: &str) -> Option<Opcode> {
+        OPCODES.iter()
+            .find(|(_, _, name)| name.eq_ignore_ascii_case(mnemonic))
+            .map(|(opcode, _, _)| *opcode)
+    }
+
+    pub fn byte(&self) -> u8 {
+        OPCODES.iter().find(|(opcode, _, _)| opcode == self).map(|(_, code, _)| *code).unwrap()
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        OPCODES.iter().find(|(opcode, _, _)| opcode == self).map(|(_, _, name)| *name).unwrap()
+    }
+
+    pub fn immediate_size(&self) -> usize {
+        match self {
+            Opcode::Push | Opcode::Jump | Opcode::JumpI | Opcode::Call => 4,
+            Opcode::Dup | Opcode::Swap | Opcode::XCall | Opcode::Log | Opcode::Feed => 1,
+            _ => 0,
+        }
+    }
+
+    pub fn is_jump(&self) -> bool {
+        matches!(self, Opcode::Jump | Opcode::JumpI | Opcode::Call)
+    }
+
+    pub fn is_storage_write(&self) -> bool {
+        matches!(self, Opcode::SStore | Opcode::MapStore | Opcode::AStore | Opcode::APush)
+    }
+
+    pub fn gas_cost(&self) -> u64 {
+        match self {
+            Opcode::Stop | Opcode::Return | Opcode::Revert => 0,
+            Opcode::Pop => 2,
+            Opcode::Push | Opcode::Dup | Opcode::Swap => 3,
+            Opcode::Add | Opcode::Sub | Opcode::Neg => 3,
+            Opcode::Eq | Opcode::Lt | Opcode::Gt | Opcode::IsZero | Opcode::And | Opcode::Or => 3,
+            Opcode::Mul | Opcode::Div | Opcode::Mod => 5,
+            Opcode::Jump | Opcode::Ret => 8,
+            Opcode::JumpI | Opcode::Call => 10,
+            Opcode::SLoad | Opcode::Feed | Opcode::MapLoad | Opcode::ALen => 50,
+            Opcode::ALoad => 100, // Reads the length to check the index
+            Opcode::SStore | Opcode::MapStore => STORAGE_WRITE_GAS,
+            Opcode::AStore => STORAGE_WRITE_GAS + 50,
+            Opcode::APush => 2 * STORAGE_WRITE_GAS + 50, // Writes the element and the length
+            Opcode::Log => 100,
+            Opcode::XCall => 100, // Plus whatever the callee uses
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instruction {
+    pub offset: usize,
+    pub opcode: Opcode,
+    pub operand: u32, // Raw immediate; PUSH reinterprets it as i32
+}
+
+// Splits bytecode into instructions, rejecting unknown opcodes and truncated immediates
+pub fn decode(code: &[u8]) -> Result<Vec<Instruction>, String> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let opcode = Opcode::from_byte(code[offset])
+            .ok_or_else(|| format!("Invalid opcode 0x{:02x} at offset {}", code[offset], offset))?;
+        let size = opcode.immediate_size();
+        let immediate = code.get(offset + 1..offset + 1 + size)
+            .ok_or_else(|| format!("Truncated {} at offset {}", opcode.mnemonic(), offset))?;
+        let operand = immediate.iter().fold(0u32, |value, byte| (value << 8) | *byte as u32);
+        instructions.push(Instruction { offset, opcode, operand });
+        offset += 1 + size;
+    }
+    Ok(instructions)
+}
@@ -0,0 +1,277 @@
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error as _;
+use sha2::{Sha256, Digest};
+use std::collections::BTreeMap;
+use std::fmt;
+use crate::security;
+
+// Gas for every storage write, plus a charge per byte for byte-array values
+pub const STORAGE_WRITE_GAS: u64 = 200;
+pub const STORAGE_BYTE_GAS: u64 = 4;
+pub const MAX_VALUE_BYTES: usize = 4096;
+
+// 256-bit storage key or value, big-endian two's complement
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Word(pub [u8; 32]);
+
+impl Word {
+    pub const ZERO: Word = Word([0; 32]);
+
+    pub fn from_i64(value: i64) -> Self {
+        let mut bytes = if value < 0 { [0xff; 32] } else { [0; 32] };
+        bytes[24..].copy_from_slice(&value.to_be_bytes());
+        Word(bytes)
+    }
+
+    pub fn from_u128(value: u128) -> Self {
+        let mut bytes = [0; 32];
+        bytes[16..].copy_from_slice(&value.to_be_bytes());
+        Word(bytes)
+    }
+
+    // Right-aligned, as numbers are; at most 32 bytes
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() > 32 {
+            return Err(format!("{} bytes do not fit in a word", bytes.len()));
+        }
+        let mut word = [0; 32];
+        word[32 - bytes.len()..].copy_from_slice(bytes);
+        Ok(Word(word))
+    }
+
+    // None unless the word is the sign extension of an i64
+    pub fn to_i64(&self) -> Option<i64> {
+        let value = i64::from_be_bytes(self.0[24..].try_into().unwrap());
+        if Word::from_i64(value) == *self { Some(value) } else { None }
+    }
+
+    pub fn to_i32(&self) -> Option<i32> {
+        self.to_i64().and_then(|value| i32::try_from(value).ok())
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Word::ZERO
+    }
+
+    pub fn wrapping_add(&self, other: &Word) -> Word {
+        let mut sum = [0; 32];
+        let mut carry = 0u16;
+        for i in (0..32).rev() {
+            let total = self.0[i] as u16 + other.0[i] as u16 + carry;
+            sum[i] = total as u8;
+            carry = total >> 8;
+        }
+        Word(sum)
+    }
+
+    pub fn wrapping_neg(&self) -> Word {
+        Word(self.0.map(|byte| !byte)).wrapping_add(&Word::from(1))
+    }
+
+    pub fn wrapping_sub(&self, other: &Word) -> Word {
+        self.wrapping_add(&other.wrapping_neg())
+    }
+
+    // Schoolbook multiplication over bytes, least significant first; overflow past 32 bytes
+    // is dropped
+    pub fn wrapping_mul(&self, other: &Word) -> Word {
+        let mut columns = [0u32; 32];
+        for i in 0..32 {
+            for j in 0..32 - i {
+                columns[i + j] += self.0[31 - i] as u32 * other.0[31 - j] as u32;
+            }
+        }
+        let mut product = [0; 32];
+        let mut carry = 0u64;
+        for (i, column) in columns.iter().enumerate() {
+            let total = *column as u64 + carry;
+            product[31 - i] = total as u8;
+            carry = total >> 8;
+        }
+        Word(product)
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.0[0] & 0x80 != 0
+    }
+
+    // Two's complement order; the derived Ord compares words as unsigned
+    pub fn signed_cmp(&self, other: &Word) -> std::cmp::Ordering {
+        other.is_negative().cmp(&self.is_negative()).then_with(|| self.cmp(other))
+    }
+
+    // Quotient rounded toward zero and remainder with the sign of the dividend, as for i32;
+    // None when dividing by zero
+    pub fn signed_div_rem(&self, other: &Word) -> Option<(Word, Word)> {
+        if other.is_zero() {
+            return None;
+        }
+        let abs = |word: &Word| if word.is_negative() { word.wrapping_neg() } else { *word };
+        let (dividend, divisor) = (abs(self), abs(other));
+        let mut quotient = Word::ZERO;
+        let mut remainder = Word::ZERO;
+        for bit in 0..256 {
+            remainder = remainder.wrapping_add(&remainder);
+            remainder.0[31] |= (dividend.0[bit / 8] >> (7 - bit % 8)) & 1;
+            if remainder >= divisor {
+                remainder = remainder.wrapping_sub(&divisor);
+                quotient.0[bit / 8] |= 1 << (7 - bit % 8);
+            }
+        }
+        if self.is_negative() != other.is_negative() {
+            quotient = quotient.wrapping_neg();
+        }
+        if self.is_negative() {
+            remainder = remainder.wrapping_neg();
+        }
+        Some((quotient, remainder))
+    }
+
+    pub fn hash(data: &[u8]) -> Word {
+        Word(Sha256::digest(data).into())
+    }
+}
+
+impl From<i32> for Word {
+    fn from(value: i32) -> Self {
+        Word::from_i64(value as i64)
+    }
+}
+
+impl From<i64> for Word {
+    fn from(value: i64) -> Self {
+        Word::from_i64(value)
+    }
+}
+
+impl From<u64> for Word {
+    fn from(value: u64) -> Self {
+        Word::from_u128(value as u128)
+    }
+}
+
+impl fmt::Display for Word {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.to_i64() {
+            Some(value) => write!(f, "{}", value),
+            None => write!(f, "0x{}", security::to_hex(&self.0)),
+        }
+    }
+}
+
+impl fmt::Debug for Word {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Word({})", self)
+    }
+}
+
+// Hex strings, so words can key JSON maps
+impl Serialize for Word {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("0x{}", security::to_hex(&self.0)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Word {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        let bytes = security::from_hex(text.trim_start_matches("0x")).ok_or_else(|| D::Error::custom("Invalid hex word"))?;
+        Word::from_slice(&bytes).map_err(D::Error::custom)
+    }
+}
+
+// Slot a named variable lives in, e.g. the string keys Wasm contracts use
+pub fn named_slot(name: &str) -> Word {
+    Word::hash(name.as_bytes())
+}
+
+// Key of `key`'s entry in the map at `slot`
+pub fn map_key(slot: &Word, key: &[u8]) -> Word {
+    let mut data = key.to_vec();
+    data.extend_from_slice(&slot.0);
+    Word::hash(&data)
+}
+
+// Dynamic arrays keep their length at `slot` and their elements consecutively from the
+// hash of the slot, so arrays never overlap each other or other variables
+pub fn array_element(slot: &Word, index: u64) -> Word {
+    Word::hash(&slot.0).wrapping_add(&Word::from(index))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageValue {
+    Word(Word),
+    Bytes(Vec<u8>),
+}
+
+impl StorageValue {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            StorageValue::Word(word) => word.is_zero(),
+            StorageValue::Bytes(bytes) => bytes.is_empty(),
+        }
+    }
+
+    // Gas to write this value; clearing a slot costs the base write
+    pub fn write_gas(value: Option<&StorageValue>) -> u64 {
+        match value {
+            Some(StorageValue::Bytes(bytes)) => STORAGE_WRITE_GAS + bytes.len() as u64 * STORAGE_BYTE_GAS,
+            _ => STORAGE_WRITE_GAS,
+        }
+    }
+}
+
+impl<T: Into<Word>> From<T> for StorageValue {
+    fn from(value: T) -> Self {
+        StorageValue::Word(value.into())
+    }
+}
+
+// Storage of one contract. Unset slots read as zero, and writing zero or an empty byte array
+// clears a slot, so equal contents always serialize the same way.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ContractStorage {
+    slots: BTreeMap<Word, StorageValue>,
+}
+
+impl ContractStorage {
+    pub fn get(&self, key: &Word) -> Option<&StorageValue> {
+        self.slots.get(key)
+    }
+
+    pub fn word(&self, key: &Word) -> Result<Word, String> {
+        match self.slots.get(key) {
+            None => Ok(Word::ZERO),
+            Some(StorageValue::Word(word)) => Ok(*word),
+            Some(StorageValue::Bytes(_)) => Err(format!("Slot {} holds bytes, not a word", key)),
+        }
+    }
+
+    // Returns the previous value
+    pub fn set(&mut self, key: Word, value: Option<StorageValue>) -> Result<Option<StorageValue>, String> {
+        match value {
+            Some(StorageValue::Bytes(bytes)) if bytes.len() > MAX_VALUE_BYTES => {
+                Err(format!("Values are limited to {} bytes", MAX_VALUE_BYTES))
+            }
+            Some(value) if !value.is_empty() => Ok(self.slots.insert(key, value)),
+            _ => Ok(self.slots.remove(&key)),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.slots.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Word, &StorageValue)> {
+        self.slots.iter()
+    }
+}
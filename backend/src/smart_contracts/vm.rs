@@ -1,35 +1,359 @@
 use std::collections::HashMap;
+use crate::security;
+use crate::smart_contracts::abi::{ContractError, ContractImport, FunctionAbi, Value};
+use crate::smart_contracts::assembler::assemble;
+use crate::smart_contracts::opcodes::{decode, Opcode};
+use crate::smart_contracts::state::{self, ContractStorage, StorageValue, Word};
+
+pub const MAX_STACK_DEPTH: usize = 1024;
+pub const MAX_SUBROUTINE_DEPTH: usize = 256; // Nested CALLs within one contract frame
+pub const MAX_LOG_TOPICS: u32 = 4;
+
+// Contract code is either assembly source or `0x`-prefixed hex bytecode
+pub fn compile(code: &str) -> Result<Vec<u8>, String> {
+    match code.trim().strip_prefix("0x") {
+        Some(hex) => security::from_hex(hex).ok_or_else(|| "Invalid hex bytecode".to_string()),
+        None => assemble(code),
+    }
+}
+
+// Call to another contract made with XCALL
+pub struct ExternalCall {
+    pub address: String,
+    pub function: String,
+    pub args: Vec<Value>,
+    pub value: u64,
+    pub gas_limit: u64,
+}
+
+// What running code can reach outside its stack: its storage through SLOAD and SSTORE, and
+// other contracts through XCALL
+pub trait Host {
+    fn sload(&mut self, key: &Word) -> Result<Word, String>;
+    fn sstore(&mut self, key: Word, value: Word) -> Result<(), String>;
+    // Returns the callee's result together with the gas it used, which the caller pays
+    fn call(&mut self, call: ExternalCall) -> (Result<Option<Value>, ContractError>, u64);
+    fn emit(&mut self, topics: Vec<String>, data: String);
+    fn feed(&self, name: &str) -> Option<i64>;
+}
+
+impl Host for ContractStorage {
+    fn sload(&mut self, key: &Word) -> Result<Word, String> {
+        self.word(key)
+    }
+
+    fn sstore(&mut self, key: Word, value: Word) -> Result<(), String> {
+        self.set(key, Some(StorageValue::Word(value))).map(|_| ())
+    }
+
+    fn call(&mut self, _call: ExternalCall) -> (Result<Option<Value>, ContractError>, u64) {
+        (Err(ContractError::Execution("Contract calls need chain state".to_string())), 0)
+    }
+
+    // Outside of chain state there is no receipt to record events in
+    fn emit(&mut self, _topics: Vec<String>, _data: String) {}
+
+    fn feed(&self, _name: &str) -> Option<i64> {
+        None
+    }
+}
+
+fn pop(stack: &mut Vec<Word>) -> Result<Word, String> {
+    stack.pop().ok_or_else(|| "Stack underflow".to_string())
+}
+
+fn push(stack: &mut Vec<Word>, value: Word) -> Result<(), String> {
+    if stack.len() >= MAX_STACK_DEPTH {
+        return Err("Stack overflow".to_string());
+    }
+    stack.push(value);
+    Ok(())
+}
+
+fn truth(condition: bool) -> Word {
+    Word::from(condition as i32)
+}
+
+// Words used as amounts, lengths and indexes must be non-negative and fit in a u64
+fn to_u64(word: &Word) -> Option<u64> {
+    word.to_i64().and_then(|value| u64::try_from(value).ok())
+}
+
+// Length of the array at `slot`
+fn array_length(host: &mut dyn Host, slot: &Word) -> Result<u64, String> {
+    let length = host.sload(slot)?;
+    to_u64(&length).ok_or_else(|| format!("Invalid array length {}", length))
+}
+
+// Checks `index` is inside the array at `slot`
+fn array_index(host: &mut dyn Host, slot: &Word, index: &Word) -> Result<u64, String> {
+    let length = array_length(host, slot)?;
+    match to_u64(index) {
+        Some(index) if index < length => Ok(index),
+        _ => Err(format!("Array index {} out of bounds for length {}", index, length)),
+    }
+}
+
+fn binary(stack: &mut Vec<Word>, op: impl Fn(Word, Word) -> Result<Word, String>) -> Result<(), String> {
+    let b = pop(stack)?;
+    let a = pop(stack)?;
+    push(stack, op(a, b)?)
+}
 
 pub struct VirtualMachine {
-    pub memory: HashMap<String, i32>,
+    pub memory: ContractStorage, // Storage for code run without a host
     pub gas_limit: u64,
     pub gas_used: u64,
+    pub imports: Vec<ContractImport>, // Targets of XCALL
+    pub feeds: Vec<String>, // Oracle feeds FEED reads
+    pub reverted: Option<Word>, // Code passed to REVERT, when that is how the run ended
 }
 
 impl VirtualMachine {
     pub fn new(gas_limit: u64) -> Self {
         VirtualMachine {
-            memory: HashMap::new(),
+            memory: ContractStorage::default(),
             gas_limit,
             gas_used: 0,
+            imports: Vec::new(),
+            feeds: Vec::new(),
+            reverted: None,
         }
     }
 
     pub fn execute(&mut self, code: &str, params: &[i32]) -> Result<i32, String> {
-        // Example: Parse and execute more complex code
-        if code.contains("loop") {
-            // Implement loop logic
-            self.gas_used += 10; // Example gas cost for loop
-        } else if code.contains("if") {
-            // Implement conditional logic
-            self.gas_used += 5; // Example gas cost for conditionals
-        }
+        let bytecode = compile(code)?;
+        self.run(&bytecode, params)
+    }
 
+    // Running out of gas consumes the whole limit
+    fn charge(&mut self, gas: u64) -> Result<(), String> {
+        self.gas_used += gas;
         if self.gas_used > self.gas_limit {
+            self.gas_used = self.gas_limit;
             return Err("Gas limit exceeded".to_string());
         }
+        Ok(())
+    }
+
+    // Calls an exported function, converting the top of the stack to its declared return type
+    pub fn call(&mut self, code: &[u8], function: &FunctionAbi, args: &[Value], host: &mut dyn Host) -> Result<Option<Value>, String> {
+        let params: Vec<Word> = args.iter().map(Value::to_word).collect();
+        let result = self.run_with_host(code, function.entry, &params, host)?;
+        function.returns
+            .map(|ty| Value::from_word(ty, &result).ok_or_else(|| format!("Result {} does not fit in {}", result, ty.name())))
+            .transpose()
+    }
+
+    pub fn run(&mut self, code: &[u8], params: &[i32]) -> Result<i32, String> {
+        let params: Vec<Word> = params.iter().map(|param| Word::from(*param)).collect();
+        let mut memory = std::mem::take(&mut self.memory);
+        let result = self.run_with_host(code, 0, &params, &mut memory);
+        self.memory = memory;
+        let result = result?;
+        result.to_i32().ok_or_else(|| format!("Result {} does not fit in i32", result))
+    }
+
+    // Runs bytecode from `entry` with the params pushed onto the stack, first param deepest.
+    // Execution ends at RETURN, STOP or the end of the code and yields the top of the stack
+    // (0 if empty). Stack words are 256-bit like storage, and arithmetic wraps at that width.
+    pub fn run_with_host(&mut self, code: &[u8], entry: u32, params: &[Word], host: &mut dyn Host) -> Result<Word, String> {
+        let instructions = decode(code)?;
+        let index_of: HashMap<usize, usize> = instructions.iter()
+            .enumerate()
+            .map(|(index, instruction)| (instruction.offset, index))
+            .collect();
+        let target = |offset: u32| {
+            index_of.get(&(offset as usize)).copied().ok_or_else(|| format!("Invalid jump target {}", offset))
+        };
+
+        let mut stack: Vec<Word> = Vec::new();
+        for param in params {
+            push(&mut stack, *param)?;
+        }
+        let mut return_stack: Vec<usize> = Vec::new();
+        let mut pc = if entry as usize == code.len() { instructions.len() } else { target(entry)? };
 
-        Ok(0) // Placeholder return value
+        while let Some(instruction) = instructions.get(pc) {
+            self.charge(instruction.opcode.gas_cost())?;
+            pc += 1;
+            match instruction.opcode {
+                Opcode::Stop => break,
+                Opcode::Push => push(&mut stack, Word::from(instruction.operand as i32))?,
+                Opcode::Pop => {
+                    pop(&mut stack)?;
+                }
+                Opcode::Dup => {
+                    let value = *stack.iter().rev().nth(instruction.operand as usize).ok_or("Stack underflow")?;
+                    push(&mut stack, value)?;
+                }
+                Opcode::Swap => {
+                    let depth = instruction.operand as usize + 1;
+                    if depth >= stack.len() {
+                        return Err("Stack underflow".to_string());
+                    }
+                    let top = stack.len() - 1;
+                    stack.swap(top, top - depth);
+                }
+                Opcode::Add => binary(&mut stack, |a, b| Ok(a.wrapping_add(&b)))?,
+                Opcode::Sub => binary(&mut stack, |a, b| Ok(a.wrapping_sub(&b)))?,
+                Opcode::Mul => binary(&mut stack, |a, b| Ok(a.wrapping_mul(&b)))?,
+                Opcode::Div => binary(&mut stack, |a, b| {
+                    a.signed_div_rem(&b).map(|(quotient, _)| quotient).ok_or_else(|| "Division by zero".to_string())
+                })?,
+                Opcode::Mod => binary(&mut stack, |a, b| {
+                    a.signed_div_rem(&b).map(|(_, remainder)| remainder).ok_or_else(|| "Division by zero".to_string())
+                })?,
+                Opcode::Neg => {
+                    let value = pop(&mut stack)?;
+                    push(&mut stack, value.wrapping_neg())?;
+                }
+                Opcode::Eq => binary(&mut stack, |a, b| Ok(truth(a == b)))?,
+                Opcode::Lt => binary(&mut stack, |a, b| Ok(truth(a.signed_cmp(&b).is_lt())))?,
+                Opcode::Gt => binary(&mut stack, |a, b| Ok(truth(a.signed_cmp(&b).is_gt())))?,
+                Opcode::IsZero => {
+                    let value = pop(&mut stack)?;
+                    push(&mut stack, truth(value.is_zero()))?;
+                }
+                Opcode::And => binary(&mut stack, |a, b| Ok(truth(!a.is_zero() && !b.is_zero())))?,
+                Opcode::Or => binary(&mut stack, |a, b| Ok(truth(!a.is_zero() || !b.is_zero())))?,
+                Opcode::Jump => pc = target(instruction.operand)?,
+                Opcode::JumpI => {
+                    if !pop(&mut stack)?.is_zero() {
+                        pc = target(instruction.operand)?;
+                    }
+                }
+                Opcode::Call => {
+                    if return_stack.len() >= MAX_SUBROUTINE_DEPTH {
+                        return Err("Subroutine depth exceeded".to_string());
+                    }
+                    return_stack.push(pc);
+                    pc = target(instruction.operand)?;
+                }
+                Opcode::Ret => pc = return_stack.pop().ok_or("RET without CALL")?,
+                // Pops the gas to forward (0 for all that is left), the value to send and the
+                // arguments, then pushes the result (0 if there is none) and 1 on success. If the
+                // callee failed it pushes its revert code (0 for other failures) and 0, so the
+                // caller can act on the reason or pass it on with REVERT
+                Opcode::XCall => {
+                    let import = self.imports.get(instruction.operand as usize)
+                        .cloned()
+                        .ok_or_else(|| format!("Unknown import {}", instruction.operand))?;
+                    let gas = pop(&mut stack)?;
+                    let gas = to_u64(&gas).ok_or_else(|| format!("Invalid gas {}", gas))?;
+                    let value = pop(&mut stack)?;
+                    let value = to_u64(&value).ok_or_else(|| format!("Invalid value {}", value))?;
+                    let mut args = Vec::with_capacity(import.function.params.len());
+                    for ty in import.function.params.iter().rev() {
+                        let arg = pop(&mut stack)?;
+                        args.push(Value::from_word(*ty, &arg).ok_or_else(|| format!("Argument {} does not fit in {}", arg, ty.name()))?);
+                    }
+                    args.reverse();
+
+                    let remaining = self.gas_limit - self.gas_used;
+                    let gas_limit = if gas == 0 { remaining } else { gas.min(remaining) };
+                    let (result, gas_used) = host.call(ExternalCall {
+                        address: import.address,
+                        function: import.function.name,
+                        args,
+                        value,
+                        gas_limit,
+                    });
+                    self.charge(gas_used)?;
+                    match result {
+                        Ok(value) => {
+                            push(&mut stack, value.map_or(Word::ZERO, |value| value.to_word()))?;
+                            push(&mut stack, truth(true))?;
+                        }
+                        Err(error) => {
+                            let code = match error {
+                                ContractError::Reverted(code) => code,
+                                _ => Word::ZERO,
+                            };
+                            push(&mut stack, code)?;
+                            push(&mut stack, Word::ZERO)?;
+                        }
+                    }
+                }
+                // Storage keys are popped first: PUSH value, PUSH key, SSTORE
+                Opcode::SLoad => {
+                    let key = pop(&mut stack)?;
+                    let value = host.sload(&key)?;
+                    push(&mut stack, value)?;
+                }
+                Opcode::SStore => {
+                    let key = pop(&mut stack)?;
+                    let value = pop(&mut stack)?;
+                    host.sstore(key, value)?;
+                }
+                // Maps and arrays live at a slot, with their entries at keys derived from it
+                Opcode::MapLoad => {
+                    let slot = pop(&mut stack)?;
+                    let key = pop(&mut stack)?;
+                    let value = host.sload(&state::map_key(&slot, &key.0))?;
+                    push(&mut stack, value)?;
+                }
+                Opcode::MapStore => {
+                    let slot = pop(&mut stack)?;
+                    let key = pop(&mut stack)?;
+                    let value = pop(&mut stack)?;
+                    host.sstore(state::map_key(&slot, &key.0), value)?;
+                }
+                Opcode::ALen => {
+                    let slot = pop(&mut stack)?;
+                    let length = host.sload(&slot)?;
+                    push(&mut stack, length)?;
+                }
+                Opcode::ALoad => {
+                    let slot = pop(&mut stack)?;
+                    let index = pop(&mut stack)?;
+                    let index = array_index(host, &slot, &index)?;
+                    let value = host.sload(&state::array_element(&slot, index))?;
+                    push(&mut stack, value)?;
+                }
+                Opcode::AStore => {
+                    let slot = pop(&mut stack)?;
+                    let index = pop(&mut stack)?;
+                    let value = pop(&mut stack)?;
+                    let index = array_index(host, &slot, &index)?;
+                    host.sstore(state::array_element(&slot, index), value)?;
+                }
+                Opcode::APush => {
+                    let slot = pop(&mut stack)?;
+                    let value = pop(&mut stack)?;
+                    let length = array_length(host, &slot)?;
+                    let grown = length.checked_add(1).ok_or("Array is full")?;
+                    host.sstore(state::array_element(&slot, length), value)?;
+                    host.sstore(slot, Word::from(grown))?;
+                }
+                // PUSH topic..., PUSH data, LOG n; words are recorded in decimal like storage keys
+                Opcode::Log => {
+                    if instruction.operand > MAX_LOG_TOPICS {
+                        return Err(format!("LOG takes at most {} topics", MAX_LOG_TOPICS));
+                    }
+                    let data = pop(&mut stack)?;
+                    let mut topics = Vec::with_capacity(instruction.operand as usize);
+                    for _ in 0..instruction.operand {
+                        topics.push(pop(&mut stack)?.to_string());
+                    }
+                    topics.reverse();
+                    host.emit(topics, data.to_string());
+                }
+                Opcode::Feed => {
+                    let name = self.feeds.get(instruction.operand as usize)
+                        .ok_or_else(|| format!("Unknown feed {}", instruction.operand))?;
+                    let value = host.feed(name).ok_or_else(|| format!("Feed {} has no finalized value", name))?;
+                    push(&mut stack, Word::from(value))?;
+                }
+                Opcode::Return => return pop(&mut stack),
+                Opcode::Revert => {
+                    let code = stack.pop().unwrap_or(Word::ZERO);
+                    self.reverted = Some(code);
+                    return Err(ContractError::Reverted(code).to_string());
+                }
+            }
+        }
+        Ok(stack.pop().unwrap_or(Word::ZERO))
     }
 
     pub fn execute_with_gas(&mut self, code: &str, params: &[i32], gas_limit: u64) -> Result<i32, String> {
@@ -49,4 +373,4 @@ impl VirtualMachine {
         // Implement optimization techniques to reduce gas usage
         self.execute_with_gas(code, params, 1000) // Example gas limit
     }
-} 
\ No newline at end of file
+}
@@ -0,0 +1,513 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use wasmparser::{Operator, Parser, Payload, TypeRef, Validator, WasmFeatures};
+use wasmtime::{Caller, Config, Engine, Error, Extern, ExternType, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, Val, ValType};
+use crate::core::merkle::hash_str;
+use crate::core::receipt::Log;
+use crate::security;
+use crate::smart_contracts::abi::{ContractAbi, FunctionAbi, Value, ValueType};
+use crate::smart_contracts::analyzer::{Finding, FindingKind, Severity};
+use crate::smart_contracts::state::{self, StorageValue, Word, MAX_VALUE_BYTES};
+use crate::smart_contracts::{CallContext, WorldState};
+
+const WASM_MAGIC_HEX: &str = "0061736d"; // "\0asm"
+const MAX_HOST_STRING: i32 = 1024;
+const MAX_CALL_ARGS: i32 = 16;
+const MAX_MEMORY_PAGES: u64 = 16; // 1 MiB
+const WASM_PAGE_SIZE: u64 = 65536;
+const MAX_CACHED_MODULES: usize = 256;
+
+// Everything the linker provides; a module importing anything else cannot be instantiated
+const HOST_FUNCTIONS: [&str; 16] = [
+    "state_get", "state_set", "storage_read", "storage_write", "storage_read_bytes", "storage_write_bytes",
+    "map_slot", "array_slot", "emit_event", "caller", "address",
+    "balance", "oracle_value", "transfer", "call_contract", "return_value",
+];
+
+// Host functions that write contract storage
+const STORAGE_WRITES: [&str; 3] = ["state_set", "storage_write", "storage_write_bytes"];
+
+// Wasm contracts are stored either as text (`(module ...)`) or as hex-encoded binary
+pub fn is_wasm(code: &str) -> bool {
+    let code = code.trim_start();
+    code.starts_with("(module") || code.starts_with(WASM_MAGIC_HEX)
+}
+
+fn module_bytes(code: &str) -> Result<Vec<u8>, String> {
+    let code = code.trim();
+    if code.starts_with(WASM_MAGIC_HEX) {
+        security::from_hex(code).ok_or_else(|| "Invalid hex-encoded Wasm module".to_string())
+    } else {
+        wat::parse_str(code).map_err(|e| e.to_string())
+    }
+}
+
+// Fuel is consumed one unit per Wasm instruction and maps one-to-one to gas. Threads and SIMD
+// are switched off so wasmtime's own validation rejects modules that use them. Every contract
+// shares the one engine so compiled modules can be reused across calls.
+fn engine() -> Result<&'static Engine, String> {
+    static ENGINE: OnceLock<Result<Engine, String>> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        config.wasm_threads(false);
+        config.wasm_relaxed_simd(false);
+        config.wasm_simd(false);
+        Engine::new(&config).map_err(|e| e.to_string())
+    }).as_ref().map_err(Clone::clone)
+}
+
+// Compiled modules by code hash, so a contract is compiled once rather than on every call.
+// The cache starts over once it is full.
+fn module(code: &str) -> Result<Module, String> {
+    static MODULES: OnceLock<Mutex<HashMap<String, Module>>> = OnceLock::new();
+    let modules = MODULES.get_or_init(Default::default);
+    let hash = hash_str(code);
+    if let Some(module) = modules.lock().unwrap().get(&hash) {
+        return Ok(module.clone());
+    }
+    let module = Module::new(engine()?, module_bytes(code)?).map_err(|e| e.to_string())?;
+    let mut modules = modules.lock().unwrap();
+    if modules.len() >= MAX_CACHED_MODULES {
+        modules.clear();
+    }
+    modules.insert(hash, module.clone());
+    Ok(module)
+}
+
+// Rejects modules that could behave differently on different nodes. Float results (NaN bit
+// patterns in particular) vary between platforms, and threads make execution order racy.
+// This is the wasmparser wasmtime itself validates with, with floats switched off, so float
+// types and instructions are refused wherever they appear.
+pub fn validate(code: &str) -> Result<(), String> {
+    let bytes = module_bytes(code)?;
+    let features = WasmFeatures::default()
+        - WasmFeatures::FLOATS
+        - WasmFeatures::THREADS
+        - WasmFeatures::SIMD
+        - WasmFeatures::RELAXED_SIMD;
+    Validator::new_with_features(features).validate_all(&bytes).map_err(|e| e.to_string())?;
+    module(code)?;
+    Ok(())
+}
+
+fn value_type(ty: &ValType) -> Result<ValueType, String> {
+    match ty {
+        ValType::I32 => Ok(ValueType::I32),
+        ValType::I64 => Ok(ValueType::I64),
+        other => Err(format!("Unsupported export type {:?}", other)),
+    }
+}
+
+// The ABI of a Wasm contract is its exported functions
+pub fn abi(code: &str) -> Result<ContractAbi, String> {
+    let module = module(code)?;
+    let mut functions = Vec::new();
+    for export in module.exports() {
+        let func = match export.ty() {
+            ExternType::Func(func) => func,
+            _ => continue,
+        };
+        let params = func.params().map(|ty| value_type(&ty)).collect::<Result<Vec<_>, _>>()?;
+        let results = func.results().map(|ty| value_type(&ty)).collect::<Result<Vec<_>, _>>()?;
+        if results.len() > 1 {
+            return Err(format!("Function {} returns more than one value", export.name()));
+        }
+        functions.push(FunctionAbi { name: export.name().to_string(), params, returns: results.first().copied(), entry: 0 });
+    }
+    Ok(ContractAbi { functions, ..Default::default() })
+}
+
+// Open block, loop or if while walking a function body
+struct Frame {
+    is_loop: bool,
+    branches_back: bool, // A br jumps back to the start of the loop
+    exits: bool, // Something inside can leave the loop
+}
+
+// Reports loops that can only repeat, state writes after calls to other contracts, and
+// instructions that follow an unconditional branch
+fn analyze_body(index: usize, operators: &[Operator], writes: &[u32], call_contract: Option<u32>, findings: &mut Vec<Finding>) {
+    let mut frames = vec![Frame { is_loop: false, branches_back: false, exits: false }];
+    let mut called_out = false;
+    let mut dead = false;
+    let mut reported_dead = false;
+    let mut reported_write = false;
+    for operator in operators {
+        if dead && !matches!(operator, Operator::End | Operator::Else) && !reported_dead {
+            findings.push(Finding::new(FindingKind::UnreachableCode, Severity::Info, Some(index), format!("Function {} has code after an unconditional branch", index)));
+            reported_dead = true;
+        }
+        match operator {
+            Operator::Block { .. } | Operator::If { .. } | Operator::Loop { .. } => {
+                if matches!(operator, Operator::If { .. }) {
+                    frames.iter_mut().for_each(|frame| frame.exits = true);
+                }
+                frames.push(Frame { is_loop: matches!(operator, Operator::Loop { .. }), branches_back: false, exits: false });
+            }
+            Operator::Else => dead = false,
+            Operator::End => {
+                dead = false;
+                if let Some(frame) = frames.pop() {
+                    if frame.is_loop && frame.branches_back && !frame.exits {
+                        findings.push(Finding::new(FindingKind::UnboundedLoop, Severity::Error, Some(index), format!("Function {} has a loop that never exits", index)));
+                    }
+                }
+            }
+            Operator::Br { relative_depth } => {
+                // Branching to a block leaves every loop nested in it; branching to a loop
+                // restarts it and leaves the loops nested in that
+                let depth = *relative_depth as usize;
+                let target = frames.len().checked_sub(depth + 1);
+                match target {
+                    Some(target) => {
+                        if frames[target].is_loop {
+                            frames[target].branches_back = true;
+                        }
+                        frames[target + 1..].iter_mut().for_each(|frame| frame.exits = true);
+                    }
+                    None => frames.iter_mut().for_each(|frame| frame.exits = true),
+                }
+                dead = true;
+            }
+            Operator::BrIf { .. } => frames.iter_mut().for_each(|frame| frame.exits = true),
+            Operator::BrTable { .. } | Operator::Return | Operator::Unreachable => {
+                frames.iter_mut().for_each(|frame| frame.exits = true);
+                dead = true;
+            }
+            Operator::Call { function_index } => {
+                if Some(*function_index) == call_contract {
+                    called_out = true;
+                }
+                if writes.contains(function_index) && called_out && !reported_write {
+                    findings.push(Finding::new(FindingKind::Reentrancy, Severity::Warning, Some(index), format!("Function {} writes state after calling another contract", index)));
+                    reported_write = true;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// Static checks run before deployment. Locations are function indices.
+pub fn analyze(code: &str) -> Result<Vec<Finding>, String> {
+    let bytes = module_bytes(code)?;
+    let mut findings = Vec::new();
+    let mut imported_functions = 0;
+    let mut function_index = 0;
+    let (mut writes, mut call_contract) = (Vec::new(), None);
+    for payload in Parser::new(0).parse_all(&bytes) {
+        match payload.map_err(|e| e.to_string())? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import.map_err(|e| e.to_string())?;
+                    let field = import.name;
+                    let is_function = matches!(import.ty, TypeRef::Func(_));
+                    if import.module != "env" || !is_function || !HOST_FUNCTIONS.contains(&field) {
+                        findings.push(Finding::new(FindingKind::DisallowedImport, Severity::Error, None, format!("Import {}.{} is not provided to contracts", import.module, field)));
+                    }
+                    if is_function {
+                        if STORAGE_WRITES.contains(&field) {
+                            writes.push(imported_functions);
+                        } else if field == "call_contract" {
+                            call_contract = Some(imported_functions);
+                        }
+                        imported_functions += 1;
+                    }
+                }
+            }
+            Payload::MemorySection(reader) => {
+                for memory in reader {
+                    let memory = memory.map_err(|e| e.to_string())?;
+                    if memory.initial > MAX_MEMORY_PAGES {
+                        findings.push(Finding::new(FindingKind::MemoryLimit, Severity::Error, None, format!("Memory starts at {} pages, above the limit of {}", memory.initial, MAX_MEMORY_PAGES)));
+                    } else if memory.maximum.is_none_or(|maximum| maximum > MAX_MEMORY_PAGES) {
+                        findings.push(Finding::new(FindingKind::MemoryLimit, Severity::Warning, None, format!("Memory can grow past {} pages", MAX_MEMORY_PAGES)));
+                    }
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                let mut reader = body.get_operators_reader().map_err(|e| e.to_string())?;
+                let mut operators = Vec::new();
+                while !reader.eof() {
+                    operators.push(reader.read().map_err(|e| e.to_string())?);
+                }
+                let index = (imported_functions + function_index) as usize;
+                analyze_body(index, &operators, &writes, call_contract, &mut findings);
+                function_index += 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(findings)
+}
+
+struct HostState {
+    context: CallContext,
+    world: WorldState,
+    return_data: Option<Value>, // Result of the last contract this one called
+    limits: StoreLimits, // Caps memory at MAX_MEMORY_PAGES however the contract grows it
+}
+
+// Host functions fail the call by returning an error, which wasmtime raises as a trap
+fn trap(message: impl Into<String>) -> Error {
+    Error::msg(message.into())
+}
+
+fn memory(caller: &mut Caller<'_, HostState>) -> Result<Memory, Error> {
+    caller.get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| trap("Contract does not export its memory"))
+}
+
+fn read_string(caller: &mut Caller<'_, HostState>, ptr: i32, len: i32) -> Result<String, Error> {
+    if ptr < 0 || !(0..=MAX_HOST_STRING).contains(&len) {
+        return Err(trap("Invalid string argument"));
+    }
+    let memory = memory(caller)?;
+    let mut buffer = vec![0u8; len as usize];
+    memory.read(&*caller, ptr as usize, &mut buffer).map_err(|e| trap(e.to_string()))?;
+    String::from_utf8(buffer).map_err(|_| trap("String argument is not valid UTF-8"))
+}
+
+// Reads call arguments, stored as consecutive little-endian i64 words
+fn read_words(caller: &mut Caller<'_, HostState>, ptr: i32, count: i32) -> Result<Vec<i64>, Error> {
+    if ptr < 0 || !(0..=MAX_CALL_ARGS).contains(&count) {
+        return Err(trap("Invalid argument list"));
+    }
+    let memory = memory(caller)?;
+    let mut buffer = vec![0u8; count as usize * 8];
+    memory.read(&*caller, ptr as usize, &mut buffer).map_err(|e| trap(e.to_string()))?;
+    Ok(buffer.chunks(8).map(|word| i64::from_le_bytes(word.try_into().unwrap())).collect())
+}
+
+fn read_bytes(caller: &mut Caller<'_, HostState>, ptr: i32, len: i32) -> Result<Vec<u8>, Error> {
+    if ptr < 0 || len < 0 || len as usize > MAX_VALUE_BYTES {
+        return Err(trap("Invalid byte array argument"));
+    }
+    let memory = memory(caller)?;
+    let mut buffer = vec![0u8; len as usize];
+    memory.read(&*caller, ptr as usize, &mut buffer).map_err(|e| trap(e.to_string()))?;
+    Ok(buffer)
+}
+
+// Storage keys and words are 32 bytes, big-endian
+fn read_word(caller: &mut Caller<'_, HostState>, ptr: i32) -> Result<Word, Error> {
+    let bytes = read_bytes(caller, ptr, 32)?;
+    Ok(Word(bytes.try_into().unwrap()))
+}
+
+fn write_bytes(caller: &mut Caller<'_, HostState>, ptr: i32, bytes: &[u8]) -> Result<(), Error> {
+    if ptr < 0 {
+        return Err(trap("Invalid output pointer"));
+    }
+    let memory = memory(caller)?;
+    memory.write(&mut *caller, ptr as usize, bytes).map_err(|e| trap(e.to_string()))
+}
+
+// Writes a string into contract memory and returns its length
+fn write_string(caller: &mut Caller<'_, HostState>, ptr: i32, value: &str) -> Result<i32, Error> {
+    write_bytes(caller, ptr, value.as_bytes())?;
+    Ok(value.len() as i32)
+}
+
+// Burns fuel for work done by the host on top of what the instructions burn
+fn charge(caller: &mut Caller<'_, HostState>, gas: u64) -> Result<(), Error> {
+    let fuel = caller.get_fuel()?;
+    if gas > fuel {
+        caller.set_fuel(0)?;
+        return Err(trap("Gas limit exceeded"));
+    }
+    caller.set_fuel(fuel - gas)
+}
+
+// Charges a storage write on top of the fuel the instructions burn
+fn store(caller: &mut Caller<'_, HostState>, key: Word, value: Option<StorageValue>) -> Result<(), Error> {
+    charge(caller, StorageValue::write_gas(value.as_ref()))?;
+    let host = caller.data_mut();
+    host.world.storage_set(&host.context.address, key, value).map_err(trap)
+}
+
+// Host functions available to contracts under the "env" module. Strings and byte arrays are
+// passed as (pointer, length) pairs into the contract's exported memory, storage keys and
+// words as pointers to 32 bytes.
+fn linker(engine: &Engine) -> Result<Linker<HostState>, String> {
+    let mut linker = Linker::new(engine);
+    // Named i32 variables, kept at the slot derived from the name
+    linker.func_wrap("env", "state_get", |mut caller: Caller<'_, HostState>, key_ptr: i32, key_len: i32| -> Result<i32, Error> {
+        let key = read_string(&mut caller, key_ptr, key_len)?;
+        let host = caller.data();
+        let word = host.world.storage_word(&host.context.address, &state::named_slot(&key)).map_err(trap)?;
+        word.to_i32().ok_or_else(|| trap(format!("State {} does not fit in an i32", key)))
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap("env", "state_set", |mut caller: Caller<'_, HostState>, key_ptr: i32, key_len: i32, value: i32| -> Result<(), Error> {
+        let key = read_string(&mut caller, key_ptr, key_len)?;
+        store(&mut caller, state::named_slot(&key), Some(value.into()))
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap("env", "storage_read", |mut caller: Caller<'_, HostState>, key_ptr: i32, out_ptr: i32| -> Result<(), Error> {
+        let key = read_word(&mut caller, key_ptr)?;
+        let host = caller.data();
+        let word = host.world.storage_word(&host.context.address, &key).map_err(trap)?;
+        write_bytes(&mut caller, out_ptr, &word.0)
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap("env", "storage_write", |mut caller: Caller<'_, HostState>, key_ptr: i32, value_ptr: i32| -> Result<(), Error> {
+        let key = read_word(&mut caller, key_ptr)?;
+        let value = read_word(&mut caller, value_ptr)?;
+        store(&mut caller, key, Some(StorageValue::Word(value)))
+    }).map_err(|e| e.to_string())?;
+    // Copies up to `out_len` bytes of the value and returns its full length, 0 for an unset slot
+    linker.func_wrap("env", "storage_read_bytes", |mut caller: Caller<'_, HostState>, key_ptr: i32, out_ptr: i32, out_len: i32| -> Result<i32, Error> {
+        let key = read_word(&mut caller, key_ptr)?;
+        let host = caller.data();
+        let bytes = match host.world.storage_value(&host.context.address, &key) {
+            None => Vec::new(),
+            Some(StorageValue::Bytes(bytes)) => bytes,
+            Some(StorageValue::Word(_)) => return Err(trap("Slot holds a word, not bytes")),
+        };
+        let copied = bytes.len().min(out_len.max(0) as usize);
+        write_bytes(&mut caller, out_ptr, &bytes[..copied])?;
+        Ok(bytes.len() as i32)
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap("env", "storage_write_bytes", |mut caller: Caller<'_, HostState>, key_ptr: i32, data_ptr: i32, data_len: i32| -> Result<(), Error> {
+        let key = read_word(&mut caller, key_ptr)?;
+        let data = read_bytes(&mut caller, data_ptr, data_len)?;
+        store(&mut caller, key, Some(StorageValue::Bytes(data)))
+    }).map_err(|e| e.to_string())?;
+    // Slot of an entry in the map at `slot`; keys are arbitrary bytes such as addresses
+    linker.func_wrap("env", "map_slot", |mut caller: Caller<'_, HostState>, slot_ptr: i32, key_ptr: i32, key_len: i32, out_ptr: i32| -> Result<(), Error> {
+        let slot = read_word(&mut caller, slot_ptr)?;
+        let key = read_bytes(&mut caller, key_ptr, key_len)?;
+        write_bytes(&mut caller, out_ptr, &state::map_key(&slot, &key).0)
+    }).map_err(|e| e.to_string())?;
+    // Slot of an element of the dynamic array whose length is kept at `slot`
+    linker.func_wrap("env", "array_slot", |mut caller: Caller<'_, HostState>, slot_ptr: i32, index: i64, out_ptr: i32| -> Result<(), Error> {
+        if index < 0 {
+            return Err(trap("Array index must not be negative"));
+        }
+        let slot = read_word(&mut caller, slot_ptr)?;
+        write_bytes(&mut caller, out_ptr, &state::array_element(&slot, index as u64).0)
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap("env", "emit_event", |mut caller: Caller<'_, HostState>, name_ptr: i32, name_len: i32, data_ptr: i32, data_len: i32| -> Result<(), Error> {
+        let name = read_string(&mut caller, name_ptr, name_len)?;
+        let data = read_string(&mut caller, data_ptr, data_len)?;
+        let host = caller.data_mut();
+        let address = host.context.address.clone();
+        host.world.emit(Log { address, topics: vec![name], data });
+        Ok(())
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap("env", "caller", |mut caller: Caller<'_, HostState>, ptr: i32| -> Result<i32, Error> {
+        let address = caller.data().context.caller.clone();
+        write_string(&mut caller, ptr, &address)
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap("env", "address", |mut caller: Caller<'_, HostState>, ptr: i32| -> Result<i32, Error> {
+        let address = caller.data().context.address.clone();
+        write_string(&mut caller, ptr, &address)
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap("env", "balance", |mut caller: Caller<'_, HostState>, addr_ptr: i32, addr_len: i32| -> Result<i64, Error> {
+        let address = read_string(&mut caller, addr_ptr, addr_len)?;
+        Ok(caller.data().world.balance(&address) as i64)
+    }).map_err(|e| e.to_string())?;
+    // Latest finalized value of an oracle feed; traps if the feed has none yet
+    linker.func_wrap("env", "oracle_value", |mut caller: Caller<'_, HostState>, name_ptr: i32, name_len: i32| -> Result<i64, Error> {
+        let name = read_string(&mut caller, name_ptr, name_len)?;
+        caller.data().world.oracle.value(&name).ok_or_else(|| trap(format!("Feed {} has no finalized value", name)))
+    }).map_err(|e| e.to_string())?;
+    // Sends value from the contract's own balance; returns 0 on success, 1 if it cannot cover it
+    linker.func_wrap("env", "transfer", |mut caller: Caller<'_, HostState>, to_ptr: i32, to_len: i32, amount: i64| -> Result<i32, Error> {
+        let to = read_string(&mut caller, to_ptr, to_len)?;
+        if amount < 0 {
+            return Err(trap("Transfer amount must not be negative"));
+        }
+        let host = caller.data_mut();
+        Ok(match host.world.transfer(&host.context.address, &to, amount as u64) {
+            Ok(()) => 0,
+            Err(_) => 1,
+        })
+    }).map_err(|e| e.to_string())?;
+    // Calls a function of another contract with the arguments converted to its parameter
+    // types, sending `value` and forwarding `gas` (0 for all that is left). Returns 0 on
+    // success and 1 if the callee failed; its result is then read with `return_value`.
+    linker.func_wrap("env", "call_contract", |mut caller: Caller<'_, HostState>, addr_ptr: i32, addr_len: i32, fn_ptr: i32, fn_len: i32, args_ptr: i32, args_count: i32, value: i64, gas: i64| -> Result<i32, Error> {
+        let address = read_string(&mut caller, addr_ptr, addr_len)?;
+        let function = read_string(&mut caller, fn_ptr, fn_len)?;
+        let words = read_words(&mut caller, args_ptr, args_count)?;
+        if value < 0 || gas < 0 {
+            return Err(trap("Value and gas must not be negative"));
+        }
+        let remaining = caller.get_fuel()?;
+        let gas_limit = if gas == 0 { remaining } else { (gas as u64).min(remaining) };
+
+        let host = caller.data_mut();
+        let params = host.world.contracts.get(&address)
+            .and_then(|contract| contract.abi.function(&function))
+            .map(|function| function.params.clone())
+            .filter(|params| params.len() == words.len());
+        // Without a matching signature the words are passed as i64 and the callee rejects them
+        let args: Vec<Value> = match params {
+            Some(params) => params.iter().zip(&words).map(|(ty, word)| match ty {
+                ValueType::I64 => Value::I64(*word),
+                ty => Value::from_i32(*ty, *word as i32),
+            }).collect(),
+            None => words.iter().map(|word| Value::I64(*word)).collect(),
+        };
+        let mut child = host.context.child(address, value as u64, gas_limit);
+        let result = host.world.call(&mut child, &function, &args);
+        host.return_data = result.clone().ok().flatten();
+
+        charge(&mut caller, child.gas_used)?;
+        Ok(if result.is_ok() { 0 } else { 1 })
+    }).map_err(|e| e.to_string())?;
+    linker.func_wrap("env", "return_value", |caller: Caller<'_, HostState>| -> i64 {
+        match caller.data().return_data {
+            Some(Value::I64(value)) => value,
+            Some(value) => value.as_i32().unwrap_or(0) as i64,
+            None => 0,
+        }
+    }).map_err(|e| e.to_string())?;
+    Ok(linker)
+}
+
+fn invoke(store: &mut Store<HostState>, module: &Module, function: &FunctionAbi, args: &[Value]) -> Result<Option<Value>, String> {
+    let instance = linker(store.engine())?.instantiate(&mut *store, module).map_err(|e| e.to_string())?;
+    let export = instance.get_func(&mut *store, &function.name)
+        .ok_or_else(|| format!("Function {} not found", function.name))?;
+    let args: Vec<Val> = args.iter()
+        .map(|arg| match arg {
+            Value::I32(value) => Val::I32(*value),
+            Value::I64(value) => Val::I64(*value),
+            Value::Bool(value) => Val::I32(*value as i32),
+        })
+        .collect();
+    let mut results = vec![Val::I32(0); export.ty(&*store).results().len()];
+    // Wasmtime wraps host errors and traps in a backtrace; the cause is what the caller needs
+    export.call(&mut *store, &args, &mut results).map_err(|e| e.root_cause().to_string())?;
+    match (function.returns, results.first()) {
+        (None, _) => Ok(None),
+        (Some(ValueType::I64), Some(Val::I64(value))) => Ok(Some(Value::I64(*value))),
+        (Some(ty), Some(Val::I32(value))) if ty != ValueType::I64 => Ok(Some(Value::from_i32(ty, *value))),
+        _ => Err(format!("Function {} did not return {}", function.name, function.returns.unwrap().name())),
+    }
+}
+
+// Runs an exported function with the world state moved into the wasmtime store for the
+// duration of the call. Fuel starts at the context's gas limit and whatever was burned is
+// recorded as gas used, even when the call fails; rolling back writes is up to the caller.
+pub fn execute(code: &str, function: &FunctionAbi, args: &[Value], world: &mut WorldState, context: &mut CallContext) -> Result<Option<Value>, String> {
+    let module = module(code)?;
+    let limits = StoreLimitsBuilder::new().memory_size((MAX_MEMORY_PAGES * WASM_PAGE_SIZE) as usize).build();
+    let host = HostState { context: context.clone(), world: std::mem::take(world), return_data: None, limits };
+    let mut store = Store::new(module.engine(), host);
+    store.limiter(|host| &mut host.limits);
+
+    // The world must go back to the caller on every path, so nothing below returns early
+    let result = store.set_fuel(context.gas_limit)
+        .map_err(|e| e.to_string())
+        .and_then(|_| invoke(&mut store, &module, function, args));
+    context.gas_used = context.gas_limit.saturating_sub(store.get_fuel().unwrap_or(0));
+    *world = store.into_data().world;
+
+    if result.is_err() && context.gas_used >= context.gas_limit {
+        return Err("Gas limit exceeded".to_string());
+    }
+    result
+}
@@ -0,0 +1,66 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+fn contract_state_key(contract_id: &str) -> String {
+    format!("contract_state:{}", contract_id)
+}
+
+// Persistent key-value store backed by sled. Values are stored as JSON.
+#[derive(Clone)]
+pub struct Storage {
+    db: sled::Db,
+}
+
+impl Storage {
+    pub fn new(path: &str) -> Self {
+        let db = sled::open(path).expect("Failed to open storage");
+        Storage { db }
+    }
+
+    // Each contract's storage is kept under its own key
+    pub fn store_state<T: Serialize>(&self, contract_id: &str, state: &T) {
+        self.put(&contract_state_key(contract_id), state);
+    }
+
+    pub fn load_state<T: DeserializeOwned + Default>(&self, contract_id: &str) -> T {
+        self.get(&contract_state_key(contract_id)).unwrap_or_default()
+    }
+
+    pub fn put<T: Serialize>(&self, key: &str, value: &T) {
+        let bytes = serde_json::to_vec(value).expect("Failed to serialize value");
+        self.db.insert(key, bytes).expect("Failed to write to storage");
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let bytes = self.db.get(key).expect("Failed to read from storage")?;
+        match serde_json::from_slice(&bytes) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                eprintln!("Failed to deserialize {}: {:?}", key, e);
+                None
+            }
+        }
+    }
+
+    // Values of every key starting with the prefix, in key order
+    pub fn scan_prefix<T: DeserializeOwned>(&self, prefix: &str) -> Vec<T> {
+        self.db.scan_prefix(prefix)
+            .map(|item| item.expect("Failed to read from storage"))
+            .filter_map(|(key, bytes)| match serde_json::from_slice(&bytes) {
+                Ok(value) => Some(value),
+                Err(e) => {
+                    eprintln!("Failed to deserialize {}: {:?}", String::from_utf8_lossy(&key), e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.db.remove(key).expect("Failed to remove from storage");
+    }
+
+    pub fn flush(&self) {
+        self.db.flush().expect("Failed to flush storage");
+    }
+}
@@ -1,224 +1,2031 @@
-#[cfg(test)]
-mod tests {
-    use crate::core::block::Block;
-    use crate::core::blockchain::Blockchain;
-    use crate::core::transaction::{Transaction, TransactionPool};
-    use ring::signature::{Ed25519KeyPair, KeyPair};
-    use ring::rand::SystemRandom;
-    use crate::smart_contracts::SmartContract;
-    use blockchain_project::storage::Storage;
-
-    #[test]
-    fn test_block_creation() {
-        let block = Block::new(0, 0, "Test Data".to_string(), "0".to_string());
-        assert_eq!(block.index, 0);
-        assert_eq!(block.data, "Test Data");
-    }
-
-    #[test]
-    fn test_blockchain_validity() {
-        let mut blockchain = Blockchain::new();
-        blockchain.balances.insert("Alice".to_string(), 100);
+use crate::core::block::Block;
+use crate::core::blockchain::Blockchain;
+use crate::core::transaction::{ContractCall, Transaction, TransactionKind, TransactionPool};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use ring::rand::SystemRandom;
+use crate::smart_contracts::{CallContext, ContractError, SmartContract, Value, WorldState};
+use crate::storage::Storage;
+use crate::core::htlc::{hash_lock, HtlcTerms};
+use crate::core::simulation::Change;
+use crate::core::nft::{collection_id, Royalty};
+use crate::core::token::{token_id, TokenParams};
+use crate::core::transaction::INTRINSIC_GAS;
+use crate::smart_contracts::{ContractStorage, StorageValue};
+use crate::core::fees;
+use crate::smart_contracts::analyzer::{self, FindingKind, Severity};
+use crate::core::oracle::{DataSource, Reporter};
+use crate::core::events::{ChainEvent, ChainEventKind, EventManager, SubscriptionFilter};
+use crate::core::receipt::Log;
+use crate::core::events::{EventFilter, IndexEntry};
+use futures_util::FutureExt;
+use crate::smart_contracts::journal::MAX_CALL_DEPTH;
+use crate::smart_contracts::ReentrancyPolicy;
+use crate::core::blockchain::contract_address;
+use crate::smart_contracts::assembler::{assemble, disassemble};
+use crate::smart_contracts::vm::VirtualMachine;
+use crate::core::pruning::NodeMode;
+use crate::core::light_client::LightClient;
+use crate::security;
+use crate::smart_contracts::state::{self, Word};
 
-        blockchain.add_transaction(Transaction {
-            sender: "Alice".to_string(),
-            receiver: "Bob".to_string(),
-            amount: 50,
-            fee: 1,
-            nonce: 1,
-            required_signatures: 1,
-            signatures: Vec::new(),
-        });
+#[test]
+fn test_block_creation() {
+    let block = Block::new(0, 0, "Test Data".to_string(), "0".to_string());
+    assert_eq!(block.index, 0);
+    assert_eq!(block.data, "Test Data");
+}
 
-        blockchain.add_block(true);
-        assert!(blockchain.is_chain_valid());
-    }
-
-    #[test]
-    fn test_transaction_pool() {
-        let mut pool = TransactionPool::new();
-        let transaction = Transaction {
-            sender: "Alice".to_string(),
-            receiver: "Bob".to_string(),
-            amount: 50,
-            fee: 1,
-            nonce: 1,
-            required_signatures: 1,
-            signatures: Vec::new(),
-        };
-        pool.add_transaction(transaction.clone());
-        assert_eq!(pool.get_transactions().len(), 1);
-        assert_eq!(pool.get_transactions()[0], transaction);
-    }
-
-    #[test]
-    fn test_block_mining() {
-        let mut block = Block::new(1, 0, "Test Data".to_string(), "0".to_string());
-        block.mine_block(2);
-        assert!(block.hash.starts_with("00"));
-    }
-
-    #[test]
-    fn test_invalid_chain() {
-        let mut blockchain = Blockchain::new();
-        blockchain.balances.insert("Alice".to_string(), 100);
+#[test]
+fn test_blockchain_validity() {
+    let mut blockchain = Blockchain::new();
+    blockchain.balances.insert("Alice".to_string(), 100);
+
+    blockchain.add_transaction(Transaction {
+        sender: "Alice".to_string(),
+        receiver: "Bob".to_string(),
+        amount: 50,
+        gas_limit: 1,
+        gas_price: 1,
+        priority_fee: 0,
+        tokens: Vec::new(),
+        valid_after_height: None,
+        valid_until_height: None,
+        nonce: 1,
+        required_signatures: 1,
+        signatures: Vec::new(),
+        kind: TransactionKind::Transfer,
+    });
+
+    blockchain.add_block(true);
+    assert!(blockchain.is_chain_valid());
+}
+
+#[test]
+fn test_transaction_pool() {
+    let mut pool = TransactionPool::new();
+    let transaction = Transaction {
+        sender: "Alice".to_string(),
+        receiver: "Bob".to_string(),
+        amount: 50,
+        gas_limit: 1,
+        gas_price: 1,
+        priority_fee: 0,
+        tokens: Vec::new(),
+        valid_after_height: None,
+        valid_until_height: None,
+        nonce: 1,
+        required_signatures: 1,
+        signatures: Vec::new(),
+        kind: TransactionKind::Transfer,
+    };
+    pool.add_transaction(transaction.clone());
+    assert_eq!(pool.get_transactions().len(), 1);
+    assert_eq!(pool.get_transactions()[0], transaction);
+}
+
+#[test]
+fn test_block_mining() {
+    let mut block = Block::new(1, 0, "Test Data".to_string(), "0".to_string());
+    block.mine_block(2);
+    assert!(block.hash.starts_with("00"));
+}
+
+#[test]
+fn test_invalid_chain() {
+    let mut blockchain = Blockchain::new();
+    blockchain.balances.insert("Alice".to_string(), 100);
+
+    blockchain.add_transaction(Transaction {
+        sender: "Alice".to_string(),
+        receiver: "Bob".to_string(),
+        amount: 50,
+        gas_limit: 1,
+        gas_price: 1,
+        priority_fee: 0,
+        tokens: Vec::new(),
+        valid_after_height: None,
+        valid_until_height: None,
+        nonce: 1,
+        required_signatures: 1,
+        signatures: Vec::new(),
+        kind: TransactionKind::Transfer,
+    });
+
+    blockchain.add_block(true);
+
+    // Tamper with the blockchain
+    blockchain.chain[1].data = "Tampered Data".to_string();
+    assert!(!blockchain.is_chain_valid());
+}
+
+#[test]
+fn test_multiple_transactions() {
+    let mut blockchain = Blockchain::new();
+    
+    // Initialize balances for the senders
+    blockchain.balances.insert("Alice".to_string(), 100);
+    blockchain.balances.insert("Charlie".to_string(), 100);
+
+    let rng = SystemRandom::new();
+    let keypair = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+    let keypair = Ed25519KeyPair::from_pkcs8(keypair.as_ref()).unwrap();
+
+    let mut transaction1 = Transaction {
+        sender: "Alice".to_string(),
+        receiver: "Bob".to_string(),
+        amount: 50,
+        gas_limit: 1,
+        gas_price: 1,
+        priority_fee: 0,
+        tokens: Vec::new(),
+        valid_after_height: None,
+        valid_until_height: None,
+        nonce: 1,
+        required_signatures: 1,
+        signatures: Vec::new(),
+        kind: TransactionKind::Transfer,
+    };
+    transaction1.sign(&keypair);
+
+    let mut transaction2 = Transaction {
+        sender: "Charlie".to_string(),
+        receiver: "Dave".to_string(),
+        amount: 30,
+        gas_limit: 1,
+        gas_price: 1,
+        priority_fee: 0,
+        tokens: Vec::new(),
+        valid_after_height: None,
+        valid_until_height: None,
+        nonce: 1,
+        required_signatures: 1,
+        signatures: Vec::new(),
+        kind: TransactionKind::Transfer,
+    };
+    transaction2.sign(&keypair);
+
+    blockchain.add_transaction(transaction1);
+    blockchain.add_transaction(transaction2);
+    blockchain.add_block(true);
+
+    let transactions: Vec<Transaction> = serde_json::from_str(&blockchain.chain[1].data).unwrap();
+    assert_eq!(transactions.len(), 2);
+}
+
+#[test]
+fn test_transaction_signature() {
+    let rng = SystemRandom::new();
+    let keypair = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+    let keypair = Ed25519KeyPair::from_pkcs8(keypair.as_ref()).unwrap();
+
+    let mut transaction = Transaction::new("Alice".to_string(), "Bob".to_string(), 50, 1, 1, 1);
+    transaction.sign(&keypair);
+
+    assert!(transaction.verify(keypair.public_key().as_ref()));
+}
+
+#[test]
+fn test_balance_check() {
+    let mut blockchain = Blockchain::new();
+    blockchain.balances.insert("Alice".to_string(), 100);
+
+    let transaction = Transaction {
+        sender: "Alice".to_string(),
+        receiver: "Bob".to_string(),
+        amount: 50,
+        gas_limit: 1,
+        gas_price: 1,
+        priority_fee: 0,
+        tokens: Vec::new(),
+        valid_after_height: None,
+        valid_until_height: None,
+        nonce: 1,
+        required_signatures: 1,
+        signatures: Vec::new(),
+        kind: TransactionKind::Transfer,
+    };
+
+    assert!(blockchain.validate_transaction(&transaction));
+}
+
+const ADD_CONTRACT: &str = "
+    .function add(i32, i32, i32) -> i32
+        ADD
+        ADD
+        RETURN
+";
+
+const MULTIPLY_CONTRACT: &str = "
+    .function multiply(i32, i32) -> i32
+        MUL
+        RETURN
+";
+
+#[test]
+fn test_smart_contract_execution() {
+    let mut contract = SmartContract::new(ADD_CONTRACT.to_string());
+    let result = contract.execute("add", &[1, 2, 3]);
+    assert_eq!(result.unwrap(), 6);
+}
+
+#[test]
+fn test_contract_state_persistence() {
+    let storage = Storage::new("test_state");
+    let mut contract = SmartContract::new("add".to_string());
+    contract.state.set(state::named_slot("key"), Some(42.into())).unwrap();
+    contract.save_state(&storage, "test_contract");
+
+    let mut loaded_contract = SmartContract::new("add".to_string());
+    loaded_contract.load_state(&storage, "test_contract");
+    assert_eq!(loaded_contract.state.word(&state::named_slot("key")), Ok(Word::from(42)));
+}
+
+#[test]
+fn test_role_based_access_control() {
+    let mut contract = SmartContract::new(ADD_CONTRACT.to_string());
+    contract.admin = Some("owner".to_string());
+    contract.require_role("owner", "add", Some("adder")).unwrap();
+    contract.grant_role("owner", "adder", "Alice").unwrap();
+
+    // Test with authorized role
+    let result = contract.execute_as("Alice", "add", &[1, 2, 3]);
+    assert_eq!(result.unwrap(), 6);
+
+    // Test with unauthorized role
+    let result = contract.execute_as("Bob", "add", &[1, 2, 3]);
+    assert!(result.is_err());
+
+    // Only admins manage roles, and revoked roles stop working
+    assert!(contract.grant_role("Bob", "adder", "Bob").is_err());
+    contract.revoke_role("owner", "adder", "Alice").unwrap();
+    assert!(contract.execute_as("Alice", "add", &[1, 2, 3]).is_err());
+}
+
+#[test]
+fn test_contract_upgradability() {
+    let mut contract = SmartContract::new(ADD_CONTRACT.to_string());
+    contract.state.set(state::named_slot("key"), Some(42.into())).unwrap();
+    contract.admin = Some("admin".to_string());
+
+    // Only the admin may upgrade
+    assert!(contract.upgrade("mallory", MULTIPLY_CONTRACT.to_string(), 1).is_err());
+
+    // Upgrade contract
+    contract.upgrade("admin", MULTIPLY_CONTRACT.to_string(), 1).unwrap();
+    assert_eq!(contract.version(), 2);
+    assert_eq!(contract.code_at(1).unwrap().0, ADD_CONTRACT);
+
+    // Ensure state is preserved
+    assert_eq!(contract.state.word(&state::named_slot("key")), Ok(Word::from(42)));
+
+    // Test new functionality
+    let result = contract.execute("multiply", &[2, 3]);
+    assert_eq!(result.unwrap(), 6);
+}
+
+#[test]
+fn test_event_emission() {
+    let code = "
+        .function ping(i32)
+            PUSH 7      ; topic
+            DUP 1       ; data: the argument
+            LOG 1
+            STOP
+    ";
+    let mut world = WorldState::default();
+    world.contracts.insert("pinger".to_string(), SmartContract::deploy(code.to_string()).unwrap());
+    let mut context = CallContext::new("Alice".to_string(), "pinger".to_string(), 1000);
+    world.call(&mut context, "ping", &[Value::I32(42)]).unwrap();
+
+    assert_eq!(world.logs, vec![Log { address: "pinger".to_string(), topics: vec!["7".to_string()], data: "42".to_string() }]);
+}
+
+#[test]
+fn test_error_handling() {
+    let mut contract = SmartContract::new(ADD_CONTRACT.to_string());
+
+    // Test with valid function
+    let result = contract.execute_with_error_handling("add", &[1, 2, 3]);
+    assert_eq!(result.unwrap(), 6);
+
+    // Test with invalid function
+    let result = contract.execute_with_error_handling("invalid", &[1, 2, 3]);
+    assert!(result.is_err());
+}
+
+fn authority_keys(count: usize) -> Vec<Ed25519KeyPair> {
+    let mut keys: Vec<Ed25519KeyPair> = (0..count).map(|_| security::generate_keypair()).collect();
+    keys.sort_by_key(security::public_key_hex);
+    keys
+}
+
+fn signed_vote(voter: &Ed25519KeyPair, candidate: &Ed25519KeyPair, authorize: bool) -> Transaction {
+    let mut transaction = Transaction::new(security::public_key_hex(voter), String::new(), 0, 1, 0, 1);
+    transaction.nonce = 1;
+    transaction.kind = TransactionKind::Vote { candidate: security::public_key_hex(candidate), authorize };
+    transaction.sign(voter);
+    transaction
+}
+
+fn signed(mut transaction: Transaction, key: &Ed25519KeyPair) -> Transaction {
+    transaction.sign(key);
+    transaction
+}
+
+#[test]
+fn test_poa_signers_take_turns() {
+    let keys = authority_keys(3);
+    let mut blockchain = Blockchain::with_authorities(keys.iter().map(security::public_key_hex).collect());
+
+    assert!(blockchain.add_block_with_poa(&keys[1]).is_ok());
+    // The same signer cannot seal two blocks in a row
+    assert!(blockchain.add_block_with_poa(&keys[1]).is_err());
+    assert!(blockchain.add_block_with_poa(&keys[2]).is_ok());
+
+    // Keys outside the signer set are rejected
+    let outsider = security::generate_keypair();
+    assert!(blockchain.add_block_with_poa(&outsider).is_err());
+    assert!(blockchain.is_chain_valid());
+
+    blockchain.chain[2].seal = blockchain.chain[1].seal.clone();
+    assert!(!blockchain.is_chain_valid());
+}
+
+#[test]
+fn test_poa_vote_in_signer() {
+    let keys = authority_keys(3);
+    let candidate = security::generate_keypair();
+    let mut blockchain = Blockchain::with_authorities(keys.iter().map(security::public_key_hex).collect());
+
+    // Unsigned votes and votes from non-signers never reach the pool
+    let mut forged = signed_vote(&keys[0], &candidate, true);
+    forged.signatures.clear();
+    blockchain.add_transaction(forged);
+    blockchain.add_transaction(signed_vote(&candidate, &candidate, true));
+    assert!(blockchain.transaction_pool.transactions.is_empty());
+
+    // A vote only counts in a block its voter seals; until then it waits in the pool
+    blockchain.add_transaction(signed_vote(&keys[0], &candidate, true));
+    blockchain.add_block_with_poa(&keys[1]).unwrap();
+    assert!(blockchain.clique.as_ref().unwrap().votes.is_empty());
+    assert_eq!(blockchain.transaction_pool.transactions.len(), 1);
 
-        blockchain.add_transaction(Transaction {
-            sender: "Alice".to_string(),
-            receiver: "Bob".to_string(),
-            amount: 50,
-            fee: 1,
-            nonce: 1,
-            required_signatures: 1,
-            signatures: Vec::new(),
-        });
+    blockchain.add_block_with_poa(&keys[0]).unwrap();
+    assert!(!blockchain.clique.as_ref().unwrap().is_signer(&security::public_key_hex(&candidate)));
 
+    blockchain.add_transaction(signed_vote(&keys[1], &candidate, true));
+    blockchain.add_block_with_poa(&keys[1]).unwrap();
+    assert!(blockchain.clique.as_ref().unwrap().is_signer(&security::public_key_hex(&candidate)));
+
+    // The new signer can seal and the whole chain still verifies from genesis
+    blockchain.add_block_with_poa(&candidate).unwrap();
+    assert!(blockchain.is_chain_valid());
+
+    // A sealer cannot slip another signer's vote into its own block
+    let mut follower = Blockchain::with_authorities(keys.iter().map(security::public_key_hex).collect());
+    let data = serde_json::to_string(&[signed_vote(&keys[0], &candidate, true)]).unwrap();
+    let mut block = Block::new(1, 0, data, follower.chain[0].hash.clone());
+    follower.clique.as_ref().unwrap().seal(&mut block, &keys[2]);
+    assert!(follower.import_block(block).unwrap_err().contains("Vote from"));
+}
+
+#[test]
+fn test_light_client_header_sync_and_proofs() {
+    let mut blockchain = Blockchain::new();
+    blockchain.balances.insert("Alice".to_string(), 100);
+    let mut client = LightClient::new(blockchain.chain[0].header(), blockchain.difficulty);
+
+    let payment = Transaction {
+        sender: "Alice".to_string(),
+        receiver: "Bob".to_string(),
+        amount: 50,
+        gas_limit: 1,
+        gas_price: 1,
+        priority_fee: 0,
+        tokens: Vec::new(),
+        valid_after_height: None,
+        valid_until_height: None,
+        nonce: 1,
+        required_signatures: 0,
+        signatures: Vec::new(),
+        kind: TransactionKind::Transfer,
+    };
+    blockchain.add_transaction(payment.clone());
+    blockchain.add_block(true);
+    let validator = security::generate_keypair();
+    blockchain.add_block_with_pos(&validator).unwrap();
+
+    client.validators = Some([security::public_key_hex(&validator)].into_iter().collect());
+    assert_eq!(client.sync_headers(blockchain.headers(0)).unwrap(), 2);
+    assert_eq!(client.tip().hash, blockchain.chain[2].hash);
+
+    let transaction_proof = blockchain.transaction_proof(&payment.id()).unwrap();
+    assert_eq!(transaction_proof.block_index, 1);
+    assert!(client.verify_transaction(&transaction_proof));
+
+    let account_proof = blockchain.account_proof("Bob").unwrap();
+    assert_eq!(account_proof.balance, 50);
+    assert!(client.verify_account(&account_proof));
+
+    let mut forged = account_proof.clone();
+    forged.balance = 500;
+    assert!(!client.verify_account(&forged));
+}
+
+#[test]
+fn test_light_client_rejects_invalid_headers() {
+    let mut blockchain = Blockchain::new();
+    blockchain.add_block(true);
+    blockchain.add_block(true);
+
+    let mut client = LightClient::new(blockchain.chain[0].header(), blockchain.difficulty);
+    let mut headers = blockchain.headers(1);
+    headers[1].state_root = "tampered".to_string();
+    assert!(client.sync_headers(headers).is_err());
+    assert_eq!(client.tip().index, 1);
+
+    // Naming a producer does not excuse a block from proof of work unless it is trusted
+    let mut unmined = blockchain.chain[2].clone();
+    unmined.signer = "Mallory".to_string();
+    unmined.hash = unmined.calculate_hash();
+    while unmined.hash.starts_with(&"0".repeat(blockchain.difficulty)) {
+        unmined.nonce += 1;
+        unmined.hash = unmined.calculate_hash();
+    }
+    assert!(client.sync_headers(vec![unmined.header()]).is_err());
+    let mut node = Blockchain::new();
+    node.import_block(blockchain.chain[1].clone()).unwrap();
+    assert!(node.import_block(unmined).unwrap_err().contains("proof-of-work"));
+
+    // A proof-of-stake block from a validator the client does not trust is rejected
+    let trusted = security::generate_keypair();
+    let untrusted = security::generate_keypair();
+    blockchain.add_block_with_pos(&untrusted).unwrap();
+    client.validators = Some([security::public_key_hex(&trusted)].into_iter().collect());
+    assert!(client.sync_headers(blockchain.headers(2)).is_err());
+
+    // Naming a trusted validator without its seal is rejected too
+    let mut forged = blockchain.chain[3].clone();
+    forged.signer = security::public_key_hex(&trusted);
+    forged.hash = forged.calculate_hash();
+    forged.seal = security::to_hex(security::sign_data(&untrusted, forged.hash.as_bytes()).as_ref());
+    assert!(client.sync_headers(vec![forged.header()]).unwrap_err().contains("seal"));
+    node.import_block(blockchain.chain[2].clone()).unwrap();
+    node.validators = client.validators.clone();
+    assert!(node.import_block(forged).unwrap_err().contains("seal"));
+}
+
+// Storage directory of its own for one test, removed when dropped even if the test fails
+struct TestDir(String);
+
+impl TestDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        TestDir(path.to_string_lossy().into_owned())
+    }
+
+    fn storage(&self) -> Storage {
+        Storage::new(&self.0)
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn test_checkpoint_bootstrap() {
+    let dir = TestDir::new("checkpoint_state");
+    let storage = dir.storage();
+    let keypair = security::generate_keypair();
+
+    let mut source = Blockchain::new();
+    source.checkpoint_interval = 2;
+    source.balances.insert("Alice".to_string(), 100);
+    source.add_block(true);
+    assert!(source.create_checkpoint(&keypair).is_err()); // Block 1 is not a checkpoint height
+
+    let mut contract = SmartContract::new("add".to_string());
+    contract.state.set(state::named_slot("counter"), Some(7.into())).unwrap();
+    source.contracts.insert("counter_contract".to_string(), contract);
+    source.add_transaction(Transaction {
+        sender: "Alice".to_string(),
+        receiver: "Bob".to_string(),
+        amount: 40,
+        gas_limit: 1,
+        gas_price: 1,
+        priority_fee: 0,
+        tokens: Vec::new(),
+        valid_after_height: None,
+        valid_until_height: None,
+        nonce: 1,
+        required_signatures: 0,
+        signatures: Vec::new(),
+        kind: TransactionKind::Transfer,
+    });
+    source.add_block(true);
+    let signer = security::public_key_hex(&keypair);
+
+    // Checkpoints signed by keys other than the trusted one are refused
+    let impostor = security::generate_keypair();
+    let hash = source.export_checkpoint(&storage, &impostor).unwrap();
+    let rejected = Blockchain::from_checkpoint(&storage, &hash, &signer).err().unwrap();
+    assert!(rejected.contains("signature"));
+
+    let hash = source.export_checkpoint(&storage, &keypair).unwrap();
+
+    // Untrusted hashes are refused
+    assert!(Blockchain::from_checkpoint(&storage, "unknown", &signer).is_err());
+
+    let mut node = Blockchain::from_checkpoint(&storage, &hash, &signer).unwrap();
+    assert_eq!(node.next_index(), 3);
+    assert_eq!(node.balances.get("Bob"), Some(&40));
+    assert_eq!(node.contracts["counter_contract"].state.word(&state::named_slot("counter")), Ok(Word::from(7)));
+
+    // The bootstrapped node verifies new blocks forward from the checkpoint
+    source.add_block(true);
+    let mut forged = source.chain[3].clone();
+    forged.state_root = "forged".to_string();
+    assert!(node.import_block(forged).is_err());
+    assert!(node.import_block(source.chain[3].clone()).is_ok());
+    assert!(node.is_chain_valid());
+}
+
+fn transfer(sender: &str, receiver: &str, amount: u64, nonce: u64) -> Transaction {
+    Transaction {
+        sender: sender.to_string(),
+        receiver: receiver.to_string(),
+        amount,
+        gas_limit: 1,
+        gas_price: 1,
+        priority_fee: 0,
+        tokens: Vec::new(),
+        valid_after_height: None,
+        valid_until_height: None,
+        nonce,
+        required_signatures: 0,
+        signatures: Vec::new(),
+        kind: TransactionKind::Transfer,
+    }
+}
+
+#[test]
+fn test_full_node_pruning() {
+    let dir = TestDir::new("pruning_state");
+    let mut blockchain = Blockchain::open(dir.storage(), NodeMode::Full { retain: 2 });
+    blockchain.finality_window = 2;
+    blockchain.balances.insert("Alice".to_string(), 100);
+    blockchain.add_transaction(transfer("Alice", "Bob", 10, 1));
+    for _ in 0..5 {
         blockchain.add_block(true);
+    }
 
-        // Tamper with the blockchain
-        blockchain.chain[1].data = "Tampered Data".to_string();
-        assert!(!blockchain.is_chain_valid());
+    // Old bodies and states are gone, headers are kept and the chain still verifies
+    assert_eq!(blockchain.chain.len(), 6);
+    assert!(!blockchain.block(1).unwrap().has_body());
+    assert!(blockchain.block(5).unwrap().has_body());
+    assert!(blockchain.state_at(2).is_none());
+    assert!(blockchain.state_at(4).is_some());
+    assert!(blockchain.is_chain_valid());
+
+    // Reopening resumes from the persisted tip and state
+    drop(blockchain);
+    let blockchain = Blockchain::open(dir.storage(), NodeMode::Full { retain: 2 });
+    assert_eq!(blockchain.next_index(), 6);
+    assert_eq!(blockchain.balances.get("Bob"), Some(&10));
+}
+
+#[test]
+fn test_in_memory_state_history_is_bounded() {
+    let mut blockchain = Blockchain::new();
+    blockchain.memory_history = 3;
+    blockchain.finality_window = 2;
+    for _ in 0..6 {
+        blockchain.add_block(true);
     }
 
-    #[test]
-    fn test_multiple_transactions() {
-        let mut blockchain = Blockchain::new();
-        
-        // Initialize balances for the senders
+    // Bodies are all kept, but only the latest states
+    assert_eq!(blockchain.state_history.len(), 3);
+    assert!(blockchain.state_at(3).is_none());
+    assert!(blockchain.state_at(4).is_some());
+    assert!(blockchain.block(1).unwrap().has_body());
+
+    // The finality window wins over a smaller limit
+    blockchain.memory_history = 0;
+    blockchain.add_block(true);
+    assert_eq!(blockchain.state_history.keys().copied().collect::<Vec<_>>(), vec![6, 7]);
+}
+
+#[test]
+fn test_archive_node_reads_pruned_bodies_from_storage() {
+    let dir = TestDir::new("archive_state");
+    let mut blockchain = Blockchain::open(dir.storage(), NodeMode::Archive);
+    blockchain.finality_window = 1;
+    blockchain.balances.insert("Alice".to_string(), 100);
+    blockchain.add_transaction(transfer("Alice", "Bob", 10, 1));
+    blockchain.add_block(true);
+    blockchain.add_block(true);
+    blockchain.add_block(true);
+
+    assert!(!blockchain.chain[1].has_body());
+    assert_eq!(blockchain.block(1).unwrap().transactions().len(), 1);
+    assert_eq!(blockchain.state_at(1).unwrap().balances.get("Bob"), Some(&10));
+}
+
+#[test]
+fn test_reorg_within_finality_window() {
+    let mut node = Blockchain::new();
+    let mut peer = Blockchain::new();
+    for blockchain in [&mut node, &mut peer] {
         blockchain.balances.insert("Alice".to_string(), 100);
-        blockchain.balances.insert("Charlie".to_string(), 100);
-
-        let rng = SystemRandom::new();
-        let keypair = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
-        let keypair = Ed25519KeyPair::from_pkcs8(keypair.as_ref()).unwrap();
-
-        let mut transaction1 = Transaction {
-            sender: "Alice".to_string(),
-            receiver: "Bob".to_string(),
-            amount: 50,
-            fee: 1,
-            nonce: 1,
-            required_signatures: 1,
-            signatures: Vec::new(),
-        };
-        transaction1.sign(&keypair);
-
-        let mut transaction2 = Transaction {
-            sender: "Charlie".to_string(),
-            receiver: "Dave".to_string(),
-            amount: 30,
-            fee: 1,
-            nonce: 1,
-            required_signatures: 1,
-            signatures: Vec::new(),
-        };
-        transaction2.sign(&keypair);
-
-        blockchain.add_transaction(transaction1);
-        blockchain.add_transaction(transaction2);
         blockchain.add_block(true);
+    }
+
+    node.add_transaction(transfer("Alice", "Bob", 10, 1));
+    node.add_block(true);
+    peer.add_transaction(transfer("Alice", "Carol", 20, 1));
+    peer.add_block(true);
+    peer.add_block(true);
+
+    // A fork branching below the finality window is ignored
+    node.finality_window = 0;
+    node.resolve_fork(peer.chain.clone());
+    assert_eq!(node.chain.last().unwrap().hash, node.chain[2].hash);
+
+    node.finality_window = 1;
+    node.resolve_fork(peer.chain.clone());
+    assert_eq!(node.chain.last().unwrap().hash, peer.chain[3].hash);
+    assert_eq!(node.balances.get("Bob"), None);
+    assert_eq!(node.balances.get("Carol"), Some(&20));
+    assert!(node.receipt(&transfer("Alice", "Bob", 10, 1).id()).is_none());
+    assert!(node.is_chain_valid());
+}
+
+#[test]
+fn test_transaction_receipts() {
+    let mut blockchain = Blockchain::new();
+    blockchain.balances.insert("Alice".to_string(), 100);
+    let payment = transfer("Alice", "Bob", 10, 1);
+    blockchain.add_transaction(payment.clone());
+    assert!(blockchain.receipt(&payment.id()).is_none());
+    blockchain.add_block(true);
+
+    let receipt = blockchain.receipt(&payment.id()).unwrap();
+    assert!(receipt.is_success());
+    assert_eq!(receipt.fee, 1);
+    assert_eq!(receipt.block_index, 1);
+    assert_eq!(receipt.block_hash, blockchain.chain[1].hash);
+    assert_eq!(blockchain.block_receipts(1), vec![receipt]);
+}
+
+const SUM_TO_N: &str = "
+    PUSH 0          ; accumulator, n is below it
+loop:
+    DUP 1
+    ISZERO
+    JUMPI done
+    DUP 1
+    ADD
+    SWAP 0
+    PUSH 1
+    SUB
+    SWAP 0
+    JUMP loop
+done:
+    RETURN
+";
+
+#[test]
+fn test_vm_bytecode_execution() {
+    let mut vm = VirtualMachine::new(10_000);
+    assert_eq!(vm.execute(SUM_TO_N, &[10]).unwrap(), 55);
+
+    // Subroutine calls share contract storage
+    let counter = "
+        CALL bump
+        CALL bump
+        PUSH 0
+        SLOAD
+        RETURN
+    bump:
+        PUSH 0
+        SLOAD
+        PUSH 1
+        ADD
+        PUSH 0
+        SSTORE
+        RET
+    ";
+    let mut vm = VirtualMachine::new(10_000);
+    assert_eq!(vm.execute(counter, &[]).unwrap(), 2);
+    assert_eq!(vm.memory.word(&Word::from(0)), Ok(Word::from(2)));
+
+    // Bytecode can be supplied as hex and round-trips through the disassembler
+    let bytecode = assemble(SUM_TO_N).unwrap();
+    assert_eq!(assemble(&disassemble(&bytecode).unwrap()).unwrap(), bytecode);
+    let mut vm = VirtualMachine::new(10_000);
+    assert_eq!(vm.execute(&format!("0x{}", security::to_hex(&bytecode)), &[4]).unwrap(), 10);
+}
+
+#[test]
+fn test_vm_gas_and_errors() {
+    let mut vm = VirtualMachine::new(11);
+    assert_eq!(vm.execute("PUSH 2\nPUSH 3\nMUL", &[]).unwrap(), 6);
+    assert_eq!(vm.gas_used, 11);
+    assert!(vm.execute_with_gas("PUSH 2\nPUSH 3\nMUL", &[], 10).is_err());
 
-        let transactions: Vec<Transaction> = serde_json::from_str(&blockchain.chain[1].data).unwrap();
-        assert_eq!(transactions.len(), 2);
+    // Gas bounds an otherwise infinite loop
+    assert_eq!(vm.execute_with_gas("top: JUMP top", &[], 1000), Err("Gas limit exceeded".to_string()));
+
+    assert!(vm.execute_with_gas("JUMP 3", &[], 1000).is_err()); // Lands inside the JUMP immediate
+    assert!(vm.execute_with_gas("PUSH 0\nDIV", &[1], 1000).is_err());
+    assert!(vm.execute_with_gas("ADD", &[1], 1000).is_err());
+    assert_eq!(vm.execute_with_gas("PUSH 7\nREVERT", &[], 1000), Err("Execution reverted with code 7".to_string()));
+    assert!(vm.execute_with_gas("FOO", &[], 1000).is_err());
+}
+
+const WASM_COUNTER: &str = r#"
+    (module
+      (import "env" "state_get" (func $state_get (param i32 i32) (result i32)))
+      (import "env" "state_set" (func $state_set (param i32 i32 i32)))
+      (import "env" "emit_event" (func $emit_event (param i32 i32 i32 i32)))
+      (import "env" "transfer" (func $transfer (param i32 i32 i64) (result i32)))
+      (memory (export "memory") 1)
+      (data (i32.const 0) "count")
+      (data (i32.const 16) "Incremented")
+      (data (i32.const 32) "Bob")
+      (func (export "increment") (param $by i32) (result i32)
+        (local $value i32)
+        (local.set $value (i32.add (call $state_get (i32.const 0) (i32.const 5)) (local.get $by)))
+        (call $state_set (i32.const 0) (i32.const 5) (local.get $value))
+        (call $emit_event (i32.const 16) (i32.const 11) (i32.const 0) (i32.const 5))
+        (local.get $value))
+      (func (export "pay") (param $amount i32) (result i32)
+        (call $transfer (i32.const 32) (i32.const 3) (i64.extend_i32_u (local.get $amount))))
+      (func (export "spin")
+        (loop $forever (br $forever))))
+"#;
+
+#[test]
+fn test_wasm_contract_execution() {
+    let mut world = WorldState::default();
+    world.contracts.insert("counter".to_string(), SmartContract::deploy(WASM_COUNTER.to_string()).unwrap());
+    world.balances.insert("counter".to_string(), 50);
+    let mut context = CallContext::new("Alice".to_string(), "counter".to_string(), 10_000);
+
+    assert_eq!(world.call(&mut context, "increment", &[Value::I32(5)]), Ok(Some(Value::I32(5))));
+    assert_eq!(world.call(&mut context, "increment", &[Value::I32(2)]), Ok(Some(Value::I32(7))));
+    assert_eq!(world.storage_word("counter", &state::named_slot("count")), Ok(Word::from(7)));
+    assert_eq!(world.logs.len(), 2);
+    assert_eq!(world.logs[0].topics, vec!["Incremented".to_string()]);
+    assert!(context.gas_used > 0);
+
+    assert_eq!(world.call(&mut context, "pay", &[Value::I32(20)]), Ok(Some(Value::I32(0))));
+    assert_eq!(world.balance("Bob"), 20);
+    assert_eq!(world.balance("counter"), 30);
+    assert_eq!(world.call(&mut context, "pay", &[Value::I32(100)]), Ok(Some(Value::I32(1))));
+
+    // Fuel runs out instead of looping forever
+    let result = world.call(&mut context, "spin", &[]);
+    assert_eq!(result, Err(ContractError::Execution("Gas limit exceeded".to_string())));
+    assert_eq!(context.gas_used, 10_000);
+    assert!(world.call(&mut context, "missing", &[]).is_err());
+}
+
+#[test]
+fn test_wasm_memory_growth_is_capped() {
+    // No declared maximum, so only the runtime limit of 16 pages stops it growing
+    let code = r#"(module (memory (export "memory") 1)
+        (func (export "grow") (param i32) (result i32) (memory.grow (local.get 0))))"#;
+    let mut world = WorldState::default();
+    world.contracts.insert("grower".to_string(), SmartContract::deploy(code.to_string()).unwrap());
+    let mut context = CallContext::new("Alice".to_string(), "grower".to_string(), 10_000);
+
+    assert_eq!(world.call(&mut context, "grow", &[Value::I32(15)]), Ok(Some(Value::I32(1))));
+    assert_eq!(world.call(&mut context, "grow", &[Value::I32(16)]), Ok(Some(Value::I32(-1))));
+    assert_eq!(world.call(&mut context, "grow", &[Value::I32(1000)]), Ok(Some(Value::I32(-1))));
+}
+
+#[test]
+fn test_wasm_deploy_rejects_nondeterminism() {
+    let floats = r#"(module (func (export "half") (param i32) (result i32)
+        (i32.trunc_f32_s (f32.div (f32.convert_i32_s (local.get 0)) (f32.const 2)))))"#;
+    assert!(SmartContract::deploy(floats.to_string()).unwrap_err().to_string().contains("floating-point"));
+    let float_local = r#"(module (func (local f64)))"#;
+    assert!(SmartContract::deploy(float_local.to_string()).is_err());
+
+    let threads = r#"(module (memory 1 1 shared))"#;
+    assert!(SmartContract::deploy(threads.to_string()).is_err());
+
+    assert!(SmartContract::deploy("PUSH 1\nJUMP nowhere".to_string()).is_err());
+    assert!(SmartContract::deploy("PUSH 1\nPUSH 2\nADD".to_string()).is_ok());
+}
+
+#[test]
+fn test_contract_abi_dispatch() {
+    let code = "
+        .function max(i32, i32) -> i32
+            DUP 1
+            DUP 1
+            LT
+            JUMPI done
+            SWAP 0
+        done:
+            RETURN
+        .function is_even(i32) -> bool
+            PUSH 2
+            MOD
+            ISZERO
+            RETURN
+        .function store(i32)
+            PUSH 1
+            SSTORE
+    ";
+    let mut contract = SmartContract::deploy(code.to_string()).unwrap();
+    assert_eq!(contract.abi.functions.len(), 3);
+    assert_eq!(contract.abi.functions[1].signature(), "is_even(i32) -> bool");
+
+    let mut context = CallContext::new("Alice".to_string(), "math".to_string(), 1000);
+    assert_eq!(contract.call("max", &[Value::I32(3), Value::I32(9)], &mut context), Ok(Some(Value::I32(9))));
+    assert_eq!(contract.call("max", &[Value::I32(9), Value::I32(3)], &mut context), Ok(Some(Value::I32(9))));
+    assert_eq!(contract.call("is_even", &[Value::I32(4)], &mut context), Ok(Some(Value::Bool(true))));
+    assert_eq!(contract.call("store", &[Value::I32(42)], &mut context), Ok(None));
+    assert_eq!(contract.state.word(&Word::from(1)), Ok(Word::from(42)));
+
+    // Callers get structured errors
+    assert_eq!(
+        contract.call("min", &[], &mut context),
+        Err(ContractError::UnknownFunction("min".to_string()))
+    );
+    assert!(matches!(
+        contract.call("is_even", &[Value::Bool(true)], &mut context),
+        Err(ContractError::InvalidArguments { .. })
+    ));
+    let error = contract.execute("min", &[1]).unwrap_err();
+    assert_eq!(error.downcast_ref::<ContractError>(), Some(&ContractError::UnknownFunction("min".to_string())));
+}
+
+#[test]
+fn test_wasm_contract_abi() {
+    let code = r#"
+        (module
+          (func (export "widen") (param i32) (result i64)
+            (i64.mul (i64.extend_i32_s (local.get 0)) (i64.const 4294967296))))
+    "#;
+    let mut contract = SmartContract::deploy(code.to_string()).unwrap();
+    assert_eq!(contract.abi.functions[0].signature(), "widen(i32) -> i64");
+
+    let mut context = CallContext::new("Alice".to_string(), "widener".to_string(), 1000);
+    assert_eq!(contract.call("widen", &[Value::I32(3)], &mut context), Ok(Some(Value::I64(3 << 32))));
+    assert!(contract.execute("widen", &[3]).is_err()); // Does not fit the word-sized API
+}
+
+const COUNTER_CONTRACT: &str = "
+    .function increment(i32) -> i32
+        PUSH 0
+        SLOAD
+        ADD
+        DUP 0
+        PUSH 0
+        SSTORE
+        RETURN
+    .function fail()
+        PUSH 1
+        REVERT
+";
+
+#[test]
+fn test_contract_deploy_and_call_transactions() {
+    let mut node = Blockchain::new();
+    let mut peer = Blockchain::new();
+    for blockchain in [&mut node, &mut peer] {
+        blockchain.balances.insert("Alice".to_string(), 10_000);
     }
 
-    #[test]
-    fn test_transaction_signature() {
-        let rng = SystemRandom::new();
-        let keypair = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
-        let keypair = Ed25519KeyPair::from_pkcs8(keypair.as_ref()).unwrap();
+    let (deploy, address) = node.deploy_contract("Alice", COUNTER_CONTRACT.to_string(), 10, 1).unwrap();
+    assert_eq!(address, contract_address("Alice", 1));
+    node.add_transaction(deploy.clone());
+    node.add_block(true);
+    assert_eq!(node.receipt(&deploy.id()).unwrap().return_value, Some(address.clone()));
+    assert_eq!(node.contracts[&address].admin, Some("Alice".to_string()));
+
+    let nonce = node.next_nonce("Alice");
+    let call = Transaction::call("Alice".to_string(), address.clone(), ContractCall::new("increment", vec![Value::I32(5)]), 20, 1000, 1, nonce);
+    let failing = Transaction::call("Alice".to_string(), address.clone(), ContractCall::new("fail", vec![]), 0, 1000, 1, nonce + 1);
+    node.add_transaction(call.clone());
+    node.add_transaction(failing.clone());
+    node.add_block(true);
+
+    let receipt = node.receipt(&call.id()).unwrap();
+    assert!(receipt.is_success());
+    assert_eq!(receipt.return_value, Some("5".to_string()));
+    assert!(receipt.gas_used > 0);
+    assert_eq!(node.contracts[&address].state.word(&Word::from(0)), Ok(Word::from(5)));
+    assert_eq!(node.balances.get(&address), Some(&20));
 
-        let mut transaction = Transaction::new("Alice".to_string(), "Bob".to_string(), 50, 1, 1);
-        transaction.sign(&keypair);
+    // A failed call changes nothing but still pays for the gas it used
+    let failed = node.receipt(&failing.id()).unwrap();
+    assert!(!failed.is_success());
+    assert!(failed.fee > 0);
+    let fees: u64 = [&deploy, &call, &failing].iter().map(|transaction| node.receipt(&transaction.id()).unwrap().fee).sum();
+    assert_eq!(node.balances.get("Alice"), Some(&(10_000 - 20 - fees)));
 
-        assert!(transaction.verify(keypair.public_key().as_ref()));
+    // Used nonces cannot be replayed
+    node.add_transaction(call);
+    assert!(node.transaction_pool.transactions.is_empty());
+
+    // Peers re-execute the contract transactions and reach the same state
+    for block in node.chain[1..].iter().cloned() {
+        peer.import_block(block).unwrap();
     }
+    assert_eq!(peer.contracts[&address].state, node.contracts[&address].state);
+    assert_eq!(peer.state_root(), node.state_root());
+}
 
-    #[test]
-    fn test_balance_check() {
-        let mut blockchain = Blockchain::new();
-        blockchain.balances.insert("Alice".to_string(), 100);
+#[test]
+fn test_failed_calls_roll_back_state() {
+    let code = "
+        .function set_then_fail(i32)
+            DUP 0
+            PUSH 1
+            SSTORE
+            PUSH 2
+            SSTORE
+            PUSH 1
+            REVERT
+        .function set_then_spin(i32)
+            PUSH 1
+            SSTORE
+        loop:
+            JUMP loop
+    ";
+    let mut world = WorldState::default();
+    world.contracts.insert("store".to_string(), SmartContract::deploy(code.to_string()).unwrap());
+    world.balances.insert("Alice".to_string(), 100);
+    let mut context = CallContext::new("Alice".to_string(), "store".to_string(), 1000);
+
+    // Every write of a failing call is undone, but the gas it burned is still reported
+    assert!(world.call(&mut context, "set_then_fail", &[Value::I32(7)]).is_err());
+    assert!(context.gas_used > 0);
+    assert!(world.call(&mut context, "set_then_spin", &[Value::I32(7)]).is_err());
+    assert_eq!(context.gas_used, 1000);
+    assert!(world.contracts["store"].state.is_empty());
+
+    // Nested frames: rolling back the outer frame also undoes an inner frame that succeeded
+    let outer = world.mark();
+    world.transfer("Alice", "Bob", 10).unwrap();
+    let inner = world.mark();
+    world.storage_set("store", Word::from(1), Some(5.into())).unwrap();
+    world.transfer("Bob", "Carol", 4).unwrap();
+    world.revert_to(inner);
+    assert_eq!(world.balance("Carol"), 0);
+    assert_eq!(world.balance("Bob"), 10);
+    world.storage_set("store", Word::from(1), Some(6.into())).unwrap();
+    world.revert_to(outer);
+    assert_eq!(world.storage_word("store", &Word::from(1)), Ok(Word::from(0)));
+    assert_eq!(world.balance("Alice"), 100);
+    assert!(!world.balances.contains_key("Bob"));
+}
+
+#[test]
+fn test_cross_contract_calls() {
+    let mut node = Blockchain::new();
+    node.balances.insert("Alice".to_string(), 100);
+    node.contracts.insert("counter".to_string(), SmartContract::deploy(COUNTER_CONTRACT.to_string()).unwrap());
+    let proxy = "
+        .import counter increment(i32) -> i32
+        .import counter fail()
+        .function bump(i32) -> i32
+            PUSH 5      ; value forwarded to the counter
+            PUSH 0      ; all remaining gas
+            XCALL 0
+            POP         ; success flag
+            RETURN
+        .function try_fail() -> i32
+            PUSH 1
+            PUSH 9
+            SSTORE
+            PUSH 0
+            PUSH 0
+            XCALL 1
+            SWAP 0
+            POP
+            RETURN
+        .function fail_reason() -> i32
+            PUSH 0
+            PUSH 0
+            XCALL 1
+            POP         ; failure flag, leaving the callee's revert code
+            RETURN
+    ";
+    node.contracts.insert("proxy".to_string(), SmartContract::deploy(proxy.to_string()).unwrap());
+
+    // Return data comes back to the caller and value moves along each frame
+    let result = node.call_contract("Alice", "proxy", "bump", &[Value::I32(3)], 10, 10_000);
+    assert_eq!(result, Ok(Some(Value::I32(3))));
+    assert_eq!(node.balances["Alice"], 90);
+    assert_eq!(node.balances["proxy"], 5);
+    assert_eq!(node.balances["counter"], 5);
+    assert_eq!(node.contracts["counter"].state.word(&Word::from(0)), Ok(Word::from(3)));
 
-        let transaction = Transaction {
-            sender: "Alice".to_string(),
-            receiver: "Bob".to_string(),
-            amount: 50,
-            fee: 1,
-            nonce: 1,
-            required_signatures: 1,
-            signatures: Vec::new(),
-        };
+    // A failing callee is reported to the caller, which carries on and keeps its own writes
+    assert_eq!(node.call_contract("Alice", "proxy", "try_fail", &[], 0, 10_000), Ok(Some(Value::I32(0))));
+    assert_eq!(node.contracts["proxy"].state.word(&Word::from(9)), Ok(Word::from(1)));
+    assert_eq!(node.call_contract("Alice", "proxy", "fail_reason", &[], 0, 10_000), Ok(Some(Value::I32(1))));
 
-        assert!(blockchain.validate_transaction(&transaction));
+    // Value the caller does not have fails the whole call
+    assert!(node.call_contract("Alice", "proxy", "bump", &[Value::I32(1)], 1000, 10_000).is_err());
+    assert_eq!(node.balances["Alice"], 90);
+}
+
+#[test]
+fn test_reentrancy_policy_and_call_depth() {
+    let code = "
+        .import looper again() -> i32
+        .function again() -> i32
+            PUSH 0
+            PUSH 0
+            XCALL 0
+            SWAP 0
+            POP
+            RETURN
+    ";
+    let mut world = WorldState::default();
+    world.contracts.insert("looper".to_string(), SmartContract::deploy(code.to_string()).unwrap());
+
+    // Forbidden by default: the nested call into the same contract fails
+    let mut context = CallContext::new("Alice".to_string(), "looper".to_string(), 100_000);
+    assert_eq!(world.call(&mut context, "again", &[]), Ok(Some(Value::I32(0))));
+
+    // Allowed, it recurses until the call depth limit stops the innermost frame
+    world.contracts.get_mut("looper").unwrap().reentrancy = ReentrancyPolicy::Allow;
+    let mut context = CallContext::new("Alice".to_string(), "looper".to_string(), 100_000);
+    assert_eq!(world.call(&mut context, "again", &[]), Ok(Some(Value::I32(1))));
+    assert!(context.gas_used > 100 * (MAX_CALL_DEPTH as u64 - 1));
+}
+
+#[test]
+fn test_timelocked_upgrade_with_migration() {
+    let (alice_key, bob_key) = (security::generate_keypair(), security::generate_keypair());
+    let (alice, bob) = (security::public_key_hex(&alice_key), security::public_key_hex(&bob_key));
+    let mut node = Blockchain::new();
+    node.balances.insert(alice.clone(), 10_000);
+    let mut deploy = Transaction::deploy(alice.clone(), COUNTER_CONTRACT.to_string(), 0, 10, 1, 1);
+    deploy.kind = TransactionKind::Deploy {
+        code: COUNTER_CONTRACT.to_string(),
+        reentrancy: ReentrancyPolicy::Forbid,
+        upgrade_delay: Some(2),
+    };
+    let address = contract_address(&alice, 1);
+    node.add_transaction(deploy);
+    node.add_transaction(Transaction::call(alice.clone(), address.clone(), ContractCall::new("increment", vec![Value::I32(5)]), 0, 1000, 1, 2));
+    node.add_block(true);
+
+    let doubled = "
+        .function get() -> i32
+            PUSH 0
+            SLOAD
+            RETURN
+        .function migrate()
+            PUSH 0
+            SLOAD
+            PUSH 2
+            MUL
+            PUSH 0
+            SSTORE
+            STOP
+    ";
+    let apply = |nonce| signed(Transaction { nonce, kind: TransactionKind::ApplyUpgrade, ..Transaction::new(alice.clone(), address.clone(), 0, 1000, 1, 0) }, &alice_key);
+    let upgrade = signed(Transaction::upgrade(alice.clone(), address.clone(), doubled.to_string(), Some("migrate".to_string()), 10, 1, 3), &alice_key);
+    node.add_transaction(upgrade.clone());
+    node.add_block(true);
+    assert_eq!(node.receipt(&upgrade.id()).unwrap().return_value, Some("4".to_string()));
+
+    // Too early: the timelock has not expired yet
+    let early = apply(4);
+    node.add_transaction(early.clone());
+    node.add_block(true);
+    assert!(!node.receipt(&early.id()).unwrap().is_success());
+    assert_eq!(node.contracts[&address].version(), 1);
+
+    // Applied once due; the migration runs against the new code and the old code is kept
+    let due = apply(5);
+    node.add_transaction(due.clone());
+    node.add_block(true);
+    assert_eq!(node.receipt(&due.id()).unwrap().return_value, Some("2".to_string()));
+    assert_eq!(node.contracts[&address].state.word(&Word::from(0)), Ok(Word::from(10)));
+    assert_eq!(node.contract_code(&address, 1).unwrap().0, COUNTER_CONTRACT);
+    assert_eq!(node.contract_code(&address, 2).unwrap().0, doubled);
+
+    // Only the admin can schedule upgrades
+    node.balances.insert(bob.clone(), 100);
+    let hostile = signed(Transaction::upgrade(bob.clone(), address.clone(), COUNTER_CONTRACT.to_string(), None, 10, 1, 1), &bob_key);
+    node.add_transaction(hostile.clone());
+    node.add_block(true);
+    assert!(!node.receipt(&hostile.id()).unwrap().is_success());
+    assert!(node.contracts[&address].pending_upgrade.is_none());
+}
+
+#[test]
+fn test_failed_migration_keeps_old_code() {
+    let alice_key = security::generate_keypair();
+    let alice = security::public_key_hex(&alice_key);
+    let mut node = Blockchain::new();
+    node.balances.insert(alice.clone(), 10_000);
+    let mut contract = SmartContract::deploy(COUNTER_CONTRACT.to_string()).unwrap();
+    contract.admin = Some(alice.clone());
+    contract.state.set(Word::from(0), Some(7.into())).unwrap();
+    node.contracts.insert("counter".to_string(), contract);
+
+    // The migration writes and then reverts, so the upgrade is undone along with it
+    let broken = "
+        .function migrate()
+            PUSH 0
+            PUSH 0
+            SSTORE
+            PUSH 1
+            REVERT
+    ";
+    let upgrade = signed(Transaction::upgrade(alice, "counter".to_string(), broken.to_string(), Some("migrate".to_string()), 1000, 1, 1), &alice_key);
+    node.add_transaction(upgrade.clone());
+    node.add_block(true);
+    let receipt = node.receipt(&upgrade.id()).unwrap();
+    assert!(!receipt.is_success());
+    assert_eq!(node.contracts["counter"].version(), 1);
+    assert_eq!(node.contracts["counter"].code, COUNTER_CONTRACT);
+    assert_eq!(node.contracts["counter"].state.word(&Word::from(0)), Ok(Word::from(7)));
+}
+
+#[test]
+fn test_contract_lifecycle() {
+    let (alice_key, bob_key) = (security::generate_keypair(), security::generate_keypair());
+    let (alice, bob) = (security::public_key_hex(&alice_key), security::public_key_hex(&bob_key));
+    let mut node = Blockchain::new();
+    node.balances.insert(alice.clone(), 10_000);
+    node.balances.insert(bob.clone(), 10_000);
+    node.balances.insert("counter".to_string(), 50);
+    let mut contract = SmartContract::deploy(COUNTER_CONTRACT.to_string()).unwrap();
+    contract.admin = Some(alice.clone());
+    contract.upgrade(&alice, COUNTER_CONTRACT.to_string(), 0).unwrap();
+    node.contracts.insert("counter".to_string(), contract);
+    let lifecycle = |key: &Ed25519KeyPair, kind: TransactionKind, nonce| {
+        signed(Transaction { nonce, kind, ..Transaction::new(security::public_key_hex(key), "counter".to_string(), 0, 10, 1, 0) }, key)
+    };
+    let increment = |nonce| Transaction::call(bob.clone(), "counter".to_string(), ContractCall::new("increment", vec![Value::I32(1)]), 0, 1000, 1, nonce);
+
+    // Paused contracts reject calls, and only the admin can change the status
+    let pause = lifecycle(&alice_key, TransactionKind::Pause, 1);
+    let blocked = increment(1);
+    let hostile = lifecycle(&bob_key, TransactionKind::Resume, 2);
+    for transaction in [&pause, &blocked, &hostile] {
+        node.add_transaction(transaction.clone());
+    }
+    node.add_block(true);
+    let log = &node.receipt(&pause.id()).unwrap().logs[0];
+    assert_eq!(log.topics, vec!["ContractPaused".to_string(), alice.clone()]);
+    assert!(!node.receipt(&blocked.id()).unwrap().is_success());
+    assert!(!node.receipt(&hostile.id()).unwrap().is_success());
+
+    let resume = lifecycle(&alice_key, TransactionKind::Resume, 2);
+    let allowed = increment(3);
+    node.add_transaction(resume);
+    node.add_transaction(allowed.clone());
+    node.add_block(true);
+    assert!(node.receipt(&allowed.id()).unwrap().is_success());
+    assert_eq!(node.contracts["counter"].state.word(&Word::from(0)), Ok(Word::from(1)));
+
+    // Terminating reclaims storage and pays the balance out to the beneficiary
+    let terminate = lifecycle(&alice_key, TransactionKind::Terminate { beneficiary: "Carol".to_string() }, 3);
+    let after = increment(4);
+    node.add_transaction(terminate.clone());
+    node.add_transaction(after.clone());
+    node.add_block(true);
+    assert_eq!(node.receipt(&terminate.id()).unwrap().logs[0].data, "Carol");
+    assert!(!node.receipt(&after.id()).unwrap().is_success());
+    assert_eq!(node.balances.get("Carol"), Some(&50));
+    assert!(!node.balances.contains_key("counter"));
+    assert!(node.contracts["counter"].state.is_empty());
+    assert!(node.contracts.get_mut("counter").unwrap().resume(&alice).is_err());
+    // Code that ran before the last upgrade is still on record
+    assert_eq!(node.contract_code("counter", 1).unwrap().0, COUNTER_CONTRACT);
+}
+
+#[test]
+fn test_role_grants_through_transactions() {
+    let (alice_key, bob_key) = (security::generate_keypair(), security::generate_keypair());
+    let (alice, bob) = (security::public_key_hex(&alice_key), security::public_key_hex(&bob_key));
+    let mut node = Blockchain::new();
+    for account in [&alice, &bob] {
+        node.balances.insert(account.clone(), 10_000);
     }
+    let mut contract = SmartContract::deploy(COUNTER_CONTRACT.to_string()).unwrap();
+    contract.admin = Some(alice.clone());
+    node.contracts.insert("counter".to_string(), contract);
+    let admin = |sender: &str, kind: TransactionKind, nonce| Transaction { nonce, kind, ..Transaction::new(sender.to_string(), "counter".to_string(), 0, 10, 1, 0) };
+    let increment = |nonce| signed(Transaction::call(bob.clone(), "counter".to_string(), ContractCall::new("increment", vec![Value::I32(1)]), 0, 1000, 1, nonce), &bob_key);
+    let grant_bob = TransactionKind::GrantRole { role: "minter".to_string(), account: bob.clone() };
+
+    // Role management must be signed by the sender it names, so the admin cannot be forged
+    let unsigned = admin(&alice, grant_bob.clone(), 1);
+    let forged = signed(admin(&alice, grant_bob.clone(), 1), &bob_key);
+    node.add_transaction(unsigned);
+    node.add_transaction(forged);
+    assert!(node.transaction_pool.transactions.is_empty());
 
-    #[test]
-    fn test_smart_contract_execution() {
-        let mut contract = SmartContract::new("add".to_string());
-        let result = contract.execute("add", &[1, 2, 3]);
-        assert_eq!(result.unwrap(), 6);
+    // The role is checked against the signed sender of the call
+    let require = signed(admin(&alice, TransactionKind::RequireRole { function: "increment".to_string(), role: Some("minter".to_string()) }, 1), &alice_key);
+    let denied = increment(1);
+    let self_grant = signed(admin(&bob, grant_bob.clone(), 2), &bob_key);
+    let grant = signed(admin(&alice, grant_bob, 2), &alice_key);
+    let allowed = increment(3);
+    for transaction in [&require, &denied, &self_grant, &grant, &allowed] {
+        node.add_transaction(transaction.clone());
     }
+    node.add_block(true);
+    assert!(!node.receipt(&denied.id()).unwrap().is_success());
+    assert!(!node.receipt(&self_grant.id()).unwrap().is_success());
+    assert_eq!(node.receipt(&grant.id()).unwrap().logs[0].data, format!("minter {}", bob));
+    assert!(node.receipt(&allowed.id()).unwrap().is_success());
 
-    #[test]
-    fn test_contract_state_persistence() {
-        let storage = Storage::new("test_state");
-        let mut contract = SmartContract::new("add".to_string());
-        contract.state.insert("key".to_string(), 42);
-        contract.save_state(&storage, "test_contract");
+    let revoke = signed(admin(&alice, TransactionKind::RevokeRole { role: "minter".to_string(), account: bob.clone() }, 3), &alice_key);
+    let revoked = increment(4);
+    node.add_transaction(revoke);
+    node.add_transaction(revoked.clone());
+    node.add_block(true);
+    assert!(!node.receipt(&revoked.id()).unwrap().is_success());
+    assert_eq!(node.contracts["counter"].state.word(&Word::from(0)), Ok(Word::from(1)));
 
-        let mut loaded_contract = SmartContract::new("add".to_string());
-        loaded_contract.load_state(&storage, "test_contract");
-        assert_eq!(loaded_contract.state.get("key"), Some(&42));
+    // Naming a role holder as the sender is not enough: restricted calls must be signed by it
+    let grant = signed(admin(&alice, TransactionKind::GrantRole { role: "minter".to_string(), account: bob.clone() }, 4), &alice_key);
+    node.add_transaction(grant);
+    node.add_block(true);
+    let mut unsigned = increment(5);
+    unsigned.signatures.clear();
+    let forged = signed(Transaction::call(bob.clone(), "counter".to_string(), ContractCall::new("increment", vec![Value::I32(1)]), 0, 1000, 1, 5), &alice_key);
+    node.add_transaction(unsigned.clone());
+    node.add_transaction(forged);
+    assert!(node.transaction_pool.transactions.is_empty());
+
+    let mut follower = Blockchain::new();
+    follower.balances = node.balances.clone();
+    follower.contracts.insert("counter".to_string(), node.contracts["counter"].clone());
+    let mut block = Block::new(1, 0, serde_json::to_string(&[unsigned]).unwrap(), follower.chain[0].hash.clone());
+    block.gas_limit = follower.block_gas_limit;
+    block.mine_block(follower.difficulty);
+    assert!(follower.import_block(block).unwrap_err().contains("signature"));
+}
+
+#[test]
+fn test_event_log_queries() {
+    let code = "
+        .function ping(i32, i32)
+            LOG 1       ; the first argument is the topic, the second the data
+            STOP
+    ";
+    let dir = TestDir::new("test_event_log");
+    let mut node = Blockchain::open(dir.storage(), NodeMode::Archive);
+    node.balances.insert("Alice".to_string(), 100_000);
+    for address in ["first", "second"] {
+        node.contracts.insert(address.to_string(), SmartContract::deploy(code.to_string()).unwrap());
+    }
+    let mut nonce = 0;
+    let mut ping = |contract: &str, topic, data| {
+        nonce += 1;
+        Transaction::call("Alice".to_string(), contract.to_string(), ContractCall::new("ping", vec![Value::I32(topic), Value::I32(data)]), 0, 1000, 1, nonce)
+    };
+    for transactions in [vec![ping("first", 1, 10), ping("second", 2, 20)], vec![], vec![ping("first", 2, 30)]] {
+        for transaction in transactions {
+            node.add_transaction(transaction);
+        }
+        node.add_block(true);
     }
 
-    #[test]
-    fn test_role_based_access_control() {
-        let mut contract = SmartContract::new("add".to_string());
-        contract.add_role("admin".to_string());
+    let data = |filter: EventFilter| node.events(&filter).into_iter().map(|record| record.log.data).collect::<Vec<_>>();
+    assert_eq!(data(EventFilter::default()), vec!["10", "20", "30"]);
+    assert_eq!(data(EventFilter { address: Some("first".to_string()), ..Default::default() }), vec!["10", "30"]);
+    assert_eq!(data(EventFilter { topic: Some("2".to_string()), ..Default::default() }), vec!["20", "30"]);
+    assert_eq!(data(EventFilter { from_block: 2, to_block: Some(3), ..Default::default() }), vec!["30"]);
+    assert_eq!(data(EventFilter { address: Some("second".to_string()), from_block: 2, ..Default::default() }), Vec::<String>::new());
 
-        // Test with authorized role
-        let result = contract.execute_with_role("admin", "add", &[1, 2, 3]);
-        assert_eq!(result.unwrap(), 6);
+    // The index is kept in storage, so a reopened node answers the same queries
+    let events = node.events(&EventFilter::default());
+    assert_eq!(events[2].block_index, 3);
+    let storage = node.storage.take().unwrap();
+    drop(node);
+    let mut reopened = Blockchain::open(storage, NodeMode::Archive);
+    assert_eq!(reopened.events(&EventFilter::default()), events);
 
-        // Test with unauthorized role
-        let result = contract.execute_with_role("user", "add", &[1, 2, 3]);
-        assert!(result.is_err());
+    // Each contract and topic is stored per block, and a reorganisation deletes the entries
+    // of the blocks it replaces
+    let stored = |node: &Blockchain| node.storage.as_ref().unwrap().scan_prefix::<IndexEntry>("events:");
+    assert_eq!(stored(&reopened).len(), 6);
+    let mut peer = Blockchain::new();
+    peer.balances.insert("Alice".to_string(), 100_000);
+    for address in ["first", "second"] {
+        peer.contracts.insert(address.to_string(), SmartContract::deploy(code.to_string()).unwrap());
     }
+    for block in &reopened.chain[1..3] {
+        peer.import_block(block.clone()).unwrap();
+    }
+    peer.add_block(true);
+    peer.add_block(true);
+    reopened.resolve_fork(peer.chain.clone());
+    assert_eq!(reopened.chain.last().unwrap().hash, peer.chain.last().unwrap().hash);
+    assert!(stored(&reopened).iter().all(|entry| entry.block_index() == 1));
+    assert_eq!(reopened.events(&EventFilter::default()), events[..2]);
+}
 
-    #[test]
-    fn test_contract_upgradability() {
-        let mut contract = SmartContract::new("add".to_string());
-        contract.state.insert("key".to_string(), 42);
+#[tokio::test]
+async fn test_event_subscriptions() {
+    let code = "
+        .function ping(i32, i32)
+            LOG 1
+            STOP
+    ";
+    let mut node = Blockchain::new();
+    node.balances.insert("Alice".to_string(), 10_000);
+    node.contracts.insert("pinger".to_string(), SmartContract::deploy(code.to_string()).unwrap());
+    let mut everything = node.event_manager.subscribe(SubscriptionFilter::default());
+    let mut pings = node.event_manager.subscribe(SubscriptionFilter {
+        kinds: vec![ChainEventKind::Contract],
+        contract_events: EventFilter { topic: Some("5".to_string()), ..Default::default() },
+    });
 
-        // Upgrade contract
-        contract.upgrade("multiply".to_string());
+    // Subscribers can live on other tasks
+    let listener = tokio::spawn(async move { pings.next().await });
+    node.add_transaction(Transaction::call("Alice".to_string(), "pinger".to_string(), ContractCall::new("ping", vec![Value::I32(4), Value::I32(0)]), 0, 1000, 1, 1));
+    node.add_transaction(Transaction::call("Alice".to_string(), "pinger".to_string(), ContractCall::new("ping", vec![Value::I32(5), Value::I32(1)]), 0, 1000, 1, 2));
+    node.add_block(true);
 
-        // Ensure state is preserved
-        assert_eq!(contract.state.get("key"), Some(&42));
+    match listener.await.unwrap() {
+        Some(ChainEvent::Contract(record)) => assert_eq!(record.log.data, "1"),
+        other => panic!("Unexpected event {:?}", other),
+    }
+    let kinds: Vec<ChainEventKind> = std::iter::from_fn(|| everything.next().now_or_never().flatten()).map(|event| event.kind()).collect();
+    assert_eq!(kinds, vec![
+        ChainEventKind::Transaction,
+        ChainEventKind::Transaction,
+        ChainEventKind::Block,
+        ChainEventKind::Contract,
+        ChainEventKind::Contract,
+    ]);
 
-        // Test new functionality
-        let result = contract.execute("multiply", &[2, 3]);
-        assert_eq!(result.unwrap(), 6);
+    // A subscriber that falls behind loses the oldest events and is told how many
+    let manager = EventManager::new(2);
+    let mut slow = manager.subscribe(SubscriptionFilter::default());
+    for index in 0..5 {
+        manager.publish(ChainEvent::NewBlock { index, hash: String::new(), transactions: 0 });
     }
+    assert_eq!(slow.next().now_or_never().flatten(), Some(ChainEvent::NewBlock { index: 3, hash: String::new(), transactions: 0 }));
+    assert_eq!(slow.missed, 3);
+}
+
+// Stands in for an exchange API in the oracle tests
+struct MockSource(i64);
 
-    #[test]
-    fn test_event_emission() {
-        let contract = SmartContract::new("add".to_string());
-        contract.emit_event("TestEvent", "EventData");
+impl DataSource for MockSource {
+    fn fetch(&self, _feed: &str) -> Result<i64, String> {
+        Ok(self.0)
+    }
+}
 
-        // You might need to capture stdout or use a mock to verify the event emission
+#[test]
+fn test_oracle_feed_median_and_contract_reads() {
+    let reporters: Vec<Reporter<MockSource>> = [100, 250, 101]
+        .into_iter()
+        .map(|value| Reporter::new(security::generate_keypair(), MockSource(value)))
+        .collect();
+    let tipper = security::generate_keypair();
+    let mut node = Blockchain::new();
+    let members = reporters.iter().map(Reporter::address).chain([security::public_key_hex(&tipper)]).collect();
+    node.create_feed("BTC/USD", members, 2).unwrap();
+    node.balances.insert("Alice".to_string(), 10_000);
+    // Reports pay for their gas like any other transaction
+    for reporter in &reporters {
+        node.balances.insert(reporter.address(), 1_000);
     }
+    node.balances.insert(security::public_key_hex(&tipper), 1_000);
+    let reader = "
+        .feed BTC/USD
+        .function price() -> i32
+            FEED 0
+            RETURN
+    ";
+    node.contracts.insert("reader".to_string(), SmartContract::deploy(reader.to_string()).unwrap());
+
+    // Unsigned reports and reports from outsiders never reach the pool
+    let mut forged = reporters[0].report("BTC/USD", 0, 1, 1).unwrap();
+    forged.signatures.clear();
+    node.add_transaction(forged);
+    let outsider = Reporter::new(security::generate_keypair(), MockSource(1));
+    node.add_transaction(outsider.report("BTC/USD", 0, 1, 1).unwrap());
+    assert!(node.transaction_pool.transactions.is_empty());
+
+    // Nothing to read before the feed has reached its quorum
+    let early = Transaction::call("Alice".to_string(), "reader".to_string(), ContractCall::new("price", vec![]), 0, 1000, 1, 1);
+    node.add_transaction(reporters[0].report("BTC/USD", 0, 1, 1).unwrap());
+    node.add_transaction(early.clone());
+    node.add_block(true);
+    assert!(!node.receipt(&early.id()).unwrap().is_success());
+
+    // Reports for another round do not count; the quorum finalizes the lower median of 100 and 250
+    let wrong_round = reporters[2].report("BTC/USD", 1, 1, 1).unwrap();
+    let finalizing = reporters[1].report("BTC/USD", 0, 1, 1).unwrap();
+    let read = Transaction::call("Alice".to_string(), "reader".to_string(), ContractCall::new("price", vec![]), 0, 1000, 1, 2);
+    for transaction in [&wrong_round, &finalizing, &read] {
+        node.add_transaction(transaction.clone());
+    }
+    node.add_block(true);
+    assert!(!node.receipt(&wrong_round.id()).unwrap().is_success());
+    assert_eq!(node.receipt(&finalizing.id()).unwrap().logs[0].data, "100");
+    assert_eq!(node.receipt(&read.id()).unwrap().return_value, Some("100".to_string()));
+    assert_eq!(node.oracle.feeds["BTC/USD"].round, 1);
+    assert!(node.balances[&reporters[1].address()] < 1_000);
+
+    // A rejected report moves none of the value it carries
+    let mut stale = Transaction::report(security::public_key_hex(&tipper), "BTC/USD".to_string(), 0, 1, 1, 1);
+    stale.receiver = "Alice".to_string();
+    stale.amount = 500;
+    stale.sign(&tipper);
+    let alice_balance = node.balances["Alice"];
+    node.add_transaction(stale.clone());
+    node.add_block(true);
+    assert!(!node.receipt(&stale.id()).unwrap().is_success());
+    assert_eq!(node.balances["Alice"], alice_balance);
+    assert!(node.balances[&security::public_key_hex(&tipper)] > 500);
+
+    // Signatures travel with the block, so a producer cannot report on a reporter's behalf
+    let mut follower = Blockchain::new();
+    follower.create_feed("BTC/USD", reporters.iter().map(Reporter::address).collect(), 2).unwrap();
+    let mut stripped = reporters[0].report("BTC/USD", 0, 1, 1).unwrap();
+    stripped.signatures.clear();
+    let mut block = Block::new(1, 0, serde_json::to_string(&[stripped]).unwrap(), follower.chain[0].hash.clone());
+    block.gas_limit = follower.block_gas_limit;
+    block.mine_block(follower.difficulty);
+    assert!(follower.import_block(block).unwrap_err().contains("signature"));
+}
+
+#[test]
+fn test_static_analysis_findings() {
+    let risky = "
+        .import counter increment(i32) -> i32
+        .function spin()
+            PUSH 1
+            POP
+            JUMP 0
+        .function bump() -> i32
+            PUSH 1
+            PUSH 0
+            PUSH 0
+            XCALL 0
+            POP
+            PUSH 7
+            SSTORE
+            PUSH 0
+            RETURN
+            ADD
+        .function underflow() -> i32
+            ADD
+            RETURN
+    ";
+    let contract = SmartContract::deploy(risky.to_string()).unwrap();
+    let findings = contract.audit().unwrap();
+    let severity_of = |kind| findings.iter().find(|finding| finding.kind == kind).map(|finding| finding.severity);
+    assert_eq!(severity_of(FindingKind::UnboundedLoop), Some(Severity::Error));
+    assert_eq!(severity_of(FindingKind::Reentrancy), Some(Severity::Warning));
+    assert_eq!(severity_of(FindingKind::UnreachableCode), Some(Severity::Info));
+    assert_eq!(severity_of(FindingKind::StackLimit), Some(Severity::Error));
+    assert!(analyzer::enforce(&findings, Severity::Error).is_err());
+
+    // Loops that can exit and code reached through every function are clean
+    let counter = SmartContract::deploy(COUNTER_CONTRACT.to_string()).unwrap();
+    assert!(counter.audit().unwrap().iter().all(|finding| finding.severity < Severity::Warning));
+    let sum = SmartContract::deploy(SUM_TO_N.to_string()).unwrap();
+    assert!(analyzer::enforce(&sum.audit().unwrap(), Severity::Warning).is_ok());
+}
+
+#[test]
+fn test_analysis_blocks_deployment() {
+    let mut node = Blockchain::new();
+    node.balances.insert("Alice".to_string(), 10_000);
+    let looping = "
+        .function run()
+            JUMP 0
+    ";
+    let error = node.deploy_contract("Alice", looping.to_string(), 10, 1).unwrap_err();
+    assert!(error.contains("UnboundedLoop") || error.contains("never exits"));
+
+    // Deployments already in a block are checked again when they execute
+    let deploy = Transaction::deploy("Alice".to_string(), looping.to_string(), 0, 10, 1, 1);
+    node.add_transaction(deploy.clone());
+    node.add_block(true);
+    assert!(!node.receipt(&deploy.id()).unwrap().is_success());
+    assert!(node.contracts.is_empty());
+
+    // Nodes can lower the bar or switch analysis off
+    node.analysis_threshold = None;
+    assert!(node.deploy_contract("Alice", looping.to_string(), 10, 1).is_ok());
+}
+
+#[test]
+fn test_gas_fees_and_block_gas_limit() {
+    let mut node = Blockchain::new();
+    let mut peer = Blockchain::new();
+    for blockchain in [&mut node, &mut peer] {
+        blockchain.balances.insert("Alice".to_string(), 10_000);
+        blockchain.contracts.insert("counter".to_string(), SmartContract::deploy(COUNTER_CONTRACT.to_string()).unwrap());
+        blockchain.block_gas_limit = 1500;
+    }
+    node.coinbase = "Miner".to_string();
+    let increment = |nonce| Transaction {
+        priority_fee: 1,
+        ..Transaction::call("Alice".to_string(), "counter".to_string(), ContractCall::new("increment", vec![Value::I32(1)]), 0, 1000, 2, nonce)
+    };
+
+    // Only one call fits in the block; the other waits in the pool for the next
+    let (first, second) = (increment(1), increment(2));
+    node.add_transaction(first.clone());
+    node.add_transaction(second.clone());
+    node.add_block(true);
+    assert!(node.receipt(&second.id()).is_none());
+    assert_eq!(node.transaction_pool.transactions.len(), 1);
+    node.add_block(true);
+    assert!(node.receipt(&second.id()).unwrap().is_success());
+
+    // Unused gas is refunded; the base fee is burned and the producer collects the tip
+    let receipt = node.receipt(&first.id()).unwrap();
+    assert!(receipt.gas_used > 1 && receipt.gas_used < 1000);
+    assert_eq!(receipt.fee, receipt.gas_used * 2);
+    assert_eq!(receipt.burned, receipt.gas_used);
+    assert_eq!(node.chain[1].gas_used, receipt.gas_used);
+    assert_eq!(node.chain[1].beneficiary, "Miner");
+    let second_receipt = node.receipt(&second.id()).unwrap();
+    let fees = receipt.fee + second_receipt.fee;
+    let tips = fees - receipt.burned - second_receipt.burned;
+    assert_eq!(node.balances["Alice"], 10_000 - fees);
+    assert_eq!(node.balances["Miner"], tips);
+
+    // Peers recompute the gas and fees of every block they import, and only drop the
+    // transactions a block included from their pool, even if others move the same amount
+    peer.add_transaction(second.clone());
+    let mut raised = node.chain[1].clone();
+    raised.gas_limit = 3000;
+    raised.hash = raised.calculate_hash();
+    raised.mine_block(peer.difficulty);
+    assert!(peer.import_block(raised).unwrap_err().contains("gas limit"));
+    peer.import_block(node.chain[1].clone()).unwrap();
+    assert_eq!(peer.transaction_pool.transactions.len(), 1);
+    peer.import_block(node.chain[2].clone()).unwrap();
+    assert!(peer.transaction_pool.transactions.is_empty());
+    assert_eq!(peer.balances["Miner"], tips);
+    assert_eq!(peer.state_root(), node.state_root());
+
+    // Senders must be able to pay for the whole gas limit up front
+    let expensive = Transaction::call("Alice".to_string(), "counter".to_string(), ContractCall::new("increment", vec![Value::I32(1)]), 0, 1000, 100, 3);
+    assert!(!node.validate_transaction(&expensive));
+}
+
+#[test]
+fn test_base_fee_burning_and_estimates() {
+    // Blocks above their target push the base fee up by at most an eighth, emptier ones down
+    let mut parent = Block::new(1, 0, String::new(), "0".to_string());
+    parent.gas_limit = 1000;
+    parent.base_fee = 100;
+    parent.gas_used = 1000;
+    assert_eq!(fees::next_base_fee(&parent), 112);
+    parent.gas_used = 500;
+    assert_eq!(fees::next_base_fee(&parent), 100);
+    parent.gas_used = 0;
+    assert_eq!(fees::next_base_fee(&parent), 88);
+    let genesis = Block::new(0, 0, String::new(), "0".to_string());
+    assert_eq!(fees::next_base_fee(&genesis), fees::INITIAL_BASE_FEE);
+
+    let mut node = Blockchain::new();
+    node.balances.insert("Alice".to_string(), 10_000);
+    node.contracts.insert("counter".to_string(), SmartContract::deploy(COUNTER_CONTRACT.to_string()).unwrap());
+    node.coinbase = "Miner".to_string();
+    node.block_gas_limit = 400;
+    assert_eq!(node.next_base_fee(), fees::INITIAL_BASE_FEE);
+    let call = Transaction {
+        priority_fee: 3,
+        ..Transaction::call("Alice".to_string(), "counter".to_string(), ContractCall::new("increment", vec![Value::I32(1)]), 0, 400, 10, 1)
+    };
+    node.add_transaction(call.clone());
+    node.add_block(true);
+
+    // The sender pays the base fee plus its tip, not the whole gas price; the base fee is burned
+    let receipt = node.receipt(&call.id()).unwrap();
+    assert_eq!(receipt.burned, receipt.gas_used);
+    assert_eq!(receipt.fee, receipt.gas_used * 4);
+    assert_eq!(node.balances["Miner"], receipt.gas_used * 3);
+    assert_eq!(node.balances.values().sum::<u64>(), 10_000 - receipt.burned);
+
+    // That block ran above its target, so the base fee rose and lower prices are turned away
+    assert_eq!(node.next_base_fee(), 2);
+    let cheap = Transaction::call("Alice".to_string(), "counter".to_string(), ContractCall::new("increment", vec![Value::I32(1)]), 0, 400, 1, 2);
+    assert!(!node.validate_transaction(&cheap));
+    let estimate = node.fee_estimate();
+    assert_eq!(estimate.base_fee, 2);
+    assert_eq!(estimate.standard_tip, 3);
+}
+
+const LEDGER_CONTRACT: &str = "
+    .function record(i32, i32) -> i32
+        DUP 0       ; balances[account] = amount, with the map at slot 1
+        DUP 2
+        PUSH 1
+        MAPSTORE
+        PUSH 2      ; history.push(amount), with the array at slot 2
+        APUSH
+        PUSH 1
+        MAPLOAD
+        PUSH 2
+        ALEN
+        ADD
+        RETURN
+    .function at(i32) -> i32
+        PUSH 2
+        ALOAD
+        RETURN
+";
+
+#[test]
+fn test_typed_storage_words_maps_and_arrays() {
+    // Words hold values far past i32, and zero or empty values clear their slot
+    let large = Word::from(u64::MAX);
+    assert_eq!(large.to_i64(), None);
+    assert_eq!(Word::from(-5).to_i32(), Some(-5));
+    let mut storage = ContractStorage::default();
+    storage.set(Word::from(1), Some(large.into())).unwrap();
+    storage.set(Word::from(2), Some(StorageValue::Bytes(b"alice".to_vec()))).unwrap();
+    storage.set(Word::from(3), Some(0.into())).unwrap();
+    assert_eq!(storage.len(), 2);
+    assert!(storage.word(&Word::from(2)).is_err());
+    let json = serde_json::to_string(&storage).unwrap();
+    assert_eq!(serde_json::from_str::<ContractStorage>(&json).unwrap(), storage);
+
+    // Map entries and array elements get distinct slots that every node derives alike
+    let slot = Word::from(7);
+    assert_eq!(state::map_key(&slot, b"alice"), state::map_key(&Word::from(7), b"alice"));
+    assert_ne!(state::map_key(&slot, b"alice"), state::map_key(&slot, b"bob"));
+    assert_ne!(state::map_key(&slot, b"alice"), state::map_key(&Word::from(8), b"alice"));
+    assert_eq!(state::array_element(&slot, 1), state::array_element(&slot, 0).wrapping_add(&Word::from(1)));
+
+    let mut contract = SmartContract::deploy(LEDGER_CONTRACT.to_string()).unwrap();
+    assert_eq!(contract.execute("record", &[10, 500]).unwrap(), 501);
+    assert_eq!(contract.execute("record", &[11, 700]).unwrap(), 702);
+    assert_eq!(contract.execute("at", &[1]).unwrap(), 700);
+    assert!(contract.execute("at", &[2]).is_err()); // Past the end of the array
+    assert_eq!(contract.state.word(&state::map_key(&Word::from(1), &Word::from(10).0)), Ok(Word::from(500)));
+    assert_eq!(contract.state.word(&Word::from(2)), Ok(Word::from(2)));
+
+    // The VM stack holds whole words, so wide values survive loads, arithmetic and stores
+    let mut vm = VirtualMachine::new(10_000);
+    vm.memory.set(Word::from(0), Some(large.into())).unwrap();
+    let bump = "
+        PUSH 0
+        SLOAD
+        DUP 0
+        PUSH 1
+        ADD
+        DUP 0
+        PUSH 0
+        SSTORE
+        LT
+        RETURN
+    ";
+    assert_eq!(vm.execute(bump, &[]).unwrap(), 1);
+    assert_eq!(vm.memory.word(&Word::from(0)), Ok(Word::from_u128(u64::MAX as u128 + 1)));
+    assert_eq!(Word::from(-7).signed_div_rem(&Word::from(2)), Some((Word::from(-3), Word::from(-1))));
+    assert_eq!(large.wrapping_mul(&large).wrapping_sub(&Word::from_u128(u128::MAX)), large.wrapping_add(&large).wrapping_neg());
+
+    // Results are narrowed to the declared return type rather than truncated
+    let mut counter = SmartContract::deploy(COUNTER_CONTRACT.to_string()).unwrap();
+    counter.state.set(Word::from(0), Some(large.into())).unwrap();
+    assert!(counter.execute("increment", &[1]).unwrap_err().to_string().contains("does not fit"));
+}
+
+#[test]
+fn test_contract_storage_persistence_and_write_gas() {
+    let dir = TestDir::new("test_contract_storage");
+    let mut node = Blockchain::open(dir.storage(), NodeMode::Archive);
+    node.balances.insert("Alice".to_string(), 10_000);
+    node.contracts.insert("counter".to_string(), SmartContract::deploy(COUNTER_CONTRACT.to_string()).unwrap());
+    let call = Transaction::call("Alice".to_string(), "counter".to_string(), ContractCall::new("increment", vec![Value::I32(5)]), 0, 1000, 1, 1);
+    node.add_transaction(call.clone());
+    node.add_block(true);
+
+    // The write is charged on top of the other instructions
+    let receipt = node.receipt(&call.id()).unwrap();
+    assert_eq!(receipt.gas_used, 62 + state::STORAGE_WRITE_GAS + INTRINSIC_GAS);
+    assert_eq!(StorageValue::write_gas(Some(&StorageValue::Bytes(vec![0; 10]))), state::STORAGE_WRITE_GAS + 10 * state::STORAGE_BYTE_GAS);
+
+    // Each contract's storage is kept in the store under its own key
+    let stored: ContractStorage = node.storage.as_ref().unwrap().load_state("counter");
+    assert_eq!(stored.word(&Word::from(0)), Ok(Word::from(5)));
+    node.contracts.remove("counter");
+    assert_eq!(node.contract_storage("counter"), Some(stored));
+}
+
+#[test]
+fn test_simulate_call_leaves_state_untouched() {
+    let mut node = Blockchain::new();
+    node.balances.insert("Alice".to_string(), 10_000);
+    node.contracts.insert("counter".to_string(), SmartContract::deploy(COUNTER_CONTRACT.to_string()).unwrap());
+    let root = node.state_root();
+
+    // A zero gas limit and nonce are filled in, so the preview doubles as a gas estimate
+    let call = Transaction::call("Alice".to_string(), "counter".to_string(), ContractCall::new("increment", vec![Value::I32(5)]), 0, 0, 1, 0);
+    let simulation = node.simulate_call(&call).unwrap();
+    assert!(simulation.receipt.is_success());
+    assert_eq!(simulation.receipt.return_value, Some("5".to_string()));
+    assert_eq!(simulation.diff.storage["counter"][&Word::from(0)].after, Some(StorageValue::Word(Word::from(5))));
+    assert_eq!(simulation.diff.balances["Alice"].after, 10_000 - simulation.receipt.fee);
+    assert_eq!(simulation.diff.nonces["Alice"].after, 1);
+    assert_eq!(node.state_root(), root);
+    assert!(node.contracts["counter"].state.is_empty());
+
+    // Sending it for real uses exactly the estimated gas
+    let estimated = simulation.receipt.gas_used;
+    let call = Transaction::call("Alice".to_string(), "counter".to_string(), ContractCall::new("increment", vec![Value::I32(5)]), 0, estimated, 1, 1);
+    node.add_transaction(call.clone());
+    node.add_block(true);
+    assert_eq!(node.receipt(&call.id()).unwrap().gas_used, estimated);
+
+    // Deployments report the new contract, and senders must still afford the gas
+    let (deploy, address) = node.deploy_contract("Alice", COUNTER_CONTRACT.to_string(), 1000, 1).unwrap();
+    assert_eq!(node.simulate_call(&deploy).unwrap().diff.deployed, vec![address.clone()]);
+    assert!(!node.contracts.contains_key(&address));
+    let broke = Transaction::call("Bob".to_string(), "counter".to_string(), ContractCall::new("increment", vec![Value::I32(1)]), 0, 1000, 1, 0);
+    assert!(node.simulate_call(&broke).is_err());
+}
+
+#[test]
+fn test_fungible_token_lifecycle() {
+    let (alice_key, bob_key) = (security::generate_keypair(), security::generate_keypair());
+    let (alice, bob) = (security::public_key_hex(&alice_key), security::public_key_hex(&bob_key));
+    let mut node = Blockchain::new();
+    node.balances.insert(alice.clone(), 10_000);
+    node.balances.insert(bob.clone(), 10_000);
+    let params = TokenParams { max_supply: Some(1_500), ..TokenParams::new("Gold", "GLD", 2, 1_000) };
+    let create = signed(Transaction::create_token(alice.clone(), params, 10, 1, 1), &alice_key);
+    node.add_transaction(create.clone());
+    node.add_block(true);
+    let gold = node.receipt(&create.id()).unwrap().return_value.unwrap();
+    assert_eq!(gold, token_id(&alice, 1));
+    assert_eq!(node.tokens.token(&gold).unwrap().symbol, "GLD");
+    assert_eq!(node.tokens.balance(&alice, &gold), 1_000);
+
+    // Token operations must be signed by their sender, and the nonce is signed so they cannot
+    // be replayed
+    let token_operation = |sender: &str, receiver: &str, kind: TransactionKind, nonce| Transaction {
+        nonce,
+        kind,
+        ..Transaction::new(sender.to_string(), receiver.to_string(), 0, 10, 1, 0)
+    };
+    let unsigned = Transaction::transfer_token(alice.clone(), bob.clone(), gold.clone(), 300, 10, 1, 2);
+    let forged = signed(unsigned.clone(), &bob_key);
+    node.add_transaction(unsigned.clone());
+    node.add_transaction(forged);
+    assert!(node.transaction_pool.transactions.is_empty());
+    let replayed = Transaction { nonce: 3, ..signed(unsigned, &alice_key) };
+    assert!(!replayed.verify_sender());
+
+    // Tokens move next to the native amount, and transfers of tokens the sender lacks are refused
+    let pay = signed(Transaction {
+        amount: 5,
+        ..Transaction::transfer_token(alice.clone(), bob.clone(), gold.clone(), 300, 10, 1, 2)
+    }, &alice_key);
+    node.add_transaction(pay.clone());
+    let overdraw = signed(Transaction::transfer_token(bob.clone(), "Carol".to_string(), gold.clone(), 301, 10, 1, 1), &bob_key);
+    assert!(!node.validate_transaction(&overdraw));
+    let approve = signed(token_operation(&alice, &bob, TransactionKind::ApproveToken { token: gold.clone(), amount: 200 }, 3), &alice_key);
+    let mint = signed(token_operation(&alice, "Carol", TransactionKind::MintToken { token: gold.clone(), amount: 600 }, 4), &alice_key);
+    let stolen_mint = signed(token_operation(&bob, &bob, TransactionKind::MintToken { token: gold.clone(), amount: 1 }, 1), &bob_key);
+    for transaction in [&approve, &mint, &stolen_mint] {
+        node.add_transaction(transaction.clone());
+    }
+    node.add_block(true);
+    assert_eq!(node.tokens.balance(&bob, &gold), 300);
+    assert_eq!(node.balances[&bob], 10_000 + 5 - node.receipt(&stolen_mint.id()).unwrap().fee);
+    assert!(!node.receipt(&mint.id()).unwrap().is_success()); // Past the maximum supply
+    assert_eq!(node.tokens.allowance(&gold, &alice, &bob), 200);
+
+    // The empty address marks burns in the logs, so nothing can be minted or sent to it
+    let to_nobody = signed(token_operation(&alice, "", TransactionKind::MintToken { token: gold.clone(), amount: 1 }, 5), &alice_key);
+    node.add_transaction(to_nobody.clone());
+    node.add_block(true);
+    assert!(!node.receipt(&to_nobody.id()).unwrap().is_success());
+    assert!(node.tokens.transfer(&gold, &alice, "", 1).is_err());
+
+    // Bob spends part of the allowance, then burns what he holds
+    let spend = signed(token_operation(&bob, "Carol", TransactionKind::TransferTokenFrom { token: gold.clone(), holder: alice.clone(), amount: 150 }, 2), &bob_key);
+    let burn = signed(token_operation(&bob, "", TransactionKind::BurnToken { token: gold.clone(), amount: 300 }, 3), &bob_key);
+    node.add_transaction(spend.clone());
+    node.add_transaction(burn);
+    node.add_block(true);
+    let receipt = node.receipt(&spend.id()).unwrap();
+    assert_eq!(receipt.logs[0].topics, vec!["Transfer".to_string(), alice.clone(), "Carol".to_string()]);
+    assert_eq!(node.tokens.balance("Carol", &gold), 150);
+    assert_eq!(node.tokens.balance(&alice, &gold), 550);
+    assert_eq!(node.tokens.allowance(&gold, &alice, &bob), 50);
+    assert_eq!(node.tokens.token(&gold).unwrap().total_supply, 700);
+    assert!(!node.tokens.balances.contains_key(&bob));
+
+    // Token balances are part of the state root peers check
+    let mut peer = Blockchain::new();
+    peer.balances.insert(alice.clone(), 10_000);
+    peer.balances.insert(bob.clone(), 10_000);
+    for block in node.chain[1..].iter().cloned() {
+        peer.import_block(block).unwrap();
+    }
+    assert_eq!(peer.tokens, node.tokens);
+    assert_eq!(peer.state_root(), node.state_root());
+}
+
+#[test]
+fn test_nft_collection_lifecycle() {
+    let (alice_key, bob_key) = (security::generate_keypair(), security::generate_keypair());
+    let (alice, bob) = (security::public_key_hex(&alice_key), security::public_key_hex(&bob_key));
+    let mut node = Blockchain::new();
+    node.balances.insert(alice.clone(), 10_000);
+    node.balances.insert(bob.clone(), 10_000);
+    let nft_operation = |sender: &str, receiver: &str, kind: TransactionKind, nonce| Transaction {
+        nonce,
+        kind,
+        ..Transaction::new(sender.to_string(), receiver.to_string(), 0, 10, 1, 0)
+    };
+    let create = signed(nft_operation(&alice, "", TransactionKind::CreateCollection {
+        name: "Kittens".to_string(),
+        symbol: "KIT".to_string(),
+        royalty: Some(Royalty { recipient: alice.clone(), basis_points: 250 }),
+    }, 1), &alice_key);
+    node.add_transaction(create.clone());
+    node.add_block(true);
+    let kittens = node.receipt(&create.id()).unwrap().return_value.unwrap();
+    assert_eq!(kittens, collection_id(&alice, 1));
+
+    // Ids are assigned in mint order, and only the creator may mint
+    let mint = |receiver: &str, uri: &str, key: &Ed25519KeyPair, nonce: u64| {
+        let kind = TransactionKind::MintNft { collection: kittens.clone(), uri: uri.to_string() };
+        signed(nft_operation(&security::public_key_hex(key), receiver, kind, nonce), key)
+    };
+    let first = mint(&alice, "ipfs://one", &alice_key, 2);
+    let second = mint(&bob, "ipfs://two", &alice_key, 3);
+    let forged = mint(&bob, "ipfs://fake", &bob_key, 1);
+    for transaction in [&first, &second, &forged] {
+        node.add_transaction(transaction.clone());
+    }
+    node.add_block(true);
+    assert_eq!(node.receipt(&second.id()).unwrap().return_value, Some("2".to_string()));
+    assert!(!node.receipt(&forged.id()).unwrap().is_success());
+    assert_eq!(node.nfts.nft(&kittens, 1).unwrap().uri, "ipfs://one");
+    assert_eq!(node.nfts.tokens_of(&bob), vec![(kittens.clone(), 2)]);
+    assert_eq!(node.nfts.royalty_info(&kittens, 2, 1_000).unwrap(), Some((alice.clone(), 25)));
+
+    // Naming the owner is not enough: a transfer must be signed by its sender
+    let unsigned = Transaction::transfer_nft(alice.clone(), bob.clone(), kittens.clone(), 1, 10, 1, 4);
+    let forged_transfer = signed(unsigned.clone(), &bob_key);
+    node.add_transaction(unsigned);
+    node.add_transaction(forged_transfer.clone());
+    assert!(node.transaction_pool.transactions.is_empty());
+    let mut follower = Blockchain::new();
+    follower.balances = node.balances.clone();
+    follower.nfts = node.nfts.clone();
+    let mut block = Block::new(1, 0, serde_json::to_string(&[forged_transfer]).unwrap(), follower.chain[0].hash.clone());
+    block.gas_limit = follower.block_gas_limit;
+    block.mine_block(follower.difficulty);
+    assert!(follower.import_block(block).unwrap_err().contains("signature"));
+
+    // An approved account may move the token once; the approval goes with the transfer
+    let approve = signed(nft_operation(&alice, &bob, TransactionKind::ApproveNft { collection: kittens.clone(), token_id: 1 }, 4), &alice_key);
+    let take = signed(Transaction::transfer_nft(bob.clone(), "Carol".to_string(), kittens.clone(), 1, 10, 1, 2), &bob_key);
+    let take_back = signed(Transaction::transfer_nft(bob.clone(), bob.clone(), kittens.clone(), 1, 10, 1, 3), &bob_key);
+    node.add_transaction(approve);
+    node.add_block(true);
+    node.add_transaction(take.clone());
+    node.add_transaction(take_back.clone());
+    node.add_block(true);
+    let receipt = node.receipt(&take.id()).unwrap();
+    assert_eq!(receipt.logs[0].topics, vec!["Transfer".to_string(), alice.clone(), "Carol".to_string(), "1".to_string()]);
+    assert!(!node.receipt(&take_back.id()).unwrap().is_success());
+    assert_eq!(node.nfts.nft(&kittens, 1).unwrap().owner, "Carol");
+    assert_eq!(node.nfts.nft(&kittens, 1).unwrap().approved, None);
+
+    // Sending to the empty address would pass for a burn, so it is refused
+    let lost = signed(Transaction::transfer_nft(bob.clone(), String::new(), kittens.clone(), 2, 10, 1, 4), &bob_key);
+    node.add_transaction(lost.clone());
+    node.add_block(true);
+    assert!(!node.receipt(&lost.id()).unwrap().is_success());
+    assert_eq!(node.nfts.nft(&kittens, 2).unwrap().owner, bob);
+
+    // Simulations report ownership changes, and collections are part of the state root
+    let burn = nft_operation(&bob, "", TransactionKind::BurnNft { collection: kittens.clone(), token_id: 2 }, 5);
+    let simulation = node.simulate_call(&burn).unwrap();
+    assert_eq!(simulation.diff.nft_owners[&kittens][&2], Change { before: Some(bob.clone()), after: None });
+    let mut peer = Blockchain::new();
+    peer.balances.insert(alice.clone(), 10_000);
+    peer.balances.insert(bob.clone(), 10_000);
+    for block in node.chain[1..].iter().cloned() {
+        peer.import_block(block).unwrap();
+    }
+    assert_eq!(peer.nfts, node.nfts);
+    assert_eq!(peer.state_root(), node.state_root());
+}
+
+#[test]
+fn test_htlc_atomic_swap_and_height_windows() {
+    let mut chain_a = Blockchain::new();
+    chain_a.balances.insert("Alice".to_string(), 1_000);
+    chain_a.balances.insert("Bob".to_string(), 100);
+    let mut chain_b = Blockchain::new();
+    chain_b.balances.insert("Bob".to_string(), 1_000);
+    chain_b.balances.insert("Alice".to_string(), 100);
+    let secret = b"swap secret";
+    let lock = hash_lock(secret);
+
+    // Alice locks on her chain first with the longer timeout; Bob locks the same hash on his
+    let lock_a = Transaction::lock_htlc("Alice".to_string(), "Bob".to_string(), 100, HtlcTerms { hash_lock: lock.clone(), timeout: 6 }, 10, 1, 1);
+    chain_a.add_transaction(lock_a.clone());
+    chain_a.add_block(true);
+    let htlc_a = chain_a.receipt(&lock_a.id()).unwrap().return_value.unwrap();
+    assert_eq!(chain_a.htlcs.get(&htlc_a).unwrap().amount, 100);
+    // Hex case does not matter: locks are kept in lowercase, as claims are hashed
+    let lock_b = Transaction::lock_htlc("Bob".to_string(), "Alice".to_string(), 50, HtlcTerms { hash_lock: lock.to_uppercase(), timeout: 4 }, 10, 1, 1);
+    chain_b.add_transaction(lock_b.clone());
+    chain_b.add_block(true);
+    let htlc_b = chain_b.receipt(&lock_b.id()).unwrap().return_value.unwrap();
+    assert_eq!(chain_b.htlcs.get(&htlc_b).unwrap().hash_lock, lock);
+
+    // Claiming on Bob's chain reveals the preimage, which Bob then uses on Alice's chain
+    let claim = |sender: &str, htlc: &str, preimage: String, nonce: u64| Transaction {
+        nonce,
+        kind: TransactionKind::ClaimHtlc { htlc: htlc.to_string(), preimage },
+        ..Transaction::new(sender.to_string(), String::new(), 0, 10, 1, 0)
+    };
+    let wrong = claim("Alice", &htlc_b, security::to_hex(b"guess"), 1);
+    let claim_b = claim("Alice", &htlc_b, security::to_hex(secret), 2);
+    chain_b.add_transaction(wrong.clone());
+    chain_b.add_transaction(claim_b.clone());
+    chain_b.add_block(true);
+    assert!(!chain_b.receipt(&wrong.id()).unwrap().is_success());
+    let receipt = chain_b.receipt(&claim_b.id()).unwrap();
+    assert_eq!(chain_b.balances["Alice"], 100 + 50 - receipt.fee - chain_b.receipt(&wrong.id()).unwrap().fee);
+    let revealed = receipt.logs[0].data.clone();
+    let claim_a = claim("Bob", &htlc_a, revealed, 1);
+    chain_a.add_transaction(claim_a.clone());
+    chain_a.add_block(true);
+    assert_eq!(chain_a.balances["Bob"], 100 + 100 - chain_a.receipt(&claim_a.id()).unwrap().fee);
+    assert!(chain_a.htlcs.locks.is_empty() && chain_b.htlcs.locks.is_empty());
 
-    #[test]
-    fn test_error_handling() {
-        let mut contract = SmartContract::new("add".to_string());
+    // Unclaimed funds go back to the sender only after the timeout
+    let timeout = chain_a.next_index() + 1;
+    let lock_c = Transaction::lock_htlc("Alice".to_string(), "Carol".to_string(), 30, HtlcTerms { hash_lock: lock, timeout }, 10, 1, 2);
+    chain_a.add_transaction(lock_c.clone());
+    chain_a.add_block(true);
+    let htlc_c = chain_a.receipt(&lock_c.id()).unwrap().return_value.unwrap();
+    let refund = |nonce: u64| Transaction {
+        nonce,
+        kind: TransactionKind::RefundHtlc { htlc: htlc_c.clone() },
+        ..Transaction::new("Alice".to_string(), String::new(), 0, 10, 1, 0)
+    };
+    chain_a.add_transaction(refund(3));
+    chain_a.add_block(true);
+    assert!(!chain_a.receipt(&refund(3).id()).unwrap().is_success());
+    let before = chain_a.balances["Alice"];
+    chain_a.add_transaction(refund(4));
+    chain_a.add_block(true);
+    assert_eq!(chain_a.balances["Alice"], before + 30 - chain_a.receipt(&refund(4).id()).unwrap().fee);
+    assert!(!chain_a.balances.contains_key("Carol"));
 
-        // Test with valid function
-        let result = contract.execute_with_error_handling("add", &[1, 2, 3]);
-        assert_eq!(result.unwrap(), 6);
+    // Transactions wait in the pool until their window opens, and expired ones are refused
+    let delayed = Transaction {
+        nonce: 5,
+        valid_after_height: Some(chain_a.next_index()),
+        ..Transaction::new("Alice".to_string(), "Dave".to_string(), 5, 10, 1, 0)
+    };
+    chain_a.add_transaction(delayed.clone());
+    chain_a.add_block(true);
+    assert!(!chain_a.balances.contains_key("Dave"));
+    assert_eq!(chain_a.transaction_pool.transactions.len(), 1);
+    chain_a.add_block(true);
+    assert_eq!(chain_a.balances["Dave"], 5);
+    let expired = Transaction {
+        nonce: 6,
+        valid_until_height: Some(chain_a.next_index() - 1),
+        ..Transaction::new("Alice".to_string(), "Dave".to_string(), 5, 10, 1, 0)
+    };
+    assert!(!chain_a.validate_transaction(&expired));
 
-        // Test with invalid function
-        let result = contract.execute_with_error_handling("invalid", &[1, 2, 3]);
-        assert!(result.is_err());
+    // Peers replaying the blocks reach the same state
+    let mut peer = Blockchain::new();
+    peer.balances.insert("Alice".to_string(), 1_000);
+    peer.balances.insert("Bob".to_string(), 100);
+    for block in chain_a.chain[1..].iter().cloned() {
+        peer.import_block(block).unwrap();
     }
-} 
\ No newline at end of file
+    assert_eq!(peer.state_root(), chain_a.state_root());
+}